@@ -11,8 +11,9 @@ use bevy::{
     render::camera::WindowOrigin
 };
 use bevy_networking_turbulence::{
-    ConnectionChannelsBuilder, MessageChannelMode, MessageChannelSettings, NetworkEvent,
-    NetworkResource, NetworkingPlugin, ReliableChannelSettings, MessageFlushingStrategy,
+    ConnectionChannelsBuilder, ConnectionRole, InterpolationBuffer, InterpolationPlugin,
+    MessageChannelMode, MessageChannelSettings, NetworkEvent, NetworkResource, NetworkingPlugin,
+    ReliableChannelSettings, MessageFlushingStrategy,
 };
 use rand::Rng;
 use serde::{Deserialize, Serialize};
@@ -68,6 +69,8 @@ impl Plugin for BallsExample {
             .insert_resource(ClearColor(Color::rgb(0.3, 0.3, 0.3)))
             .add_startup_system(client_setup.system())
             .add_system_to_stage(CoreStage::PreUpdate, handle_messages_client.system())
+            // render balls 100ms in the past, smoothing out the link conditioner jitter
+            .add_plugin(InterpolationPlugin::<Transform>::default())
             .insert_resource(ServerIds::default())
             .add_system(ball_control_system.system())
         }
@@ -177,6 +180,7 @@ const CLIENT_STATE_MESSAGE_SETTINGS: MessageChannelSettings = MessageChannelSett
 #[derive(Serialize, Deserialize, Debug, Clone)]
 struct GameStateMessage {
     frame: u32,
+    time: f64,
     balls: Vec<(u32, Vec3, Vec3)>,
 }
 
@@ -190,10 +194,12 @@ const GAME_STATE_MESSAGE_SETTINGS: MessageChannelSettings = MessageChannelSettin
 fn network_broadcast_system(
     mut state: ResMut<NetworkBroadcast>,
     mut net: ResMut<NetworkResource>,
+    time: Res<Time>,
     ball_query: Query<(Entity, &Ball, &Transform)>,
 ) {
     let mut message = GameStateMessage {
         frame: state.frame,
+        time: time.seconds_since_startup(),
         balls: Vec::new(),
     };
     state.frame += 1;
@@ -217,12 +223,12 @@ fn handle_packets(
         match event {
             NetworkEvent::Connected(handle) => match net.connections.get_mut(handle) {
                 Some(connection) => {
-                    match connection.remote_address() {
-                        Some(remote_address) => {
+                    match connection.info().role {
+                        ConnectionRole::Server => {
                             log::debug!(
-                                "Incoming connection on [{}] from [{}]",
+                                "Incoming connection on [{}] from [{:?}]",
                                 handle,
-                                remote_address
+                                connection.remote_address()
                             );
 
                             // New client connected - spawn a ball
@@ -242,8 +248,12 @@ fn handle_packets(
                                 Transform::from_translation(Vec3::new(pos_x, pos_y, 1.0)),
                             ));
                         }
-                        None => {
-                            log::debug!("Connected on [{}]", handle);
+                        ConnectionRole::Client => {
+                            log::debug!(
+                                "Connected on [{}] to [{:?}]",
+                                handle,
+                                connection.remote_address()
+                            );
                         }
                     }
 
@@ -310,7 +320,7 @@ fn handle_messages_client(
     mut net: ResMut<NetworkResource>,
     mut server_ids: ResMut<ServerIds>,
     mut materials: ResMut<Assets<ColorMaterial>>,
-    mut balls: Query<(Entity, &mut Ball, &mut Transform)>,
+    mut balls: Query<(Entity, &mut Ball, &mut InterpolationBuffer<Transform>)>,
 ) {
     for (handle, connection) in net.connections.iter_mut() {
         let channels = connection.channels().unwrap();
@@ -319,10 +329,11 @@ fn handle_messages_client(
         }
 
         // it is possible that many state updates came at the same time - spawn once
-        let mut to_spawn: HashMap<u32, (u32, Vec3, Vec3)> = HashMap::new();
+        let mut to_spawn: HashMap<u32, (u32, f64, Vec3, Vec3)> = HashMap::new();
 
         while let Some(mut state_message) = channels.recv::<GameStateMessage>() {
             let message_frame = state_message.frame;
+            let message_time = state_message.time;
             log::info!(
                 "GameStateMessage received on [{}]: {:?}",
                 handle,
@@ -330,7 +341,7 @@ fn handle_messages_client(
             );
 
             // update all balls
-            for (entity, mut ball, mut transform) in balls.iter_mut() {
+            for (entity, mut ball, mut buffer) in balls.iter_mut() {
                 let server_id_entry = server_ids.get_mut(&entity.id()).unwrap();
                 let (server_id, update_frame) = *server_id_entry;
 
@@ -341,30 +352,34 @@ fn handle_messages_client(
                 {
                     let (_id, velocity, translation) = state_message.balls.remove(index);
 
+                    // older states still fill gaps in the interpolation buffer
+                    buffer.push(message_time, Transform::from_translation(translation));
+
                     if update_frame > message_frame {
                         continue;
                     }
                     server_id_entry.1 = message_frame;
 
                     ball.velocity = velocity;
-                    transform.translation = translation;
                 } else {
                     // TODO: despawn disconnected balls
                 }
             }
             // create new balls
             for (id, velocity, translation) in state_message.balls.drain(..) {
-                if let Some((frame, _velocity, _translation)) = to_spawn.get(&id) {
+                if let Some((frame, _time, _velocity, _translation)) = to_spawn.get(&id) {
                     if *frame > message_frame {
                         continue;
                     }
                 };
-                to_spawn.insert(id, (message_frame, velocity, translation));
+                to_spawn.insert(id, (message_frame, message_time, velocity, translation));
             }
         }
 
-        for (id, (frame, velocity, translation)) in to_spawn.iter() {
+        for (id, (frame, time, velocity, translation)) in to_spawn.iter() {
             log::info!("Spawning {} @{}", id, frame);
+            let mut buffer = InterpolationBuffer::default();
+            buffer.push(*time, Transform::from_translation(*translation));
             let entity = commands
                 .spawn_bundle(SpriteBundle {
                     material: materials.add(
@@ -378,6 +393,7 @@ fn handle_messages_client(
                     velocity: *velocity,
                 })
                 .insert(Pawn { controller: *id })
+                .insert(buffer)
                 .id();
             server_ids.insert(entity.id(), (*id, *frame));
         }
@@ -0,0 +1,322 @@
+//! Banning peers and restricting who may connect.
+//!
+//! Listeners drop packets from banned addresses and from addresses outside of
+//! `ListenerSettings::allowlist`, before any connection state is created for them.
+
+#[cfg(not(target_arch = "wasm32"))]
+use std::sync::RwLock;
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    str::FromStr,
+};
+
+#[cfg(not(target_arch = "wasm32"))]
+use instant::{Duration, Instant};
+
+use super::ConnectionHandle;
+
+/// Block of IP addresses in CIDR notation, i.e. `"10.0.0.0/8".parse()`.
+/// An address without prefix length is a single host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct IpRange {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpRange {
+    /// `None` if `prefix_len` is longer than the address.
+    /// Host bits of `network` are cleared, `10.0.0.5/8` is the same range as `10.0.0.0/8`.
+    pub fn new(network: IpAddr, prefix_len: u8) -> Option<Self> {
+        let max_len = match canonical(network) {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        if prefix_len > max_len {
+            return None;
+        }
+        Some(IpRange {
+            network: mask(network, prefix_len),
+            prefix_len,
+        })
+    }
+
+    pub fn network(&self) -> IpAddr {
+        self.network
+    }
+
+    pub fn prefix_len(&self) -> u8 {
+        self.prefix_len
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = canonical(ip);
+        ip.is_ipv4() == self.network.is_ipv4() && mask(ip, self.prefix_len) == self.network
+    }
+}
+
+impl From<IpAddr> for IpRange {
+    fn from(ip: IpAddr) -> Self {
+        let ip = canonical(ip);
+        let prefix_len = match ip {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        IpRange {
+            network: ip,
+            prefix_len,
+        }
+    }
+}
+
+impl fmt::Display for IpRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix_len)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IpRangeParseError(String);
+
+impl fmt::Display for IpRangeParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid IP range: {}", self.0)
+    }
+}
+
+impl std::error::Error for IpRangeParseError {}
+
+impl FromStr for IpRange {
+    type Err = IpRangeParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = || IpRangeParseError(s.to_string());
+        let mut parts = s.splitn(2, '/');
+        let ip: IpAddr = parts
+            .next()
+            .and_then(|ip| ip.parse().ok())
+            .ok_or_else(error)?;
+        match parts.next() {
+            Some(prefix_len) => {
+                let prefix_len = prefix_len.parse().map_err(|_| error())?;
+                IpRange::new(ip, prefix_len).ok_or_else(error)
+            }
+            None => Ok(IpRange::from(ip)),
+        }
+    }
+}
+
+// keeps the first `prefix_len` bits of the canonical address
+fn mask(ip: IpAddr, prefix_len: u8) -> IpAddr {
+    match canonical(ip) {
+        IpAddr::V4(ip) => {
+            let mask = u32::MAX.checked_shl(32 - prefix_len as u32).unwrap_or(0);
+            IpAddr::V4(Ipv4Addr::from(u32::from(ip) & mask))
+        }
+        IpAddr::V6(ip) => {
+            let mask = u128::MAX.checked_shl(128 - prefix_len as u32).unwrap_or(0);
+            IpAddr::V6(Ipv6Addr::from(u128::from(ip) & mask))
+        }
+    }
+}
+
+// IPv4 clients of dual stack sockets show up as IPv4-mapped IPv6 addresses
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => match v6.segments() {
+            [0, 0, 0, 0, 0, 0xffff, high, low] => {
+                IpAddr::V4(Ipv4Addr::from(((high as u32) << 16) | low as u32))
+            }
+            _ => IpAddr::V6(v6),
+        },
+        ip => ip,
+    }
+}
+
+/// What `NetworkResource::ban` applies to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BanTarget {
+    /// IP address of the connection's peer.
+    Handle(ConnectionHandle),
+    /// Exactly this address and port.
+    Address(SocketAddr),
+    Range(IpRange),
+}
+
+impl From<ConnectionHandle> for BanTarget {
+    fn from(handle: ConnectionHandle) -> Self {
+        BanTarget::Handle(handle)
+    }
+}
+
+impl From<SocketAddr> for BanTarget {
+    fn from(address: SocketAddr) -> Self {
+        BanTarget::Address(address)
+    }
+}
+
+impl From<IpAddr> for BanTarget {
+    fn from(ip: IpAddr) -> Self {
+        BanTarget::Range(IpRange::from(ip))
+    }
+}
+
+impl From<Ipv4Addr> for BanTarget {
+    fn from(ip: Ipv4Addr) -> Self {
+        BanTarget::from(IpAddr::V4(ip))
+    }
+}
+
+impl From<Ipv6Addr> for BanTarget {
+    fn from(ip: Ipv6Addr) -> Self {
+        BanTarget::from(IpAddr::V6(ip))
+    }
+}
+
+impl From<IpRange> for BanTarget {
+    fn from(range: IpRange) -> Self {
+        BanTarget::Range(range)
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum BanRule {
+    Address(SocketAddr),
+    Range(IpRange),
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl BanRule {
+    pub fn matches(&self, address: SocketAddr) -> bool {
+        match self {
+            BanRule::Address(banned) => {
+                banned.port() == address.port() && canonical(banned.ip()) == canonical(address.ip())
+            }
+            BanRule::Range(range) => range.contains(address.ip()),
+        }
+    }
+}
+
+/// Bans shared by the resource and its listeners.
+#[cfg(not(target_arch = "wasm32"))]
+#[derive(Default)]
+pub(crate) struct BanList {
+    // rule and its end, `None` for permanent
+    bans: RwLock<Vec<(BanRule, Option<Instant>)>>,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl BanList {
+    pub fn ban(&self, rule: BanRule, duration: Duration) {
+        let until = Instant::now().checked_add(duration);
+        let mut bans = self.bans.write().expect("ban list lock is poisoned");
+        Self::prune(&mut bans);
+        bans.retain(|(banned, _)| *banned != rule);
+        bans.push((rule, until));
+    }
+
+    pub fn unban(&self, rule: BanRule) -> bool {
+        let mut bans = self.bans.write().expect("ban list lock is poisoned");
+        Self::prune(&mut bans);
+        let len = bans.len();
+        bans.retain(|(banned, _)| *banned != rule);
+        bans.len() != len
+    }
+
+    pub fn is_banned(&self, address: SocketAddr) -> bool {
+        let now = Instant::now();
+        self.bans
+            .read()
+            .expect("ban list lock is poisoned")
+            .iter()
+            .any(|(rule, until)| until.is_none_or(|until| until > now) && rule.matches(address))
+    }
+
+    fn prune(bans: &mut Vec<(BanRule, Option<Instant>)>) {
+        let now = Instant::now();
+        bans.retain(|(_, until)| until.is_none_or(|until| until > now));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn range(s: &str) -> IpRange {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn ip_range_zero_prefix_contains_everything_of_its_family() {
+        let v4 = range("0.0.0.0/0");
+        assert!(v4.contains(ip("0.0.0.0")));
+        assert!(v4.contains(ip("255.255.255.255")));
+        assert!(v4.contains(ip("::ffff:10.0.0.1")));
+        assert!(!v4.contains(ip("::1")));
+
+        let v6 = range("::/0");
+        assert!(v6.contains(ip("::1")));
+        assert!(v6.contains(ip("ffff::1")));
+        assert!(!v6.contains(ip("10.0.0.1")));
+    }
+
+    #[test]
+    fn ip_range_full_prefix_is_a_single_host() {
+        let host = range("10.0.0.5/32");
+        assert_eq!(host, range("10.0.0.5"));
+        assert!(host.contains(ip("10.0.0.5")));
+        assert!(!host.contains(ip("10.0.0.4")));
+        assert!(!host.contains(ip("10.0.0.6")));
+
+        let host = range("fe80::1/128");
+        assert!(host.contains(ip("fe80::1")));
+        assert!(!host.contains(ip("fe80::2")));
+
+        assert!("10.0.0.5/33".parse::<IpRange>().is_err());
+        assert!("fe80::1/129".parse::<IpRange>().is_err());
+    }
+
+    #[test]
+    fn ip_range_clears_host_bits() {
+        assert_eq!(range("10.0.0.5/8"), range("10.0.0.0/8"));
+        assert_eq!(range("10.0.0.5/8").network(), ip("10.0.0.0"));
+        assert_eq!(range("10.0.0.5/8").to_string(), "10.0.0.0/8");
+        assert_eq!(range("10.1.2.3/0").network(), ip("0.0.0.0"));
+        assert_eq!(range("::ffff:10.0.0.5/8"), range("10.0.0.0/8"));
+        assert!(range("10.0.0.5/8").contains(ip("10.255.0.1")));
+        assert!(!range("10.0.0.5/8").contains(ip("11.0.0.1")));
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    #[test]
+    fn ban_list_unbans_equal_ranges() {
+        let bans = BanList::default();
+        let address: SocketAddr = "10.0.0.7:4000".parse().unwrap();
+        bans.ban(BanRule::Range(range("10.0.0.5/8")), Duration::from_secs(60));
+        assert!(bans.is_banned(address));
+        assert!(!bans.is_banned("11.0.0.7:4000".parse().unwrap()));
+
+        assert!(bans.unban(BanRule::Range(range("10.0.0.0/8"))));
+        assert!(!bans.is_banned(address));
+        assert!(!bans.unban(BanRule::Range(range("10.0.0.0/8"))));
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    #[test]
+    fn ban_list_matches_addresses_and_expires() {
+        let bans = BanList::default();
+        let address: SocketAddr = "10.0.0.7:4000".parse().unwrap();
+        bans.ban(BanRule::Address(address), Duration::from_secs(60));
+        assert!(bans.is_banned(address));
+        assert!(bans.is_banned("[::ffff:10.0.0.7]:4000".parse().unwrap()));
+        assert!(!bans.is_banned("10.0.0.7:4001".parse().unwrap()));
+
+        bans.ban(BanRule::Address(address), Duration::from_secs(0));
+        assert!(!bans.is_banned(address));
+    }
+}
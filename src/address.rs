@@ -0,0 +1,114 @@
+use std::{error::Error, fmt, net::SocketAddr, str::FromStr};
+
+/// Transport a connection runs over.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Transport {
+    /// Plain UDP datagrams, handled by naia-socket with `use-udp` feature.
+    Udp,
+    /// WebRTC data channel, handled by naia-socket with `use-webrtc` feature.
+    WebRtc,
+    /// WebSocket binary messages, available with `use-websocket` feature.
+    WebSocket,
+}
+
+impl Transport {
+    pub fn scheme(&self) -> &'static str {
+        match self {
+            Transport::Udp => "udp",
+            Transport::WebRtc => "webrtc",
+            Transport::WebSocket => "ws",
+        }
+    }
+}
+
+impl Default for Transport {
+    /// The transport naia-socket was compiled for.
+    fn default() -> Self {
+        cfg_if::cfg_if! {
+            if #[cfg(any(target_arch = "wasm32", feature = "use-webrtc"))] {
+                Transport::WebRtc
+            } else {
+                Transport::Udp
+            }
+        }
+    }
+}
+
+impl fmt::Display for Transport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.scheme())
+    }
+}
+
+/// Socket address with a transport selector.
+///
+/// Plain `SocketAddr` converts into the default transport, while the string form
+/// accepts a scheme prefix, i.e. `"ws://192.168.1.1:14193".parse()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct NetworkAddress {
+    pub transport: Transport,
+    pub socket_address: SocketAddr,
+}
+
+impl NetworkAddress {
+    pub fn new(transport: Transport, socket_address: SocketAddr) -> Self {
+        NetworkAddress {
+            transport,
+            socket_address,
+        }
+    }
+}
+
+impl From<SocketAddr> for NetworkAddress {
+    fn from(socket_address: SocketAddr) -> Self {
+        NetworkAddress::new(Transport::default(), socket_address)
+    }
+}
+
+impl fmt::Display for NetworkAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}://{}", self.transport, self.socket_address)
+    }
+}
+
+#[derive(Debug)]
+pub enum AddressParseError {
+    UnknownScheme(String),
+    InvalidSocketAddress(std::net::AddrParseError),
+}
+
+impl fmt::Display for AddressParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AddressParseError::UnknownScheme(scheme) => {
+                write!(f, "unknown address scheme: {}", scheme)
+            }
+            AddressParseError::InvalidSocketAddress(error) => error.fmt(f),
+        }
+    }
+}
+
+impl Error for AddressParseError {}
+
+impl FromStr for NetworkAddress {
+    type Err = AddressParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (transport, address) = match s.find("://") {
+            Some(index) => {
+                let transport = match &s[..index] {
+                    "udp" => Transport::Udp,
+                    "webrtc" => Transport::WebRtc,
+                    "ws" => Transport::WebSocket,
+                    scheme => return Err(AddressParseError::UnknownScheme(scheme.to_string())),
+                };
+                (transport, &s[index + 3..])
+            }
+            None => (Transport::default(), s),
+        };
+        let socket_address = address
+            .parse()
+            .map_err(AddressParseError::InvalidSocketAddress)?;
+        Ok(NetworkAddress::new(transport, socket_address))
+    }
+}
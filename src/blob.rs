@@ -0,0 +1,553 @@
+//! Transfers of data too large for a single message, like saved games or custom maps.
+//!
+//! `Blobs::send` splits the data into chunks, sent over a reliable channel as fast as its send
+//! window allows. Both sides get `BlobProgress` events while the transfer runs, the receiver
+//! gets the data with `BlobReceived`. Either side can abort with `Blobs::cancel`, the other one
+//! then gets `BlobCancelled`, as do both when the connection is gone.
+
+use bevy_app::{AppBuilder, CoreStage, Events, Plugin};
+use bevy_ecs::prelude::*;
+use bytes::{Bytes, BytesMut};
+use instant::Duration;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use super::{
+    ConnectionChannelsBuilder, ConnectionHandle, MessageChannelMode, MessageChannelSettings,
+    NetworkResource, ReliableChannelSettings,
+};
+
+pub const BLOB_CHANNEL: u8 = 243;
+
+const CHUNK_LEN: usize = 1024;
+
+const BLOB_MESSAGE_SETTINGS: MessageChannelSettings = MessageChannelSettings {
+    channel: BLOB_CHANNEL,
+    channel_mode: MessageChannelMode::Reliable {
+        reliability_settings: ReliableChannelSettings {
+            bandwidth: 262144,
+            recv_window_size: 65536,
+            send_window_size: 65536,
+            burst_bandwidth: 65536,
+            init_send: 8192,
+            wakeup_time: Duration::from_millis(100),
+            initial_rtt: Duration::from_millis(200),
+            max_rtt: Duration::from_secs(2),
+            rtt_update_factor: 0.1,
+            rtt_resend_factor: 1.5,
+        },
+        // chunk and its envelope
+        max_message_len: 2048,
+    },
+    message_buffer_size: 64,
+    packet_buffer_size: 64,
+};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum BlobMessage {
+    Start {
+        id: u32,
+        channel: u8,
+        len: u64,
+    },
+    Chunk {
+        id: u32,
+        offset: u64,
+        data: Vec<u8>,
+    },
+    /// `by_sender` tells whether `id` is one of the sender's outgoing or incoming transfers.
+    Cancel {
+        id: u32,
+        by_sender: bool,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BlobDirection {
+    Outgoing,
+    Incoming,
+}
+
+/// Identifies a transfer, ids are assigned by the sending side.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BlobId {
+    pub handle: ConnectionHandle,
+    pub id: u32,
+    pub direction: BlobDirection,
+}
+
+#[derive(Debug, Clone)]
+pub struct BlobProgress {
+    pub blob: BlobId,
+    /// Channel passed to `Blobs::send`.
+    pub channel: u8,
+    pub transferred: usize,
+    pub len: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct BlobReceived {
+    pub blob: BlobId,
+    pub channel: u8,
+    pub data: Bytes,
+}
+
+#[derive(Debug, Clone)]
+pub struct BlobCancelled {
+    pub blob: BlobId,
+    pub channel: u8,
+}
+
+struct OutgoingBlob {
+    channel: u8,
+    data: Bytes,
+    started: bool,
+    sent: usize,
+}
+
+struct IncomingBlob {
+    channel: u8,
+    len: usize,
+    data: BytesMut,
+}
+
+/// Transfers in progress, inserted by `BlobPlugin`.
+pub struct Blobs {
+    next_id: u32,
+    max_len: usize,
+    max_incoming: usize,
+    outgoing: HashMap<(ConnectionHandle, u32), OutgoingBlob>,
+    incoming: HashMap<(ConnectionHandle, u32), IncomingBlob>,
+    // cancelled locally, waiting for `blob_system` to tell the peer
+    cancelled: Vec<(BlobId, u8)>,
+}
+
+impl Blobs {
+    fn new(max_len: usize, max_incoming: usize) -> Self {
+        Blobs {
+            next_id: 0,
+            max_len,
+            max_incoming,
+            outgoing: HashMap::new(),
+            incoming: HashMap::new(),
+            cancelled: Vec::new(),
+        }
+    }
+
+    /// Starts sending `data` to `handle`. `channel` is passed through to the receiving side,
+    /// to tell different kinds of blobs apart.
+    pub fn send<B: Into<Bytes>>(
+        &mut self,
+        net: &NetworkResource,
+        handle: ConnectionHandle,
+        channel: u8,
+        data: B,
+    ) -> Option<BlobId> {
+        if !net.connections.contains_key(&handle) {
+            return None;
+        }
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        self.outgoing.insert(
+            (handle, id),
+            OutgoingBlob {
+                channel,
+                data: data.into(),
+                started: false,
+                sent: 0,
+            },
+        );
+        Some(BlobId {
+            handle,
+            id,
+            direction: BlobDirection::Outgoing,
+        })
+    }
+
+    /// Aborts a transfer in either direction, returns `false` when it is not running.
+    pub fn cancel(&mut self, blob: BlobId) -> bool {
+        let key = (blob.handle, blob.id);
+        let channel = match blob.direction {
+            BlobDirection::Outgoing => self.outgoing.remove(&key).map(|blob| blob.channel),
+            BlobDirection::Incoming => self.incoming.remove(&key).map(|blob| blob.channel),
+        };
+        match channel {
+            Some(channel) => {
+                self.cancelled.push((blob, channel));
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Applies `message` from `handle`, returns the transfer it cancelled.
+    fn receive(&mut self, handle: ConnectionHandle, message: BlobMessage) -> Option<BlobCancelled> {
+        match message {
+            BlobMessage::Start { id, channel, len } => {
+                let len = len as usize;
+                let blob = BlobId {
+                    handle,
+                    id,
+                    direction: BlobDirection::Incoming,
+                };
+                if len > self.max_len {
+                    log::error!("Blob {} of [{}] is too large: {} bytes", id, handle, len);
+                    return Some(self.refuse(blob, channel));
+                }
+                let active = self
+                    .incoming
+                    .keys()
+                    .filter(|(incoming_handle, incoming_id)| {
+                        *incoming_handle == handle && *incoming_id != id
+                    })
+                    .count();
+                if active >= self.max_incoming {
+                    log::error!("Too many blobs from [{}], cancelled {}", handle, id);
+                    return Some(self.refuse(blob, channel));
+                }
+                self.incoming.insert(
+                    (handle, id),
+                    IncomingBlob {
+                        channel,
+                        len,
+                        // grows with the chunks, the announced length is not proof of anything
+                        data: BytesMut::with_capacity(len.min(CHUNK_LEN)),
+                    },
+                );
+                None
+            }
+            BlobMessage::Chunk { id, offset, data } => {
+                // not there when cancelled locally, the rest is still on its way
+                let incoming = self.incoming.get_mut(&(handle, id))?;
+                let error = if offset != incoming.data.len() as u64 {
+                    "is out of order"
+                } else if incoming.data.len() + data.len() > incoming.len {
+                    "is longer than announced"
+                } else {
+                    incoming.data.extend_from_slice(&data);
+                    return None;
+                };
+                log::error!("Blob {} of [{}] {}", id, handle, error);
+                let channel = incoming.channel;
+                let blob = BlobId {
+                    handle,
+                    id,
+                    direction: BlobDirection::Incoming,
+                };
+                Some(self.refuse(blob, channel))
+            }
+            BlobMessage::Cancel { id, by_sender } => {
+                let (direction, removed) = if by_sender {
+                    (
+                        BlobDirection::Incoming,
+                        self.incoming.remove(&(handle, id)).map(|blob| blob.channel),
+                    )
+                } else {
+                    (
+                        BlobDirection::Outgoing,
+                        self.outgoing.remove(&(handle, id)).map(|blob| blob.channel),
+                    )
+                };
+                removed.map(|channel| BlobCancelled {
+                    blob: BlobId {
+                        handle,
+                        id,
+                        direction,
+                    },
+                    channel,
+                })
+            }
+        }
+    }
+
+    /// Drops incoming transfer `blob` and tells the sender.
+    fn refuse(&mut self, blob: BlobId, channel: u8) -> BlobCancelled {
+        self.incoming.remove(&(blob.handle, blob.id));
+        self.cancelled.push((blob, channel));
+        BlobCancelled { blob, channel }
+    }
+
+    /// Progress of incoming transfer `id`, with its data once it is complete.
+    fn incoming_progress(
+        &mut self,
+        handle: ConnectionHandle,
+        id: u32,
+    ) -> Option<(BlobProgress, Option<BlobReceived>)> {
+        let incoming = self.incoming.get(&(handle, id))?;
+        let blob = BlobId {
+            handle,
+            id,
+            direction: BlobDirection::Incoming,
+        };
+        let progress = BlobProgress {
+            blob,
+            channel: incoming.channel,
+            transferred: incoming.data.len(),
+            len: incoming.len,
+        };
+        if incoming.data.len() < incoming.len {
+            return Some((progress, None));
+        }
+        let incoming = self.incoming.remove(&(handle, id)).unwrap();
+        let received = BlobReceived {
+            blob,
+            channel: incoming.channel,
+            data: incoming.data.freeze(),
+        };
+        Some((progress, Some(received)))
+    }
+}
+
+pub fn blob_system(
+    mut blobs: ResMut<Blobs>,
+    mut net: ResMut<NetworkResource>,
+    mut progress_events: ResMut<Events<BlobProgress>>,
+    mut received_events: ResMut<Events<BlobReceived>>,
+    mut cancelled_events: ResMut<Events<BlobCancelled>>,
+) {
+    let blobs = &mut *blobs;
+
+    for (blob, _) in blobs.cancelled.drain(..) {
+        if let Some(channels) = net
+            .connections
+            .get_mut(&blob.handle)
+            .and_then(|connection| connection.channels())
+        {
+            let cancel = BlobMessage::Cancel {
+                id: blob.id,
+                by_sender: blob.direction == BlobDirection::Outgoing,
+            };
+            if channels.send(cancel).is_some() {
+                log::debug!("Blob channel of [{}] is full, cancel dropped", blob.handle);
+            }
+            channels.flush::<BlobMessage>();
+        }
+    }
+
+    // connection gone, cancel its transfers
+    let connections = &net.connections;
+    let mut gone = Vec::new();
+    blobs.outgoing.retain(|(handle, id), outgoing| {
+        let keep = connections.contains_key(handle);
+        if !keep {
+            gone.push((*handle, *id, BlobDirection::Outgoing, outgoing.channel));
+        }
+        keep
+    });
+    blobs.incoming.retain(|(handle, id), incoming| {
+        let keep = connections.contains_key(handle);
+        if !keep {
+            gone.push((*handle, *id, BlobDirection::Incoming, incoming.channel));
+        }
+        keep
+    });
+    for (handle, id, direction, channel) in gone {
+        cancelled_events.send(BlobCancelled {
+            blob: BlobId {
+                handle,
+                id,
+                direction,
+            },
+            channel,
+        });
+    }
+
+    for (handle, connection) in net.connections.iter_mut() {
+        let channels = match connection.channels() {
+            Some(channels) => channels,
+            None => continue,
+        };
+
+        let mut progressed = Vec::new();
+        while let Some(message) = channels.recv::<BlobMessage>() {
+            match message {
+                BlobMessage::Start { id, .. } | BlobMessage::Chunk { id, .. } => {
+                    progressed.push(id)
+                }
+                BlobMessage::Cancel { .. } => {}
+            }
+            if let Some(cancelled) = blobs.receive(*handle, message) {
+                cancelled_events.send(cancelled);
+            }
+        }
+
+        progressed.sort_unstable();
+        progressed.dedup();
+        for id in progressed {
+            if let Some((progress, received)) = blobs.incoming_progress(*handle, id) {
+                progress_events.send(progress);
+                if let Some(received) = received {
+                    received_events.send(received);
+                }
+            }
+        }
+
+        // send as much as the channel takes, the reliable send window is the flow control
+        let mut finished = Vec::new();
+        let mut sent_any = false;
+        for ((outgoing_handle, id), outgoing) in blobs.outgoing.iter_mut() {
+            if outgoing_handle != handle {
+                continue;
+            }
+            let sent_before = outgoing.sent;
+            if !outgoing.started {
+                let start = BlobMessage::Start {
+                    id: *id,
+                    channel: outgoing.channel,
+                    len: outgoing.data.len() as u64,
+                };
+                if channels.send(start).is_some() {
+                    continue;
+                }
+                outgoing.started = true;
+                sent_any = true;
+            }
+            while outgoing.sent < outgoing.data.len() {
+                let end = (outgoing.sent + CHUNK_LEN).min(outgoing.data.len());
+                let chunk = BlobMessage::Chunk {
+                    id: *id,
+                    offset: outgoing.sent as u64,
+                    data: outgoing.data[outgoing.sent..end].to_vec(),
+                };
+                if channels.send(chunk).is_some() {
+                    break;
+                }
+                outgoing.sent = end;
+                sent_any = true;
+            }
+            if outgoing.sent != sent_before || outgoing.data.is_empty() {
+                progress_events.send(BlobProgress {
+                    blob: BlobId {
+                        handle: *handle,
+                        id: *id,
+                        direction: BlobDirection::Outgoing,
+                    },
+                    channel: outgoing.channel,
+                    transferred: outgoing.sent,
+                    len: outgoing.data.len(),
+                });
+            }
+            if outgoing.sent == outgoing.data.len() {
+                finished.push(*id);
+            }
+        }
+        if sent_any {
+            channels.flush::<BlobMessage>();
+        }
+        for id in finished {
+            blobs.outgoing.remove(&(*handle, id));
+        }
+    }
+}
+
+/// Adds the `Blobs` resource for transfers, and their events.
+pub struct BlobPlugin {
+    /// Larger incoming blobs are refused.
+    pub max_len: usize,
+    /// Incoming blobs transferred at once per connection, further ones are cancelled.
+    pub max_incoming: usize,
+}
+
+impl Default for BlobPlugin {
+    fn default() -> Self {
+        BlobPlugin {
+            max_len: 64 * 1024 * 1024,
+            max_incoming: 4,
+        }
+    }
+}
+
+impl Plugin for BlobPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.world_mut()
+            .get_resource_mut::<NetworkResource>()
+            .expect("`NetworkingPlugin` has to be added before `BlobPlugin`")
+            .add_channels_builder(|builder: &mut ConnectionChannelsBuilder| {
+                builder
+                    .register::<BlobMessage>(BLOB_MESSAGE_SETTINGS)
+                    .unwrap();
+            });
+
+        app.insert_resource(Blobs::new(self.max_len, self.max_incoming))
+            .add_event::<BlobProgress>()
+            .add_event::<BlobReceived>()
+            .add_event::<BlobCancelled>()
+            .add_system_to_stage(CoreStage::PreUpdate, blob_system.system());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HANDLE: ConnectionHandle = 1;
+
+    fn start(id: u32, len: u64) -> BlobMessage {
+        BlobMessage::Start {
+            id,
+            channel: 7,
+            len,
+        }
+    }
+
+    fn chunk(id: u32, offset: u64, data: &[u8]) -> BlobMessage {
+        BlobMessage::Chunk {
+            id,
+            offset,
+            data: data.to_vec(),
+        }
+    }
+
+    #[test]
+    fn chunks_are_reassembled() {
+        let mut blobs = Blobs::new(1024, 4);
+        assert!(blobs.receive(HANDLE, start(0, 6)).is_none());
+        assert!(blobs.receive(HANDLE, chunk(0, 0, b"abc")).is_none());
+        let (progress, received) = blobs.incoming_progress(HANDLE, 0).unwrap();
+        assert_eq!((progress.transferred, progress.len), (3, 6));
+        assert!(received.is_none());
+
+        assert!(blobs.receive(HANDLE, chunk(0, 3, b"def")).is_none());
+        let (progress, received) = blobs.incoming_progress(HANDLE, 0).unwrap();
+        assert_eq!(progress.transferred, 6);
+        let received = received.unwrap();
+        assert_eq!(received.channel, 7);
+        assert_eq!(&received.data[..], b"abcdef");
+        assert!(blobs.incoming.is_empty());
+    }
+
+    #[test]
+    fn out_of_order_chunk_cancels() {
+        let mut blobs = Blobs::new(1024, 4);
+        blobs.receive(HANDLE, start(0, 6));
+        let cancelled = blobs.receive(HANDLE, chunk(0, 3, b"def")).unwrap();
+        assert_eq!(cancelled.blob.direction, BlobDirection::Incoming);
+        assert!(blobs.incoming.is_empty());
+        assert_eq!(blobs.cancelled.len(), 1);
+
+        // the rest of a cancelled transfer is ignored
+        assert!(blobs.receive(HANDLE, chunk(0, 0, b"abc")).is_none());
+        assert!(blobs.incoming_progress(HANDLE, 0).is_none());
+    }
+
+    #[test]
+    fn oversize_blobs_are_refused() {
+        let mut blobs = Blobs::new(8, 4);
+        assert!(blobs.receive(HANDLE, start(0, 9)).is_some());
+        assert!(blobs.incoming.is_empty());
+
+        blobs.receive(HANDLE, start(1, 4));
+        blobs.receive(HANDLE, chunk(1, 0, b"abc"));
+        assert!(blobs.receive(HANDLE, chunk(1, 3, b"de")).is_some());
+        assert!(blobs.incoming.is_empty());
+        assert_eq!(blobs.cancelled.len(), 2);
+    }
+
+    #[test]
+    fn incoming_blobs_are_capped_per_connection() {
+        let mut blobs = Blobs::new(1024, 1);
+        assert!(blobs.receive(HANDLE, start(0, 4)).is_none());
+        assert!(blobs.receive(HANDLE, start(1, 4)).is_some());
+        assert!(blobs.receive(HANDLE + 1, start(0, 4)).is_none());
+    }
+}
@@ -7,7 +7,10 @@ use std::{
     future::Future,
     ops::Deref,
     pin::Pin,
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
     task::{Context, Poll, Waker},
     time::Duration,
 };
@@ -19,6 +22,8 @@ use turbulence::{
     runtime::Runtime,
 };
 
+use super::ConnectionHandle;
+
 #[derive(Clone, Debug)]
 pub struct SimpleBufferPool(pub usize);
 
@@ -30,20 +35,57 @@ impl BufferPool for SimpleBufferPool {
     }
 }
 
+/// Runs turbulence tasks on the bevy task pool.
+/// Clones from `for_connection` share the tasks, tagged with the connection that spawned them.
 #[derive(Clone)]
-pub struct TaskPoolRuntime(Arc<TaskPoolRuntimeInner>);
+pub struct TaskPoolRuntime(Arc<TaskPoolRuntimeInner>, Option<ConnectionHandle>);
 
 pub struct TaskPoolRuntimeInner {
     pool: TaskPool,
-    tasks: Mutex<Vec<Task<()>>>, // FIXME: cleanup finished
+    tasks: Mutex<Vec<RuntimeTask>>,
+}
+
+struct RuntimeTask {
+    connection: Option<ConnectionHandle>,
+    finished: Arc<AtomicBool>,
+    // dropping it cancels the task
+    #[allow(dead_code)]
+    task: Task<()>,
 }
 
 impl TaskPoolRuntime {
     pub fn new(pool: TaskPool) -> Self {
-        TaskPoolRuntime(Arc::new(TaskPoolRuntimeInner {
-            pool,
-            tasks: Mutex::new(Vec::new()),
-        }))
+        TaskPoolRuntime(
+            Arc::new(TaskPoolRuntimeInner {
+                pool,
+                tasks: Mutex::new(Vec::new()),
+            }),
+            None,
+        )
+    }
+
+    /// Runtime for the channels of `handle`, so `cancel_connection` can stop its tasks.
+    pub fn for_connection(&self, handle: ConnectionHandle) -> Self {
+        TaskPoolRuntime(Arc::clone(&self.0), Some(handle))
+    }
+
+    /// Cancels all tasks spawned for the connection.
+    pub fn cancel_connection(&self, handle: ConnectionHandle) {
+        self.tasks
+            .lock()
+            .expect("runtime tasks lock is poisoned")
+            .retain(|task| task.connection != Some(handle));
+    }
+
+    /// Tasks spawned and not finished yet.
+    pub fn live_tasks(&self) -> usize {
+        let mut tasks = self.tasks.lock().expect("runtime tasks lock is poisoned");
+        Self::reap(&mut tasks);
+        tasks.len()
+    }
+
+    fn reap(tasks: &mut Vec<RuntimeTask>) {
+        tasks.retain(|task| !task.finished.load(Ordering::Acquire));
     }
 }
 
@@ -60,9 +102,24 @@ impl Runtime for TaskPoolRuntime {
     type Sleep = Pin<Box<dyn Future<Output = ()> + Send>>;
 
     fn spawn<F: Future<Output = ()> + Send + 'static>(&self, f: F) {
-        let task = self.pool.spawn(Box::pin(f));
+        let finished = Arc::new(AtomicBool::new(false));
+        let task = self.pool.spawn({
+            let finished = Arc::clone(&finished);
+            async move {
+                f.await;
+                finished.store(true, Ordering::Release);
+            }
+        });
         #[cfg(not(target_arch = "wasm32"))]
-        self.tasks.lock().unwrap().push(task);
+        {
+            let mut tasks = self.tasks.lock().expect("runtime tasks lock is poisoned");
+            Self::reap(&mut tasks);
+            tasks.push(RuntimeTask {
+                connection: self.1,
+                finished,
+                task,
+            });
+        }
     }
 
     fn now(&self) -> Self::Instant {
@@ -84,3 +141,48 @@ impl Runtime for TaskPoolRuntime {
         })
     }
 }
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finished_tasks_are_reaped() {
+        let runtime = TaskPoolRuntime::new(TaskPool::new());
+        let (done_tx, done_rx) = crossbeam_channel::bounded(1);
+        runtime.spawn(async move {
+            let _ = done_tx.send(());
+        });
+        done_rx
+            .recv_timeout(Duration::from_secs(5))
+            .expect("task runs");
+        // `finished` is set right after the task body returns
+        for _ in 0..500 {
+            if runtime.live_tasks() == 0 {
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        panic!("finished task was not reaped");
+    }
+
+    #[test]
+    fn tasks_of_a_connection_are_cancelled() {
+        let runtime = TaskPoolRuntime::new(TaskPool::new());
+        runtime
+            .for_connection(1)
+            .spawn(futures::future::pending::<()>());
+        runtime
+            .for_connection(1)
+            .spawn(futures::future::pending::<()>());
+        runtime
+            .for_connection(2)
+            .spawn(futures::future::pending::<()>());
+        assert_eq!(runtime.live_tasks(), 3);
+
+        runtime.cancel_connection(1);
+        assert_eq!(runtime.live_tasks(), 1);
+        runtime.cancel_connection(2);
+        assert_eq!(runtime.live_tasks(), 0);
+    }
+}
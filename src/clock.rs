@@ -0,0 +1,308 @@
+//! Server clock synchronization.
+//!
+//! Clients periodically send their local time to the server, which answers with its own time.
+//! Assuming symmetric latency, every answer gives an estimate of the server clock offset,
+//! the one with the shortest round trip out of the last few is used.
+//! The server tick is the server time divided by the tick duration, see `NetworkTime::tick`.
+
+use bevy_app::{AppBuilder, CoreStage, Plugin};
+use bevy_ecs::prelude::*;
+use instant::{Duration, Instant};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+
+use super::{
+    ConnectionChannelsBuilder, ConnectionRole, MessageChannelMode, MessageChannelSettings,
+    NetworkResource,
+};
+
+pub const CLOCK_CHANNEL: u8 = 245;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub enum ClockMessage {
+    Request { client_time: f64 },
+    Response { client_time: f64, server_time: f64 },
+}
+
+/// Message tagged with the server tick it belongs to.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Stamped<M> {
+    pub tick: u32,
+    pub message: M,
+}
+
+/// Local and estimated server time. On the server, both are the same.
+pub struct NetworkTime {
+    tick_rate: f64,
+    epoch: Instant,
+    offset: Option<f64>,
+    rtt: Option<f64>,
+    samples: VecDeque<(f64, f64)>,
+    max_samples: usize,
+    last_request: Option<Instant>,
+}
+
+impl NetworkTime {
+    fn new(tick_rate: f64, max_samples: usize) -> Self {
+        NetworkTime {
+            tick_rate,
+            epoch: Instant::now(),
+            offset: None,
+            rtt: None,
+            samples: VecDeque::new(),
+            max_samples,
+            last_request: None,
+        }
+    }
+
+    /// Seconds since the plugin was built.
+    pub fn local_time(&self) -> f64 {
+        self.epoch.elapsed().as_secs_f64()
+    }
+
+    /// Estimated server time in seconds, equal to local time until synchronized.
+    pub fn server_time(&self) -> f64 {
+        self.local_time() + self.offset.unwrap_or(0.0)
+    }
+
+    /// Current server tick.
+    pub fn tick(&self) -> u32 {
+        (self.server_time() * self.tick_rate) as u32
+    }
+
+    pub fn tick_rate(&self) -> f64 {
+        self.tick_rate
+    }
+
+    /// Server time at the start of `tick`.
+    pub fn tick_time(&self, tick: u32) -> f64 {
+        tick as f64 / self.tick_rate
+    }
+
+    /// Seconds to add to local time to get server time, `None` until the first answer arrived.
+    pub fn offset(&self) -> Option<f64> {
+        self.offset
+    }
+
+    /// Round trip time of the sample used for the offset.
+    pub fn rtt(&self) -> Option<Duration> {
+        self.rtt.map(Duration::from_secs_f64)
+    }
+
+    pub fn is_synchronized(&self) -> bool {
+        self.offset.is_some()
+    }
+
+    /// Tags `message` with the current server tick.
+    pub fn stamp<M>(&self, message: M) -> Stamped<M> {
+        Stamped {
+            tick: self.tick(),
+            message,
+        }
+    }
+
+    fn add_sample(&mut self, client_time: f64, server_time: f64) {
+        let now = self.local_time();
+        self.add_sample_at(now, client_time, server_time);
+    }
+
+    fn add_sample_at(&mut self, now: f64, client_time: f64, server_time: f64) {
+        if !client_time.is_finite() || !server_time.is_finite() {
+            log::debug!("Dropping clock answer with non-finite time");
+            return;
+        }
+        let rtt = now - client_time;
+        if rtt < 0.0 {
+            // answer to a request we never sent
+            return;
+        }
+        let offset = server_time + rtt / 2.0 - now;
+        self.samples.push_back((rtt, offset));
+        while self.samples.len() > self.max_samples {
+            self.samples.pop_front();
+        }
+
+        // queueing delays only ever add to the round trip, trust the fastest one
+        if let Some((rtt, offset)) = self
+            .samples
+            .iter()
+            .copied()
+            .min_by(|(a, _), (b, _)| a.total_cmp(b))
+        {
+            self.rtt = Some(rtt);
+            self.offset = Some(offset);
+        }
+    }
+}
+
+pub fn clock_server_system(mut net: ResMut<NetworkResource>, time: Res<NetworkTime>) {
+    for (handle, connection) in net.connections.iter_mut() {
+        if connection.info().role != ConnectionRole::Server {
+            continue;
+        }
+        let channels = match connection.channels() {
+            Some(channels) => channels,
+            None => continue,
+        };
+        let mut answered = false;
+        while let Some(message) = channels.recv::<ClockMessage>() {
+            if let ClockMessage::Request { client_time } = message {
+                let response = ClockMessage::Response {
+                    client_time,
+                    server_time: time.local_time(),
+                };
+                if channels.send(response).is_some() {
+                    log::debug!("Clock channel of [{}] is full", handle);
+                }
+                answered = true;
+            }
+        }
+        if answered {
+            channels.flush::<ClockMessage>();
+        }
+    }
+}
+
+pub fn clock_client_system(
+    mut net: ResMut<NetworkResource>,
+    mut time: ResMut<NetworkTime>,
+    settings: Res<ClockSyncSettings>,
+) {
+    let request = time
+        .last_request
+        .is_none_or(|last| last.elapsed() >= settings.sync_interval);
+    let client_time = time.local_time();
+
+    let mut requested = false;
+    for (handle, connection) in net.connections.iter_mut() {
+        if connection.info().role != ConnectionRole::Client {
+            continue;
+        }
+        let channels = match connection.channels() {
+            Some(channels) => channels,
+            None => continue,
+        };
+        while let Some(message) = channels.recv::<ClockMessage>() {
+            if let ClockMessage::Response {
+                client_time,
+                server_time,
+            } = message
+            {
+                time.add_sample(client_time, server_time);
+            }
+        }
+        if request {
+            if channels
+                .send(ClockMessage::Request { client_time })
+                .is_some()
+            {
+                log::debug!("Clock channel of [{}] is full", handle);
+            }
+            channels.flush::<ClockMessage>();
+            requested = true;
+        }
+    }
+    if requested {
+        time.last_request = Some(Instant::now());
+    }
+}
+
+pub struct ClockSyncSettings {
+    /// How often clients ask for the server time.
+    pub sync_interval: Duration,
+}
+
+/// Maintains the `NetworkTime` resource.
+pub struct ClockSyncPlugin {
+    pub channel: u8,
+    /// Server ticks per second.
+    pub tick_rate: f64,
+    pub sync_interval: Duration,
+    /// Number of recent answers the offset is picked from.
+    pub samples: usize,
+}
+
+impl Default for ClockSyncPlugin {
+    fn default() -> Self {
+        ClockSyncPlugin {
+            channel: CLOCK_CHANNEL,
+            tick_rate: 60.0,
+            sync_interval: Duration::from_secs(1),
+            samples: 8,
+        }
+    }
+}
+
+impl Plugin for ClockSyncPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        let channel = self.channel;
+        app.world_mut()
+            .get_resource_mut::<NetworkResource>()
+            .expect("`NetworkingPlugin` has to be added before `ClockSyncPlugin`")
+            .add_channels_builder(move |builder: &mut ConnectionChannelsBuilder| {
+                builder
+                    .register::<ClockMessage>(MessageChannelSettings {
+                        channel,
+                        channel_mode: MessageChannelMode::Unreliable,
+                        message_buffer_size: 8,
+                        packet_buffer_size: 8,
+                    })
+                    .unwrap();
+            });
+
+        app.insert_resource(NetworkTime::new(self.tick_rate, self.samples))
+            .insert_resource(ClockSyncSettings {
+                sync_interval: self.sync_interval,
+            })
+            .add_system_to_stage(CoreStage::PreUpdate, clock_server_system.system())
+            .add_system_to_stage(CoreStage::PreUpdate, clock_client_system.system());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn offset_assumes_symmetric_latency() {
+        let mut time = NetworkTime::new(60.0, 4);
+        // sent at 1.0, server answered at 10.5, received at 2.0
+        time.add_sample_at(2.0, 1.0, 10.5);
+        assert_eq!(time.offset(), Some(9.0));
+        assert_eq!(time.rtt(), Some(Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn offset_of_fastest_recent_round_trip() {
+        let mut time = NetworkTime::new(60.0, 2);
+        time.add_sample_at(1.0, 0.5, 10.0);
+        time.add_sample_at(3.0, 1.0, 12.0);
+        assert_eq!(time.offset(), Some(9.25));
+        assert_eq!(time.rtt(), Some(Duration::from_millis(500)));
+
+        // the fast sample falls out of the window
+        time.add_sample_at(5.0, 3.0, 14.0);
+        assert_eq!(time.offset(), Some(10.0));
+        assert_eq!(time.rtt(), Some(Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn bogus_answers_are_ignored() {
+        let mut time = NetworkTime::new(60.0, 4);
+        time.add_sample_at(1.0, 2.0, 10.0);
+        time.add_sample_at(1.0, f64::NAN, 10.0);
+        time.add_sample_at(1.0, 0.5, f64::INFINITY);
+        assert!(!time.is_synchronized());
+
+        time.add_sample_at(1.0, 0.5, 10.0);
+        time.add_sample_at(1.0, 0.5, f64::NAN);
+        assert_eq!(time.offset(), Some(9.25));
+    }
+
+    #[test]
+    fn ticks_follow_tick_rate() {
+        let time = NetworkTime::new(20.0, 4);
+        assert_eq!(time.tick_time(30), 1.5);
+        assert_eq!(time.tick_rate(), 20.0);
+    }
+}
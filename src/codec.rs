@@ -0,0 +1,660 @@
+//! Per channel message codecs.
+//!
+//! Turbulence serializes channel messages with bincode default options, fixed size integers
+//! included. A message type registered with a `MessageCodec` is encoded by that codec instead,
+//! and travels over its channel as opaque bytes wrapped in `Encoded`.
+//! `NetworkResource::send_message`, `broadcast_message` and `recv_message` apply the codec
+//! transparently, sending through `MessageChannels` directly bypasses it.
+
+use bincode::Options;
+use serde::{
+    de::{self, DeserializeOwned, SeqAccess, Visitor},
+    ser::SerializeTuple,
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+use std::{
+    any::{Any, TypeId},
+    collections::HashMap,
+    error::Error,
+    fmt,
+    marker::PhantomData,
+    sync::Arc,
+};
+
+use super::{
+    transport::copy_channel_settings, ConnectionChannelsBuilder, MessageChannelSettings,
+    NetworkResource,
+};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CodecError(pub String);
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Codec error: {}", self.0)
+    }
+}
+
+impl Error for CodecError {}
+
+impl From<bincode::Error> for CodecError {
+    fn from(error: bincode::Error) -> Self {
+        CodecError(error.to_string())
+    }
+}
+
+/// Converts messages of type `M` to bytes and back.
+pub trait MessageCodec<M>: Send + Sync + 'static {
+    fn encode(&self, message: &M) -> Result<Vec<u8>, CodecError>;
+    fn decode(&self, bytes: &[u8]) -> Result<M, CodecError>;
+
+    /// Statistics of codecs keeping track of their output, see `NetworkResource::channel_stats`.
+    fn stats(&self) -> Option<ChannelStats> {
+        None
+    }
+}
+
+/// Sizes of messages before and after encoding, in both directions.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ChannelStats {
+    pub messages_tx: usize,
+    pub messages_rx: usize,
+    /// Sizes of the wrapped encoding.
+    pub raw_bytes_tx: usize,
+    pub raw_bytes_rx: usize,
+    /// Sizes as sent over the channel.
+    pub bytes_tx: usize,
+    pub bytes_rx: usize,
+}
+
+impl ChannelStats {
+    /// Sent bytes per raw byte, below 1.0 when compression pays off.
+    pub fn compression_ratio_tx(&self) -> f32 {
+        ratio(self.bytes_tx, self.raw_bytes_tx)
+    }
+
+    pub fn compression_ratio_rx(&self) -> f32 {
+        ratio(self.bytes_rx, self.raw_bytes_rx)
+    }
+}
+
+fn ratio(bytes: usize, raw_bytes: usize) -> f32 {
+    if raw_bytes == 0 {
+        1.0
+    } else {
+        bytes as f32 / raw_bytes as f32
+    }
+}
+
+/// Bincode with the same options turbulence uses, for wrapping in other codecs.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct BincodeCodec;
+
+impl<M: Serialize + DeserializeOwned> MessageCodec<M> for BincodeCodec {
+    fn encode(&self, message: &M) -> Result<Vec<u8>, CodecError> {
+        Ok(bincode::serialize(message)?)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<M, CodecError> {
+        Ok(bincode::deserialize(bytes)?)
+    }
+}
+
+/// Bincode with variable length integers, small values take a single byte.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct VarintCodec;
+
+impl<M: Serialize + DeserializeOwned> MessageCodec<M> for VarintCodec {
+    fn encode(&self, message: &M) -> Result<Vec<u8>, CodecError> {
+        Ok(bincode::DefaultOptions::new().serialize(message)?)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<M, CodecError> {
+        Ok(bincode::DefaultOptions::new().deserialize(bytes)?)
+    }
+}
+
+/// Types written bit by bit, see `BitPackCodec`.
+pub trait BitPack: Sized {
+    fn pack(&self, writer: &mut BitWriter);
+    fn unpack(reader: &mut BitReader) -> Result<Self, CodecError>;
+}
+
+/// Encodes `BitPack` messages.
+pub struct BitPackCodec<M>(PhantomData<fn() -> M>);
+
+impl<M> Default for BitPackCodec<M> {
+    fn default() -> Self {
+        BitPackCodec(PhantomData)
+    }
+}
+
+impl<M: BitPack + 'static> MessageCodec<M> for BitPackCodec<M> {
+    fn encode(&self, message: &M) -> Result<Vec<u8>, CodecError> {
+        let mut writer = BitWriter::default();
+        message.pack(&mut writer);
+        Ok(writer.finish())
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<M, CodecError> {
+        M::unpack(&mut BitReader::new(bytes))
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct BitWriter {
+    bytes: Vec<u8>,
+    bits: usize,
+}
+
+impl BitWriter {
+    /// Writes the lowest `bits` bits of `value`.
+    pub fn write_bits(&mut self, value: u64, bits: u32) {
+        debug_assert!(bits <= 64);
+        for bit in 0..bits {
+            if self.bits.is_multiple_of(8) {
+                self.bytes.push(0);
+            }
+            if value >> bit & 1 == 1 {
+                *self.bytes.last_mut().unwrap() |= 1 << (self.bits % 8);
+            }
+            self.bits += 1;
+        }
+    }
+
+    pub fn write_bool(&mut self, value: bool) {
+        self.write_bits(value as u64, 1);
+    }
+
+    /// Writes 7 bits at a time, small values take fewer bits.
+    pub fn write_varint(&mut self, mut value: u64) {
+        loop {
+            let more = value >= 0x80;
+            self.write_bits(value & 0x7f, 7);
+            self.write_bool(more);
+            value >>= 7;
+            if !more {
+                break;
+            }
+        }
+    }
+
+    /// Writes `value` clamped to `min..=max` with `bits` bits of precision.
+    pub fn write_quantized(&mut self, value: f32, min: f32, max: f32, bits: u32) {
+        debug_assert!(bits < 64);
+        let steps = ((1u64 << bits) - 1) as f32;
+        let normalized = (value.max(min).min(max) - min) / (max - min);
+        self.write_bits((normalized * steps).round() as u64, bits);
+    }
+
+    pub fn len_bits(&self) -> usize {
+        self.bits
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+#[derive(Debug)]
+pub struct BitReader<'a> {
+    bytes: &'a [u8],
+    bits: usize,
+}
+
+impl<'a> BitReader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        BitReader { bytes, bits: 0 }
+    }
+
+    pub fn read_bits(&mut self, bits: u32) -> Result<u64, CodecError> {
+        debug_assert!(bits <= 64);
+        if self.bits + bits as usize > self.bytes.len() * 8 {
+            return Err(CodecError(
+                "Unexpected end of bit packed message".to_string(),
+            ));
+        }
+        let mut value = 0;
+        for bit in 0..bits {
+            if self.bytes[self.bits / 8] >> (self.bits % 8) & 1 == 1 {
+                value |= 1 << bit;
+            }
+            self.bits += 1;
+        }
+        Ok(value)
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, CodecError> {
+        Ok(self.read_bits(1)? == 1)
+    }
+
+    pub fn read_varint(&mut self) -> Result<u64, CodecError> {
+        let mut value = 0;
+        for shift in (0..64).step_by(7) {
+            value |= self.read_bits(7)? << shift;
+            if !self.read_bool()? {
+                return Ok(value);
+            }
+        }
+        Err(CodecError("Varint is too long".to_string()))
+    }
+
+    pub fn read_quantized(&mut self, min: f32, max: f32, bits: u32) -> Result<f32, CodecError> {
+        debug_assert!(bits < 64);
+        let steps = ((1u64 << bits) - 1) as f32;
+        Ok(min + self.read_bits(bits)? as f32 / steps * (max - min))
+    }
+}
+
+macro_rules! bit_pack_fixed {
+    ($($ty:ty: $bits:expr),*) => {
+        $(
+            impl BitPack for $ty {
+                fn pack(&self, writer: &mut BitWriter) {
+                    writer.write_bits(*self as u64, $bits);
+                }
+
+                fn unpack(reader: &mut BitReader) -> Result<Self, CodecError> {
+                    Ok(reader.read_bits($bits)? as $ty)
+                }
+            }
+        )*
+    };
+}
+
+bit_pack_fixed!(u8: 8, u16: 16);
+
+impl BitPack for bool {
+    fn pack(&self, writer: &mut BitWriter) {
+        writer.write_bool(*self);
+    }
+
+    fn unpack(reader: &mut BitReader) -> Result<Self, CodecError> {
+        reader.read_bool()
+    }
+}
+
+impl BitPack for u32 {
+    fn pack(&self, writer: &mut BitWriter) {
+        writer.write_varint(*self as u64);
+    }
+
+    fn unpack(reader: &mut BitReader) -> Result<Self, CodecError> {
+        Ok(reader.read_varint()? as u32)
+    }
+}
+
+impl BitPack for u64 {
+    fn pack(&self, writer: &mut BitWriter) {
+        writer.write_varint(*self);
+    }
+
+    fn unpack(reader: &mut BitReader) -> Result<Self, CodecError> {
+        reader.read_varint()
+    }
+}
+
+impl BitPack for i32 {
+    fn pack(&self, writer: &mut BitWriter) {
+        // zigzag, so small negative values stay small
+        writer.write_varint(((*self << 1) ^ (*self >> 31)) as u32 as u64);
+    }
+
+    fn unpack(reader: &mut BitReader) -> Result<Self, CodecError> {
+        let value = reader.read_varint()? as u32;
+        Ok((value >> 1) as i32 ^ -((value & 1) as i32))
+    }
+}
+
+impl BitPack for f32 {
+    fn pack(&self, writer: &mut BitWriter) {
+        writer.write_bits(self.to_bits() as u64, 32);
+    }
+
+    fn unpack(reader: &mut BitReader) -> Result<Self, CodecError> {
+        Ok(f32::from_bits(reader.read_bits(32)? as u32))
+    }
+}
+
+impl<T: BitPack> BitPack for Option<T> {
+    fn pack(&self, writer: &mut BitWriter) {
+        writer.write_bool(self.is_some());
+        if let Some(value) = self {
+            value.pack(writer);
+        }
+    }
+
+    fn unpack(reader: &mut BitReader) -> Result<Self, CodecError> {
+        if reader.read_bool()? {
+            Ok(Some(T::unpack(reader)?))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+impl<T: BitPack> BitPack for Vec<T> {
+    fn pack(&self, writer: &mut BitWriter) {
+        writer.write_varint(self.len() as u64);
+        for value in self.iter() {
+            value.pack(writer);
+        }
+    }
+
+    fn unpack(reader: &mut BitReader) -> Result<Self, CodecError> {
+        let len = reader.read_varint()? as usize;
+        // every element takes at least a bit, do not trust the length blindly
+        let mut values = Vec::with_capacity(len.min(reader.bytes.len() * 8));
+        for _ in 0..len {
+            values.push(T::unpack(reader)?);
+        }
+        Ok(values)
+    }
+}
+
+/// Codec encoded message as sent over the channel.
+///
+/// Serialized as a tuple of bytes, a varint length followed by the encoded message,
+/// so bincode does not add its 8 byte sequence length.
+pub struct Encoded<M> {
+    bytes: Vec<u8>,
+    marker: PhantomData<fn() -> M>,
+}
+
+impl<M: 'static> Encoded<M> {
+    pub(crate) fn encode(codec: &dyn MessageCodec<M>, message: &M) -> Result<Self, CodecError> {
+        codec.encode(message).map(Encoded::new)
+    }
+
+    pub(crate) fn decode(&self, codec: &dyn MessageCodec<M>) -> Result<M, CodecError> {
+        codec.decode(&self.bytes)
+    }
+}
+
+impl<M> Encoded<M> {
+    fn new(bytes: Vec<u8>) -> Self {
+        Encoded {
+            bytes,
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<M> Clone for Encoded<M> {
+    fn clone(&self) -> Self {
+        Encoded::new(self.bytes.clone())
+    }
+}
+
+impl<M> fmt::Debug for Encoded<M> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Encoded")
+            .field("len", &self.bytes.len())
+            .finish()
+    }
+}
+
+impl<M> Serialize for Encoded<M> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut len = Vec::with_capacity(5);
+        let mut value = self.bytes.len();
+        loop {
+            let more = value >= 0x80;
+            len.push((value & 0x7f) as u8 | if more { 0x80 } else { 0 });
+            value >>= 7;
+            if !more {
+                break;
+            }
+        }
+
+        let mut tuple = serializer.serialize_tuple(len.len() + self.bytes.len())?;
+        for byte in len.iter().chain(self.bytes.iter()) {
+            tuple.serialize_element(byte)?;
+        }
+        tuple.end()
+    }
+}
+
+impl<'de, M> Deserialize<'de> for Encoded<M> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct EncodedVisitor<M>(PhantomData<fn() -> M>);
+
+        impl<'de, M> Visitor<'de> for EncodedVisitor<M> {
+            type Value = Encoded<M>;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("varint length prefixed bytes")
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+                let mut len = 0usize;
+                for shift in (0..35).step_by(7) {
+                    let byte: u8 = seq
+                        .next_element()?
+                        .ok_or_else(|| de::Error::custom("missing length"))?;
+                    len |= ((byte & 0x7f) as usize) << shift;
+                    if byte & 0x80 == 0 {
+                        let mut bytes = Vec::with_capacity(len.min(u16::MAX as usize));
+                        for _ in 0..len {
+                            bytes
+                                .push(seq.next_element()?.ok_or_else(|| {
+                                    de::Error::invalid_length(bytes.len(), &self)
+                                })?);
+                        }
+                        return Ok(Encoded::new(bytes));
+                    }
+                }
+                Err(de::Error::custom("length is too long"))
+            }
+        }
+
+        // the actual length is not known upfront, the visitor stops after the encoded bytes
+        deserializer.deserialize_tuple(usize::MAX, EncodedVisitor(PhantomData))
+    }
+}
+
+/// Codecs by message type, kept in `NetworkResource`.
+#[derive(Default)]
+pub(crate) struct CodecRegistry(HashMap<TypeId, Box<dyn Any + Send + Sync>>);
+
+impl CodecRegistry {
+    fn insert<M: 'static>(&mut self, codec: Arc<dyn MessageCodec<M>>) {
+        self.0.insert(TypeId::of::<M>(), Box::new(codec));
+    }
+
+    pub fn get<M: 'static>(&self) -> Option<Arc<dyn MessageCodec<M>>> {
+        self.0
+            .get(&TypeId::of::<M>())
+            .and_then(|codec| codec.downcast_ref::<Arc<dyn MessageCodec<M>>>())
+            .cloned()
+    }
+}
+
+impl NetworkResource {
+    /// Statistics of the codec registered for `M`, if it keeps any.
+    pub fn channel_stats<M: 'static>(&self) -> Option<ChannelStats> {
+        self.codecs.get::<M>().and_then(|codec| codec.stats())
+    }
+
+    /// Registers message type `M` on the channel given in `settings`, encoded with `codec`.
+    pub fn register_codec<M, C>(&mut self, settings: MessageChannelSettings, codec: C)
+    where
+        M: Send + Sync + 'static,
+        C: MessageCodec<M>,
+    {
+        self.add_codec(None, settings, codec);
+    }
+
+    pub(crate) fn add_codec<M, C>(
+        &mut self,
+        name: Option<&'static str>,
+        settings: MessageChannelSettings,
+        codec: C,
+    ) where
+        M: Send + Sync + 'static,
+        C: MessageCodec<M>,
+    {
+        self.codecs.insert::<M>(Arc::new(codec));
+        self.add_channels_builder(move |builder: &mut ConnectionChannelsBuilder| {
+            let settings = copy_channel_settings(&settings);
+            let channel = settings.channel;
+            let result = match name {
+                Some(name) => builder.register_named::<Encoded<M>>(name, settings),
+                None => builder.register::<Encoded<M>>(settings),
+            };
+            if let Err(error) = result {
+                log::error!(
+                    "Failed to register codec for {} on channel {}: {}",
+                    std::any::type_name::<M>(),
+                    channel,
+                    error
+                );
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WIDTHS: [u32; 10] = [1, 7, 8, 9, 15, 16, 17, 32, 63, 64];
+
+    fn max_value(bits: u32) -> u64 {
+        u64::MAX >> (64 - bits)
+    }
+
+    #[test]
+    fn bits_round_trip_at_width_boundaries() {
+        let mut writer = BitWriter::default();
+        for bits in WIDTHS.iter().copied() {
+            writer.write_bits(max_value(bits), bits);
+            writer.write_bits(0, bits);
+            writer.write_bits(1 << (bits - 1), bits);
+        }
+        let len_bits = writer.len_bits();
+        assert_eq!(len_bits, 3 * WIDTHS.iter().sum::<u32>() as usize);
+        let bytes = writer.finish();
+        assert!(bytes.len() * 8 >= len_bits && bytes.len() * 8 < len_bits + 8);
+
+        let mut reader = BitReader::new(&bytes);
+        for bits in WIDTHS.iter().copied() {
+            assert_eq!(reader.read_bits(bits).unwrap(), max_value(bits));
+            assert_eq!(reader.read_bits(bits).unwrap(), 0);
+            assert_eq!(reader.read_bits(bits).unwrap(), 1u64 << (bits - 1));
+        }
+    }
+
+    #[test]
+    fn bits_above_width_are_dropped() {
+        let mut writer = BitWriter::default();
+        writer.write_bits(0x1ff, 8);
+        writer.write_bits(0b10, 1);
+        let bytes = writer.finish();
+        let mut reader = BitReader::new(&bytes);
+        assert_eq!(reader.read_bits(8).unwrap(), 0xff);
+        assert_eq!(reader.read_bits(1).unwrap(), 0);
+    }
+
+    #[test]
+    fn reading_past_the_end_fails() {
+        let mut writer = BitWriter::default();
+        writer.write_bits(0x5, 3);
+        let bytes = writer.finish();
+        let mut reader = BitReader::new(&bytes);
+        assert_eq!(reader.read_bits(3).unwrap(), 0x5);
+        // padding of the last byte
+        assert_eq!(reader.read_bits(5).unwrap(), 0);
+        assert!(reader.read_bits(1).is_err());
+        assert!(BitReader::new(&[]).read_bool().is_err());
+    }
+
+    #[test]
+    fn varints_round_trip_at_group_boundaries() {
+        let values = [
+            0,
+            0x7f,
+            0x80,
+            0x3fff,
+            0x4000,
+            u32::MAX as u64,
+            u64::MAX >> 1,
+            u64::MAX,
+        ];
+        let mut writer = BitWriter::default();
+        for value in values.iter() {
+            writer.write_varint(*value);
+        }
+        let bytes = writer.finish();
+        let mut reader = BitReader::new(&bytes);
+        for value in values.iter() {
+            assert_eq!(reader.read_varint().unwrap(), *value);
+        }
+
+        let mut writer = BitWriter::default();
+        writer.write_varint(0x7f);
+        assert_eq!(writer.len_bits(), 8);
+        writer.write_varint(0x80);
+        assert_eq!(writer.len_bits(), 8 + 16);
+    }
+
+    #[test]
+    fn bit_pack_types_round_trip() {
+        let ints = vec![0, 1, -1, 63, -64, 64, -65, i32::MAX, i32::MIN];
+        let options = vec![None, Some(u8::MAX), Some(0)];
+        let mut writer = BitWriter::default();
+        ints.pack(&mut writer);
+        options.pack(&mut writer);
+        u16::MAX.pack(&mut writer);
+        u32::MAX.pack(&mut writer);
+        true.pack(&mut writer);
+        f32::MIN_POSITIVE.pack(&mut writer);
+        writer.write_quantized(0.3, -1.0, 1.0, 10);
+        writer.write_quantized(5.0, -1.0, 1.0, 10);
+        let bytes = writer.finish();
+
+        let mut reader = BitReader::new(&bytes);
+        assert_eq!(Vec::<i32>::unpack(&mut reader).unwrap(), ints);
+        assert_eq!(Vec::<Option<u8>>::unpack(&mut reader).unwrap(), options);
+        assert_eq!(u16::unpack(&mut reader).unwrap(), u16::MAX);
+        assert_eq!(u32::unpack(&mut reader).unwrap(), u32::MAX);
+        assert!(bool::unpack(&mut reader).unwrap());
+        assert_eq!(f32::unpack(&mut reader).unwrap(), f32::MIN_POSITIVE);
+        let step = 2.0 / 1023.0;
+        assert!((reader.read_quantized(-1.0, 1.0, 10).unwrap() - 0.3).abs() <= step / 2.0);
+        // clamped to the range
+        assert_eq!(reader.read_quantized(-1.0, 1.0, 10).unwrap(), 1.0);
+    }
+
+    #[test]
+    fn encoded_serializes_with_varint_length() {
+        for len in [0, 1, 0x7f, 0x80, 0x3fff, 0x4000].iter().copied() {
+            let bytes: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            let encoded = Encoded::<()>::new(bytes.clone());
+            let serialized = bincode::serialize(&encoded).unwrap();
+            let prefix_len = match len {
+                0..=0x7f => 1,
+                0x80..=0x3fff => 2,
+                _ => 3,
+            };
+            assert_eq!(serialized.len(), prefix_len + len);
+            assert_eq!(&serialized[prefix_len..], &bytes[..]);
+
+            let deserialized: Encoded<()> = bincode::deserialize(&serialized).unwrap();
+            assert_eq!(deserialized.bytes, bytes);
+        }
+    }
+
+    #[test]
+    fn encoded_deserializes_within_other_fields() {
+        let value = (7u32, Encoded::<()>::new(vec![1, 2, 3]), 9u16);
+        let serialized = bincode::serialize(&value).unwrap();
+        let (before, encoded, after): (u32, Encoded<()>, u16) =
+            bincode::deserialize(&serialized).unwrap();
+        assert_eq!((before, after), (7, 9));
+        assert_eq!(encoded.bytes, vec![1, 2, 3]);
+
+        // length says more bytes than there are
+        assert!(bincode::deserialize::<Encoded<()>>(&[5, 1, 2]).is_err());
+    }
+}
@@ -0,0 +1,188 @@
+//! LZ4 compression of encoded messages, `compression` feature.
+//!
+//! `Compressed` wraps another codec. Messages encoded to at least `threshold` bytes are
+//! compressed, and sent uncompressed when that does not make them smaller. A leading flag byte
+//! tells the receiver which one it got.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use super::codec::{BincodeCodec, ChannelStats, CodecError, MessageCodec};
+
+const FLAG_RAW: u8 = 0;
+const FLAG_LZ4: u8 = 1;
+
+#[derive(Debug, Default)]
+struct AtomicStats {
+    messages_tx: AtomicUsize,
+    messages_rx: AtomicUsize,
+    raw_bytes_tx: AtomicUsize,
+    raw_bytes_rx: AtomicUsize,
+    bytes_tx: AtomicUsize,
+    bytes_rx: AtomicUsize,
+}
+
+/// Compresses the output of codec `C`.
+///
+/// ```ignore
+/// net.register_codec::<LevelData, _>(LEVEL_DATA_SETTINGS, Compressed::new(BincodeCodec));
+/// ```
+pub struct Compressed<C> {
+    codec: C,
+    /// Smaller messages are not worth compressing.
+    pub threshold: usize,
+    /// Largest accepted decompressed size, protects the receiver from decompression bombs.
+    pub max_decompressed_len: usize,
+    stats: AtomicStats,
+}
+
+impl<C> Compressed<C> {
+    pub fn new(codec: C) -> Self {
+        Compressed {
+            codec,
+            threshold: 256,
+            max_decompressed_len: 1 << 20,
+            stats: AtomicStats::default(),
+        }
+    }
+
+    pub fn with_threshold(mut self, threshold: usize) -> Self {
+        self.threshold = threshold;
+        self
+    }
+}
+
+impl Default for Compressed<BincodeCodec> {
+    fn default() -> Self {
+        Compressed::new(BincodeCodec)
+    }
+}
+
+impl<M, C: MessageCodec<M>> MessageCodec<M> for Compressed<C> {
+    fn encode(&self, message: &M) -> Result<Vec<u8>, CodecError> {
+        let raw = self.codec.encode(message)?;
+
+        let mut bytes = Vec::with_capacity(raw.len() + 1);
+        let compressed = if raw.len() >= self.threshold {
+            Some(lz4_flex::compress_prepend_size(&raw))
+                .filter(|compressed| compressed.len() < raw.len())
+        } else {
+            None
+        };
+        match compressed {
+            Some(compressed) => {
+                bytes.push(FLAG_LZ4);
+                bytes.extend_from_slice(&compressed);
+            }
+            None => {
+                bytes.push(FLAG_RAW);
+                bytes.extend_from_slice(&raw);
+            }
+        }
+
+        self.stats.messages_tx.fetch_add(1, Ordering::Relaxed);
+        self.stats
+            .raw_bytes_tx
+            .fetch_add(raw.len(), Ordering::Relaxed);
+        self.stats
+            .bytes_tx
+            .fetch_add(bytes.len(), Ordering::Relaxed);
+        Ok(bytes)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<M, CodecError> {
+        let (flag, payload) = bytes
+            .split_first()
+            .ok_or_else(|| CodecError("Empty compressed message".to_string()))?;
+        let raw = match *flag {
+            FLAG_RAW => payload.to_vec(),
+            FLAG_LZ4 => {
+                let len = payload
+                    .get(..4)
+                    .map(|len| u32::from_le_bytes([len[0], len[1], len[2], len[3]]) as usize)
+                    .ok_or_else(|| CodecError("Truncated compressed message".to_string()))?;
+                if len > self.max_decompressed_len {
+                    return Err(CodecError(format!(
+                        "Decompressed message of {} bytes exceeds {}",
+                        len, self.max_decompressed_len
+                    )));
+                }
+                lz4_flex::decompress_size_prepended(payload)
+                    .map_err(|error| CodecError(error.to_string()))?
+            }
+            flag => return Err(CodecError(format!("Unknown compression flag {}", flag))),
+        };
+
+        self.stats.messages_rx.fetch_add(1, Ordering::Relaxed);
+        self.stats
+            .raw_bytes_rx
+            .fetch_add(raw.len(), Ordering::Relaxed);
+        self.stats
+            .bytes_rx
+            .fetch_add(bytes.len(), Ordering::Relaxed);
+        self.codec.decode(&raw)
+    }
+
+    fn stats(&self) -> Option<ChannelStats> {
+        Some(ChannelStats {
+            messages_tx: self.stats.messages_tx.load(Ordering::Relaxed),
+            messages_rx: self.stats.messages_rx.load(Ordering::Relaxed),
+            raw_bytes_tx: self.stats.raw_bytes_tx.load(Ordering::Relaxed),
+            raw_bytes_rx: self.stats.raw_bytes_rx.load(Ordering::Relaxed),
+            bytes_tx: self.stats.bytes_tx.load(Ordering::Relaxed),
+            bytes_rx: self.stats.bytes_rx.load(Ordering::Relaxed),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compressed_round_trip() {
+        let codec = Compressed::default();
+        let large = vec![7u8; 4096];
+        let bytes = MessageCodec::<Vec<u8>>::encode(&codec, &large).unwrap();
+        assert_eq!(bytes[0], FLAG_LZ4);
+        assert!(bytes.len() < large.len());
+        assert_eq!(
+            MessageCodec::<Vec<u8>>::decode(&codec, &bytes).unwrap(),
+            large
+        );
+
+        let small = vec![7u8; 16];
+        let bytes = MessageCodec::<Vec<u8>>::encode(&codec, &small).unwrap();
+        assert_eq!(bytes[0], FLAG_RAW);
+        assert_eq!(
+            MessageCodec::<Vec<u8>>::decode(&codec, &bytes).unwrap(),
+            small
+        );
+
+        let stats = MessageCodec::<Vec<u8>>::stats(&codec).unwrap();
+        assert_eq!(stats.messages_tx, 2);
+        assert_eq!(stats.messages_rx, 2);
+        assert!(stats.bytes_tx < stats.raw_bytes_tx);
+    }
+
+    #[test]
+    fn decompressed_length_over_limit_is_rejected() {
+        let codec = Compressed {
+            max_decompressed_len: 1024,
+            ..Compressed::default()
+        };
+
+        let mut bomb = vec![FLAG_LZ4];
+        bomb.extend_from_slice(&lz4_flex::compress_prepend_size(&[0; 1 << 16]));
+        assert!(MessageCodec::<Vec<u8>>::decode(&codec, &bomb).is_err());
+
+        // a forged length is rejected before decompressing anything
+        let mut forged = vec![FLAG_LZ4];
+        forged.extend_from_slice(&u32::MAX.to_le_bytes());
+        assert!(MessageCodec::<Vec<u8>>::decode(&codec, &forged).is_err());
+
+        assert_eq!(
+            MessageCodec::<Vec<u8>>::stats(&codec).unwrap().messages_rx,
+            0
+        );
+    }
+}
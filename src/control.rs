@@ -0,0 +1,202 @@
+//! Control packets exchanged by datagram listeners and clients before a connection exists.
+//!
+//! They start with `CONTROL_MAGIC`, so connections can tell them apart from payload packets.
+//!
+//! A client first sends `ConnectRequest`. The server answers with a `Challenge` cookie without
+//! keeping any state, so spoofed source addresses cannot make it allocate connections.
+//! Only a `ChallengeResponse` echoing a valid cookie creates the connection.
+
+use std::fmt;
+
+use bincode::Options;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    access::IpRange,
+    token::{ConnectToken, ConnectTokenError, MAX_CONNECT_TOKEN_LEN},
+};
+
+pub(crate) const CONTROL_MAGIC: [u8; 8] = *b"\xffbnt\x00ctl";
+
+/// Connect requests are padded to this length, so answering them does not amplify traffic.
+pub(crate) const MIN_CONNECT_REQUEST_LEN: usize = 128;
+
+/// Largest control packet decoded, room for the largest connect token with a cookie.
+const MAX_CONTROL_PACKET_LEN: usize = CONTROL_MAGIC.len() + MAX_CONNECT_TOKEN_LEN + 64;
+
+/// Limits of datagram listeners on traffic from addresses without a connection.
+#[derive(Debug, Clone)]
+pub struct ListenerSettings {
+    /// Connections accepted by listeners, but not yet picked up by `receive_packets`.
+    pub max_pending_connections: usize,
+    /// Connect requests and challenge responses allowed per second from a single IP.
+    pub handshake_rate: f32,
+    /// Burst of handshake packets allowed from a single IP.
+    pub handshake_burst: f32,
+    /// Packets queued per connection until `receive_packets` picks them up.
+    /// Further packets are dropped, see `NetworkError::QueueOverflow`.
+    pub receive_queue_len: usize,
+    /// Only these addresses may connect, for private servers. `None` admits everyone.
+    pub allowlist: Option<Vec<IpRange>>,
+}
+
+impl Default for ListenerSettings {
+    fn default() -> Self {
+        ListenerSettings {
+            max_pending_connections: 64,
+            handshake_rate: 10.0,
+            handshake_burst: 20.0,
+            receive_queue_len: 1024,
+            allowlist: None,
+        }
+    }
+}
+
+/// Why the server refused a connection, see `NetworkError::Rejected`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RejectReason {
+    InvalidToken,
+    ExpiredToken,
+    TokenReused,
+    /// Server reached `NetworkingPlugin::max_connections`.
+    ServerFull,
+}
+
+impl From<ConnectTokenError> for RejectReason {
+    fn from(error: ConnectTokenError) -> Self {
+        match error {
+            ConnectTokenError::Malformed | ConnectTokenError::InvalidSignature => {
+                RejectReason::InvalidToken
+            }
+            ConnectTokenError::Expired => RejectReason::ExpiredToken,
+            ConnectTokenError::Reused => RejectReason::TokenReused,
+        }
+    }
+}
+
+impl fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RejectReason::InvalidToken => write!(f, "invalid connect token"),
+            RejectReason::ExpiredToken => write!(f, "connect token expired"),
+            RejectReason::TokenReused => write!(f, "connect token already used"),
+            RejectReason::ServerFull => write!(f, "server is full"),
+        }
+    }
+}
+
+/// Proof that the client receives packets sent to its address.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct Cookie {
+    /// Seconds since UNIX epoch.
+    pub issued_at: u64,
+    pub mac: [u8; 32],
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) enum ControlPacket {
+    /// Client asks for a connection, padded to `MIN_CONNECT_REQUEST_LEN`.
+    ConnectRequest,
+    Challenge {
+        cookie: Cookie,
+    },
+    ChallengeResponse {
+        cookie: Cookie,
+        token: Option<ConnectToken>,
+    },
+    /// Server created the connection, payload packets are accepted now.
+    Accepted,
+    Rejected(RejectReason),
+    /// First message of WebSocket clients, TCP already proved their address.
+    Connect {
+        token: Option<ConnectToken>,
+    },
+}
+
+impl ControlPacket {
+    pub fn is_control(payload: &[u8]) -> bool {
+        payload.starts_with(&CONTROL_MAGIC)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = CONTROL_MAGIC.to_vec();
+        bytes.extend(bincode::serialize(self).expect("control packet serializes"));
+        bytes
+    }
+
+    pub fn encode_padded(&self, len: usize) -> Vec<u8> {
+        let mut bytes = self.encode();
+        if bytes.len() < len {
+            bytes.resize(len, 0);
+        }
+        bytes
+    }
+
+    pub fn decode(payload: &[u8]) -> Option<Self> {
+        if !Self::is_control(payload) || payload.len() > MAX_CONTROL_PACKET_LEN {
+            return None;
+        }
+        // same encoding as `bincode::serialize`, connect requests carry padding
+        let packet: Self = bincode::DefaultOptions::new()
+            .with_fixint_encoding()
+            .allow_trailing_bytes()
+            .with_limit(MAX_CONTROL_PACKET_LEN as u64)
+            .deserialize(&payload[CONTROL_MAGIC.len()..])
+            .ok()?;
+        match &packet {
+            ControlPacket::ChallengeResponse {
+                token: Some(token), ..
+            }
+            | ControlPacket::Connect { token: Some(token) }
+                if token.to_bytes().len() > MAX_CONNECT_TOKEN_LEN =>
+            {
+                None
+            }
+            _ => Some(packet),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejected_round_trip() {
+        let bytes = ControlPacket::Rejected(RejectReason::ServerFull).encode();
+        assert!(matches!(
+            ControlPacket::decode(&bytes),
+            Some(ControlPacket::Rejected(RejectReason::ServerFull))
+        ));
+    }
+
+    #[test]
+    fn truncated_packets_are_refused() {
+        let bytes = ControlPacket::Rejected(RejectReason::ServerFull).encode();
+        for len in 0..bytes.len() {
+            assert!(
+                ControlPacket::decode(&bytes[..len]).is_none(),
+                "{} bytes",
+                len
+            );
+        }
+    }
+
+    #[test]
+    fn bad_magic_is_refused() {
+        let mut bytes = ControlPacket::Accepted.encode();
+        assert!(ControlPacket::decode(&bytes).is_some());
+        bytes[1] ^= 1;
+        assert!(!ControlPacket::is_control(&bytes));
+        assert!(ControlPacket::decode(&bytes).is_none());
+    }
+
+    #[test]
+    fn unknown_reject_reason_is_refused() {
+        let mut bytes = ControlPacket::Rejected(RejectReason::ServerFull).encode();
+        // the reason is the trailing variant index
+        let len = bytes.len();
+        bytes[len - 4..].copy_from_slice(&99u32.to_le_bytes());
+        assert!(ControlPacket::decode(&bytes).is_none());
+    }
+}
@@ -0,0 +1,577 @@
+//! Encrypted transport, `encryption` feature.
+//!
+//! `EncryptedConnection` wraps another connection and seals everything it sends, raw packets and
+//! message channel traffic alike. When connecting, peers exchange ephemeral x25519 keys in hello
+//! packets. After that, every packet is encrypted and authenticated with ChaCha20-Poly1305 under
+//! a key for its direction. Packets that fail authentication, or that were already received,
+//! are dropped.
+//!
+//! Without `EncryptionConfig::psk` the key exchange is unauthenticated. It stops eavesdropping
+//! and spoofed packets, but not an active man in the middle. Set the same pre-shared key on both
+//! ends to rule that out.
+
+use std::{error::Error, net::SocketAddr};
+
+#[cfg(not(target_arch = "wasm32"))]
+use bevy_tasks::Task;
+use bevy_tasks::TaskPool;
+use chacha20poly1305::{
+    aead::{Aead, NewAead, Payload},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use crossbeam_channel::{bounded, Receiver, Sender, TrySendError};
+use futures_lite::StreamExt;
+use instant::{Duration, Instant};
+use sha2::{Digest, Sha256};
+use turbulence::{
+    message_channels::MessageChannels,
+    packet_multiplexer::{IncomingMultiplexedPackets, PacketMultiplexer},
+};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use super::{
+    transport::MultiplexedPacket, Connection, ConnectionChannelsBuilder, ConnectionInfo,
+    ConnectionRole, NetworkError, Packet, PacketStats,
+};
+
+const PACKET_HELLO: u8 = 1;
+const PACKET_DATA: u8 = 2;
+
+const KEY_LEN: usize = 32;
+const TAG_LEN: usize = 16;
+// packet type and little endian counter, authenticated with the payload
+const DATA_HEADER_LEN: usize = 9;
+// nonce of the server hello key confirmation, never used for data
+const CONFIRM_COUNTER: u64 = u64::MAX;
+
+const HELLO_RESEND_INTERVAL: Duration = Duration::from_millis(250);
+// packets waiting for the key exchange or the next flush
+const OUTGOING_QUEUE_LEN: usize = 1024;
+
+/// Settings of `EncryptedConnection`, see `NetworkingPlugin::encryption`.
+#[derive(Clone)]
+pub struct EncryptionConfig {
+    /// Mixed into the session keys. Peers with different keys cannot talk to each other.
+    pub psk: Option<[u8; 32]>,
+    /// Connections not finishing the key exchange in time are disconnected.
+    pub handshake_timeout: Duration,
+}
+
+impl Default for EncryptionConfig {
+    fn default() -> Self {
+        EncryptionConfig {
+            psk: None,
+            handshake_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Sliding window of the last 64 received counters.
+#[derive(Default)]
+struct ReplayWindow {
+    latest: Option<u64>,
+    // bit `n` is set when `latest - n` was received
+    seen: u64,
+}
+
+impl ReplayWindow {
+    fn is_fresh(&self, counter: u64) -> bool {
+        match self.latest {
+            None => true,
+            Some(latest) if counter > latest => true,
+            Some(latest) => {
+                let age = latest - counter;
+                age < 64 && self.seen & (1 << age) == 0
+            }
+        }
+    }
+
+    fn accept(&mut self, counter: u64) {
+        match self.latest {
+            Some(latest) if counter <= latest => self.seen |= 1 << (latest - counter),
+            latest => {
+                let shift = latest.map_or(64, |latest| counter - latest);
+                self.seen = if shift >= 64 { 0 } else { self.seen << shift };
+                self.seen |= 1;
+                self.latest = Some(counter);
+            }
+        }
+    }
+}
+
+struct Session {
+    send: ChaCha20Poly1305,
+    recv: ChaCha20Poly1305,
+    send_counter: u64,
+    replay: ReplayWindow,
+}
+
+impl Session {
+    fn new(
+        secret: &StaticSecret,
+        client: &PublicKey,
+        server: &PublicKey,
+        role: ConnectionRole,
+        psk: Option<&[u8; 32]>,
+    ) -> Self {
+        let remote = match role {
+            ConnectionRole::Client => server,
+            ConnectionRole::Server => client,
+        };
+        let shared = secret.diffie_hellman(remote);
+        let client_to_server =
+            derive_key(b"client to server", shared.as_bytes(), client, server, psk);
+        let server_to_client =
+            derive_key(b"server to client", shared.as_bytes(), client, server, psk);
+        let (send, recv) = match role {
+            ConnectionRole::Client => (client_to_server, server_to_client),
+            ConnectionRole::Server => (server_to_client, client_to_server),
+        };
+        Session {
+            send,
+            recv,
+            send_counter: 0,
+            replay: ReplayWindow::default(),
+        }
+    }
+
+    fn seal(&mut self, payload: &[u8]) -> Option<Packet> {
+        let counter = self.send_counter;
+        self.send_counter += 1;
+
+        let mut packet = Vec::with_capacity(DATA_HEADER_LEN + payload.len() + TAG_LEN);
+        packet.push(PACKET_DATA);
+        packet.extend_from_slice(&counter.to_le_bytes());
+        let sealed = self
+            .send
+            .encrypt(
+                &Nonce::from(nonce(counter)),
+                Payload {
+                    msg: payload,
+                    aad: &packet,
+                },
+            )
+            .ok()?;
+        packet.extend_from_slice(&sealed);
+        Some(Packet::from(packet))
+    }
+
+    fn open(&mut self, packet: &[u8]) -> Option<Packet> {
+        if packet.len() < DATA_HEADER_LEN + TAG_LEN {
+            return None;
+        }
+        let (header, sealed) = packet.split_at(DATA_HEADER_LEN);
+        let mut counter = [0; 8];
+        counter.copy_from_slice(&header[1..]);
+        let counter = u64::from_le_bytes(counter);
+        if counter == CONFIRM_COUNTER || !self.replay.is_fresh(counter) {
+            return None;
+        }
+
+        let payload = self
+            .recv
+            .decrypt(
+                &Nonce::from(nonce(counter)),
+                Payload {
+                    msg: sealed,
+                    aad: header,
+                },
+            )
+            .ok()?;
+        self.replay.accept(counter);
+        Some(Packet::from(payload))
+    }
+}
+
+fn nonce(counter: u64) -> [u8; 12] {
+    let mut nonce = [0; 12];
+    nonce[4..].copy_from_slice(&counter.to_le_bytes());
+    nonce
+}
+
+fn derive_key(
+    label: &[u8],
+    shared: &[u8; 32],
+    client: &PublicKey,
+    server: &PublicKey,
+    psk: Option<&[u8; 32]>,
+) -> ChaCha20Poly1305 {
+    let mut hasher = Sha256::new();
+    hasher.update(b"bevy_networking_turbulence encryption");
+    hasher.update(label);
+    hasher.update(shared);
+    hasher.update(client.as_bytes());
+    hasher.update(server.as_bytes());
+    if let Some(psk) = psk {
+        hasher.update(psk);
+    }
+    let key: Key = hasher.finalize();
+    ChaCha20Poly1305::new(&key)
+}
+
+/// Connection encrypting all traffic of the wrapped one.
+///
+/// Created by `receive_packets` for every new connection when `NetworkingPlugin::encryption`
+/// is set. Both ends have to enable it.
+pub struct EncryptedConnection {
+    inner: Box<dyn Connection>,
+    task_pool: TaskPool,
+
+    secret: StaticSecret,
+    public: PublicKey,
+    psk: Option<[u8; 32]>,
+    handshake_timeout: Duration,
+    created_at: Instant,
+    last_hello: Option<Instant>,
+    // client key and our answer to its hello, on server side
+    hello: Option<(PublicKey, Packet)>,
+    session: Option<Session>,
+
+    outgoing_tx: Sender<Packet>,
+    outgoing_rx: Receiver<Packet>,
+
+    channels: Option<MessageChannels>,
+    channels_rx: Option<IncomingMultiplexedPackets<MultiplexedPacket>>,
+    #[cfg(not(target_arch = "wasm32"))]
+    channels_task: Option<Task<()>>,
+}
+
+impl EncryptedConnection {
+    pub fn new(
+        inner: Box<dyn Connection>,
+        task_pool: TaskPool,
+        config: &EncryptionConfig,
+    ) -> Result<Self, Box<dyn Error + Sync + Send>> {
+        let mut secret = [0; KEY_LEN];
+        getrandom::getrandom(&mut secret)?;
+        let secret = StaticSecret::from(secret);
+        let public = PublicKey::from(&secret);
+        let (outgoing_tx, outgoing_rx) = bounded(OUTGOING_QUEUE_LEN);
+
+        Ok(EncryptedConnection {
+            inner,
+            task_pool,
+            secret,
+            public,
+            psk: config.psk,
+            handshake_timeout: config.handshake_timeout,
+            created_at: Instant::now(),
+            last_hello: None,
+            hello: None,
+            session: None,
+            outgoing_tx,
+            outgoing_rx,
+            channels: None,
+            channels_rx: None,
+            #[cfg(not(target_arch = "wasm32"))]
+            channels_task: None,
+        })
+    }
+
+    /// Whether the key exchange finished and packets flow.
+    pub fn is_established(&self) -> bool {
+        self.session.is_some()
+    }
+
+    fn send_hello(&mut self) {
+        let mut packet = Vec::with_capacity(1 + KEY_LEN);
+        packet.push(PACKET_HELLO);
+        packet.extend_from_slice(self.public.as_bytes());
+        if let Err(error) = self.inner.send(Packet::from(packet)) {
+            log::error!(
+                "Cannot send hello to {:?}: {}",
+                self.remote_address(),
+                error
+            );
+        }
+        self.last_hello = Some(Instant::now());
+    }
+
+    fn receive_hello(&mut self, packet: &[u8]) {
+        match self.info().role {
+            ConnectionRole::Server => self.accept_hello(packet),
+            ConnectionRole::Client => self.finish_hello(packet),
+        }
+    }
+
+    // server side: answers with our key, proving we derived the session keys
+    fn accept_hello(&mut self, packet: &[u8]) {
+        if packet.len() != 1 + KEY_LEN {
+            return;
+        }
+        let mut client = [0; KEY_LEN];
+        client.copy_from_slice(&packet[1..]);
+        let client = PublicKey::from(client);
+
+        if let Some((known, answer)) = self.hello.as_ref() {
+            // the client did not get our answer yet, other keys are not welcome anymore
+            if known.as_bytes() == client.as_bytes() {
+                let answer = answer.clone();
+                if let Err(error) = self.inner.send(answer) {
+                    log::error!(
+                        "Cannot send hello to {:?}: {}",
+                        self.remote_address(),
+                        error
+                    );
+                }
+            }
+            return;
+        }
+
+        let session = Session::new(
+            &self.secret,
+            &client,
+            &self.public,
+            ConnectionRole::Server,
+            self.psk.as_ref(),
+        );
+        let mut answer = Vec::with_capacity(1 + KEY_LEN + TAG_LEN);
+        answer.push(PACKET_HELLO);
+        answer.extend_from_slice(self.public.as_bytes());
+        let confirmation = match session.send.encrypt(
+            &Nonce::from(nonce(CONFIRM_COUNTER)),
+            Payload {
+                msg: &[],
+                aad: &answer,
+            },
+        ) {
+            Ok(confirmation) => confirmation,
+            Err(_) => return,
+        };
+        answer.extend_from_slice(&confirmation);
+        let answer = Packet::from(answer);
+
+        self.session = Some(session);
+        self.hello = Some((client, answer.clone()));
+        if let Err(error) = self.inner.send(answer) {
+            log::error!(
+                "Cannot send hello to {:?}: {}",
+                self.remote_address(),
+                error
+            );
+        }
+        self.flush();
+    }
+
+    // client side: verifies the server derived the same keys
+    fn finish_hello(&mut self, packet: &[u8]) {
+        if self.session.is_some() || packet.len() != 1 + KEY_LEN + TAG_LEN {
+            return;
+        }
+        let (header, confirmation) = packet.split_at(1 + KEY_LEN);
+        let mut server = [0; KEY_LEN];
+        server.copy_from_slice(&header[1..]);
+        let server = PublicKey::from(server);
+
+        let session = Session::new(
+            &self.secret,
+            &self.public,
+            &server,
+            ConnectionRole::Client,
+            self.psk.as_ref(),
+        );
+        let confirmed = session
+            .recv
+            .decrypt(
+                &Nonce::from(nonce(CONFIRM_COUNTER)),
+                Payload {
+                    msg: confirmation,
+                    aad: header,
+                },
+            )
+            .is_ok();
+        if !confirmed {
+            log::debug!("Dropped unconfirmed hello from {:?}", self.remote_address());
+            return;
+        }
+
+        self.session = Some(session);
+        self.flush();
+    }
+}
+
+impl Connection for EncryptedConnection {
+    fn remote_address(&self) -> Option<SocketAddr> {
+        self.inner.remote_address()
+    }
+
+    fn info(&self) -> &ConnectionInfo {
+        self.inner.info()
+    }
+
+    fn send(&mut self, payload: Packet) -> Result<(), Box<dyn Error + Sync + Send>> {
+        if self.outgoing_tx.try_send(payload).is_err() {
+            return Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::WouldBlock,
+                "Encrypted connection send queue is full",
+            )));
+        }
+        self.flush();
+        Ok(())
+    }
+
+    fn receive(&mut self) -> Option<Result<Packet, NetworkError>> {
+        self.flush();
+        if self.session.is_none() && self.created_at.elapsed() > self.handshake_timeout {
+            log::warn!("Key exchange with {:?} timed out", self.remote_address());
+            return Some(Err(NetworkError::Disconnected));
+        }
+
+        loop {
+            let packet = match self.inner.receive()? {
+                Ok(packet) => packet,
+                Err(error) => return Some(Err(error)),
+            };
+            match packet.first() {
+                Some(&PACKET_HELLO) => self.receive_hello(&packet),
+                Some(&PACKET_DATA) => {
+                    if let Some(payload) = self
+                        .session
+                        .as_mut()
+                        .and_then(|session| session.open(&packet))
+                    {
+                        return Some(Ok(payload));
+                    }
+                    log::debug!(
+                        "Dropped unauthenticated packet from {:?}",
+                        self.remote_address()
+                    );
+                }
+                _ => log::debug!(
+                    "Dropped unencrypted packet from {:?}",
+                    self.remote_address()
+                ),
+            }
+        }
+    }
+
+    fn flush(&mut self) {
+        let session = match self.session.as_mut() {
+            Some(session) => session,
+            None => {
+                let hello_due = self
+                    .last_hello
+                    .is_none_or(|sent| sent.elapsed() >= HELLO_RESEND_INTERVAL);
+                if self.info().role == ConnectionRole::Client && hello_due {
+                    self.send_hello();
+                }
+                return;
+            }
+        };
+
+        while let Ok(payload) = self.outgoing_rx.try_recv() {
+            match session.seal(&payload) {
+                Some(packet) => {
+                    if let Err(error) = self.inner.send(packet) {
+                        log::error!(
+                            "Cannot send to {:?}: {}",
+                            self.inner.remote_address(),
+                            error
+                        );
+                    }
+                }
+                None => log::error!("Cannot encrypt packet of {} bytes", payload.len()),
+            }
+        }
+    }
+
+    fn build_channels(&mut self, builder: ConnectionChannelsBuilder) {
+        let mut multiplexer = PacketMultiplexer::new();
+        self.channels = Some(builder.build(&mut multiplexer));
+        let (channels_rx, mut channels_tx) = multiplexer.start();
+        self.channels_rx = Some(channels_rx);
+
+        // encrypted and sent on the next flush, keys live in the main world
+        let outgoing_tx = self.outgoing_tx.clone();
+        #[allow(unused_variables)]
+        let channels_task = self.task_pool.spawn(async move {
+            while let Some(packet) = channels_tx.next().await {
+                match outgoing_tx.try_send(Packet::copy_from_slice(&packet)) {
+                    Ok(()) => {}
+                    Err(TrySendError::Full(_)) => {
+                        log::warn!("Encrypted connection send queue is full, dropped a packet");
+                    }
+                    // connection dropped
+                    Err(TrySendError::Disconnected(_)) => return,
+                }
+            }
+            log::error!("Channel stream Disconnected");
+        });
+
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            self.channels_task = Some(channels_task);
+        }
+    }
+
+    fn channels(&mut self) -> Option<&mut MessageChannels> {
+        self.channels.as_mut()
+    }
+
+    fn channels_rx(&mut self) -> Option<&mut IncomingMultiplexedPackets<MultiplexedPacket>> {
+        self.channels_rx.as_mut()
+    }
+
+    fn stats(&self) -> PacketStats {
+        self.inner.stats()
+    }
+
+    fn last_packet_timings(&self) -> (u128, u128) {
+        self.inner.last_packet_timings()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ReplayWindow;
+
+    fn receive(window: &mut ReplayWindow, counter: u64) -> bool {
+        let fresh = window.is_fresh(counter);
+        if fresh {
+            window.accept(counter);
+        }
+        fresh
+    }
+
+    #[test]
+    fn replay_window_rejects_duplicates() {
+        let mut window = ReplayWindow::default();
+        assert!(receive(&mut window, 0));
+        assert!(!receive(&mut window, 0));
+        assert!(receive(&mut window, 5));
+        assert!(!receive(&mut window, 5));
+        // late but not seen yet
+        assert!(receive(&mut window, 3));
+        assert!(!receive(&mut window, 3));
+        assert!(!receive(&mut window, 0));
+    }
+
+    #[test]
+    fn replay_window_rejects_far_old_packets() {
+        let mut window = ReplayWindow::default();
+        assert!(receive(&mut window, 100));
+        assert!(receive(&mut window, 37));
+        assert!(!receive(&mut window, 36));
+        assert!(!receive(&mut window, 0));
+    }
+
+    #[test]
+    fn replay_window_shifts() {
+        let mut window = ReplayWindow::default();
+        assert!(receive(&mut window, 10));
+        assert!(receive(&mut window, 12));
+        // 10 is still remembered after a shift by two
+        assert!(!receive(&mut window, 10));
+        assert!(receive(&mut window, 11));
+
+        assert!(receive(&mut window, 12 + 63));
+        assert!(!receive(&mut window, 12));
+        assert!(!receive(&mut window, 11));
+
+        // a jump past the window forgets everything before it
+        assert!(receive(&mut window, 1000));
+        assert!(!receive(&mut window, 1000));
+        assert!(receive(&mut window, 999));
+        assert!(!receive(&mut window, 12 + 63));
+    }
+}
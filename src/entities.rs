@@ -0,0 +1,180 @@
+use bevy_app::EventReader;
+use bevy_ecs::prelude::*;
+use std::{collections::HashMap, ops::Deref};
+
+use super::{ConnectionHandle, NetworkEvent, NetworkResource, PacketStats};
+
+/// Component of the entity spawned for every connection,
+/// when `NetworkingPlugin::spawn_connection_entities` is enabled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NetworkConnection {
+    pub handle: ConnectionHandle,
+}
+
+/// `PacketStats` of the connection entity, refreshed every frame.
+/// Only changed stats are written, so `Changed<ConnectionStats>` filters out idle connections.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnectionStats(pub PacketStats);
+
+impl Deref for ConnectionStats {
+    type Target = PacketStats;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+/// Maps connection handles to their entities.
+#[derive(Debug, Default)]
+pub struct ConnectionEntities(HashMap<ConnectionHandle, Entity>);
+
+impl ConnectionEntities {
+    pub fn get(&self, handle: ConnectionHandle) -> Option<Entity> {
+        self.0.get(&handle).copied()
+    }
+}
+
+pub fn connection_entities(
+    mut commands: Commands,
+    net: Res<NetworkResource>,
+    mut entities: ResMut<ConnectionEntities>,
+    mut network_events: EventReader<NetworkEvent>,
+) {
+    for event in network_events.iter() {
+        match event {
+            NetworkEvent::Connected(handle) => {
+                let stats = match net.connections.get(handle) {
+                    Some(connection) => connection.stats(),
+                    // already gone again
+                    None => continue,
+                };
+                let entity = commands
+                    .spawn_bundle((
+                        NetworkConnection { handle: *handle },
+                        ConnectionStats(stats),
+                    ))
+                    .id();
+                entities.0.insert(*handle, entity);
+            }
+            NetworkEvent::Disconnected(handle) => {
+                if let Some(entity) = entities.0.remove(handle) {
+                    commands.entity(entity).despawn();
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+pub fn update_connection_stats(
+    net: Res<NetworkResource>,
+    mut query: Query<(&NetworkConnection, &mut ConnectionStats)>,
+) {
+    for (connection, mut stats) in query.iter_mut() {
+        if let Some(current) = net
+            .connections
+            .get(&connection.handle)
+            .map(|connection| connection.stats())
+        {
+            if stats.0 != current {
+                stats.0 = current;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        tests::{add_connection, network, TestConnection},
+        ConnectionRole,
+    };
+    use bevy_app::Events;
+    use bevy_ecs::schedule::{Stage, SystemStage};
+
+    fn world() -> (World, SystemStage) {
+        let mut world = World::new();
+        world.insert_resource(network());
+        world.insert_resource(ConnectionEntities::default());
+        world.insert_resource(Events::<NetworkEvent>::default());
+        let stage = SystemStage::single_threaded()
+            .with_system(connection_entities.system())
+            .with_system(update_connection_stats.system());
+        (world, stage)
+    }
+
+    fn send(world: &mut World, event: NetworkEvent) {
+        world
+            .get_resource_mut::<Events<NetworkEvent>>()
+            .unwrap()
+            .send(event);
+    }
+
+    #[test]
+    fn spawns_and_despawns_connection_entities() {
+        let (mut world, mut stage) = world();
+        let handle = add_connection(
+            &mut world.get_resource_mut::<NetworkResource>().unwrap(),
+            TestConnection::new(ConnectionRole::Server),
+        );
+
+        send(&mut world, NetworkEvent::Connected(handle));
+        stage.run(&mut world);
+        let entity = world
+            .get_resource::<ConnectionEntities>()
+            .unwrap()
+            .get(handle)
+            .expect("connection entity");
+        assert_eq!(
+            world.get::<NetworkConnection>(entity),
+            Some(&NetworkConnection { handle })
+        );
+        assert!(world.get::<ConnectionStats>(entity).is_some());
+
+        send(&mut world, NetworkEvent::Disconnected(handle));
+        stage.run(&mut world);
+        assert!(world.get_entity(entity).is_none());
+        assert!(world
+            .get_resource::<ConnectionEntities>()
+            .unwrap()
+            .get(handle)
+            .is_none());
+    }
+
+    #[test]
+    fn skips_connections_gone_before_spawning() {
+        let (mut world, mut stage) = world();
+        send(&mut world, NetworkEvent::Connected(7));
+        stage.run(&mut world);
+        assert!(world
+            .get_resource::<ConnectionEntities>()
+            .unwrap()
+            .get(7)
+            .is_none());
+    }
+
+    #[test]
+    fn writes_only_changed_stats() {
+        let (mut world, mut stage) = world();
+        let connection = TestConnection::new(ConnectionRole::Server);
+        let stats = connection.stats.clone();
+        let handle = add_connection(
+            &mut world.get_resource_mut::<NetworkResource>().unwrap(),
+            connection,
+        );
+        send(&mut world, NetworkEvent::Connected(handle));
+        stage.run(&mut world);
+        world.clear_trackers();
+
+        let mut changed = world.query_filtered::<Entity, Changed<ConnectionStats>>();
+        stage.run(&mut world);
+        assert_eq!(changed.iter(&world).count(), 0);
+        world.clear_trackers();
+
+        stats.write().unwrap().add_rx(10);
+        stage.run(&mut world);
+        let entity = changed.iter(&world).next().expect("changed stats");
+        assert_eq!(world.get::<ConnectionStats>(entity).unwrap().bytes_rx, 10);
+    }
+}
@@ -0,0 +1,250 @@
+//! Connection handshake, verifying that both peers talk the same protocol.
+//!
+//! When channels are built for a new connection, both sides send the fingerprints of their
+//! `ConnectionChannelsBuilder` and `register_message` registrations. A peer with a different
+//! fingerprint gets `NetworkEvent::Error` and is disconnected, instead of exchanging garbled
+//! messages. So is a peer that sent no handshake within `HANDSHAKE_TIMEOUT`.
+
+use bevy_app::Events;
+use bevy_ecs::prelude::*;
+use instant::{Duration, Instant};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    ConnectionHandle, MessageChannelMode, MessageChannelSettings, NetworkError, NetworkEvent,
+    NetworkResource, ReliableChannelSettings,
+};
+
+/// Default channel of the handshake, see `NetworkingPlugin::handshake_channel`.
+pub const HANDSHAKE_CHANNEL: u8 = 255;
+
+/// Time a peer has to send its handshake, once channels are built.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub(crate) const HANDSHAKE_MESSAGE_SETTINGS: MessageChannelSettings = MessageChannelSettings {
+    channel: HANDSHAKE_CHANNEL,
+    channel_mode: MessageChannelMode::Reliable {
+        reliability_settings: ReliableChannelSettings {
+            bandwidth: 4096,
+            recv_window_size: 1024,
+            send_window_size: 1024,
+            burst_bandwidth: 1024,
+            init_send: 512,
+            wakeup_time: Duration::from_millis(100),
+            initial_rtt: Duration::from_millis(200),
+            max_rtt: Duration::from_secs(2),
+            rtt_update_factor: 0.1,
+            rtt_resend_factor: 1.5,
+        },
+        max_message_len: 1024,
+    },
+    message_buffer_size: 8,
+    packet_buffer_size: 8,
+};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Handshake {
+    /// Fingerprint of all registered channels, see `ConnectionChannelsBuilder::fingerprint`.
+    pub channels: u64,
+    /// Fingerprint of `register_message` registrations.
+    pub messages: u64,
+}
+
+// user data of connections whose handshake did not arrive yet
+struct PendingHandshake {
+    deadline: Instant,
+}
+
+// registered messages are part of the channels too, report the narrower one
+fn check_handshake(local: &Handshake, remote: &Handshake) -> Result<(), NetworkError> {
+    if remote.messages != local.messages {
+        Err(NetworkError::MessagesMismatch {
+            local: local.messages,
+            remote: remote.messages,
+        })
+    } else if remote.channels != local.channels {
+        Err(NetworkError::ChannelsMismatch {
+            local: local.channels,
+            remote: remote.channels,
+        })
+    } else {
+        Ok(())
+    }
+}
+
+/// Reports channels registered on the handshake channel at startup, instead of at the first
+/// connection.
+pub fn check_handshake_channel(net: Res<NetworkResource>) {
+    if !net.has_channels() {
+        return;
+    }
+    if let Err(NetworkError::HandshakeChannelTaken(channel)) = net.channels_builder() {
+        log::error!(
+            "Channel {} is taken by the connection handshake, register your messages on another \
+             channel or move it with `NetworkingPlugin::handshake_channel`",
+            channel
+        );
+    }
+}
+
+impl NetworkResource {
+    pub(crate) fn send_handshake(&mut self, handle: ConnectionHandle, channels_fingerprint: u64) {
+        let handshake = Handshake {
+            channels: channels_fingerprint,
+            messages: self.message_registry.fingerprint(),
+        };
+        if let Some(channels) = self
+            .connections
+            .get_mut(&handle)
+            .and_then(|connection| connection.channels())
+        {
+            if channels.send(handshake).is_some() {
+                log::error!("Handshake channel of [{}] is full", handle);
+            }
+            channels.flush::<Handshake>();
+        }
+        let _ = self.set_user_data(
+            handle,
+            PendingHandshake {
+                deadline: Instant::now() + HANDSHAKE_TIMEOUT,
+            },
+        );
+    }
+
+    /// Compares received handshakes with ours, disconnecting peers that differ.
+    pub(crate) fn verify_handshakes(&mut self, network_events: &mut Events<NetworkEvent>) {
+        let local = match self.channels_fingerprint {
+            Some(channels) => Handshake {
+                channels,
+                messages: self.message_registry.fingerprint(),
+            },
+            // no channels built yet
+            None => return,
+        };
+
+        let mut verified = Vec::new();
+        let mut failed = Vec::new();
+        for (handle, connection) in self.connections.iter_mut() {
+            if let Some(channels) = connection.channels() {
+                while let Some(remote) = channels.recv::<Handshake>() {
+                    match check_handshake(&local, &remote) {
+                        Ok(()) => verified.push(*handle),
+                        Err(error) => failed.push((*handle, error)),
+                    }
+                }
+            }
+        }
+        for handle in verified {
+            self.remove_user_data::<PendingHandshake>(handle);
+        }
+
+        let now = Instant::now();
+        for handle in self.connections.keys() {
+            if self
+                .user_data::<PendingHandshake>(*handle)
+                .is_some_and(|pending| pending.deadline <= now)
+            {
+                failed.push((*handle, NetworkError::HandshakeTimeout));
+            }
+        }
+
+        for (handle, error) in failed {
+            if !self.connections.contains_key(&handle) {
+                // failed more than once
+                continue;
+            }
+            log::error!("Handshake of [{}] failed: {:?}", handle, error);
+            network_events.send(NetworkEvent::Error(handle, error));
+            self.disconnect(handle);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        tests::{add_connection, network, TestConnection},
+        ConnectionRole,
+    };
+
+    fn handshake(channels: u64, messages: u64) -> Handshake {
+        Handshake { channels, messages }
+    }
+
+    fn errors(events: &Events<NetworkEvent>) -> Vec<(ConnectionHandle, String)> {
+        events
+            .get_reader()
+            .iter(events)
+            .filter_map(|event| match event {
+                NetworkEvent::Error(handle, error) => Some((*handle, format!("{:?}", error))),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn matching_handshake_passes() {
+        assert!(check_handshake(&handshake(1, 2), &handshake(1, 2)).is_ok());
+    }
+
+    #[test]
+    fn mismatching_handshake_fails() {
+        assert!(matches!(
+            check_handshake(&handshake(1, 2), &handshake(3, 2)),
+            Err(NetworkError::ChannelsMismatch {
+                local: 1,
+                remote: 3
+            })
+        ));
+        assert!(matches!(
+            check_handshake(&handshake(1, 2), &handshake(3, 4)),
+            Err(NetworkError::MessagesMismatch {
+                local: 2,
+                remote: 4
+            })
+        ));
+    }
+
+    #[test]
+    fn missing_handshake_times_out() {
+        let mut net = network();
+        net.channels_fingerprint = Some(1);
+        let late = add_connection(&mut net, TestConnection::new(ConnectionRole::Server));
+        let waiting = add_connection(&mut net, TestConnection::new(ConnectionRole::Server));
+        let now = Instant::now();
+        for (handle, deadline) in [(late, now), (waiting, now + HANDSHAKE_TIMEOUT)].iter() {
+            net.set_user_data(
+                *handle,
+                PendingHandshake {
+                    deadline: *deadline,
+                },
+            )
+            .unwrap();
+        }
+
+        let mut events = Events::<NetworkEvent>::default();
+        net.verify_handshakes(&mut events);
+        assert_eq!(
+            errors(&events),
+            vec![(late, "HandshakeTimeout".to_string())]
+        );
+        assert!(!net.connections.contains_key(&late));
+        assert!(net.connections.contains_key(&waiting));
+    }
+
+    #[test]
+    fn taken_handshake_channel_is_reported() {
+        let mut net = network();
+        net.set_channels_builder(|builder: &mut crate::ConnectionChannelsBuilder| {
+            builder.register::<u32>(HANDSHAKE_MESSAGE_SETTINGS).unwrap();
+        });
+        assert!(matches!(
+            net.channels_builder(),
+            Err(NetworkError::HandshakeChannelTaken(HANDSHAKE_CHANNEL))
+        ));
+
+        net.set_handshake_channel(HANDSHAKE_CHANNEL - 1);
+        assert!(net.channels_builder().is_ok());
+    }
+}
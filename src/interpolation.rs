@@ -0,0 +1,300 @@
+//! Interpolation of remote entity states.
+//!
+//! States received from the server are pushed with their server timestamp into the
+//! `InterpolationBuffer` component of the entity. Every frame the component of type `T` on the
+//! same entity is set to the buffered state at `InterpolationSettings::delay` seconds behind the
+//! newest estimated server time, interpolating between the two surrounding samples.
+//! When no newer sample arrived in time, the last two samples are extrapolated, for at most
+//! `InterpolationSettings::max_extrapolation` seconds.
+
+use bevy_app::{AppBuilder, CoreStage, Plugin};
+use bevy_ecs::prelude::*;
+use bevy_math::{Quat, Vec2, Vec3};
+use bevy_transform::{components::Transform, TransformSystem};
+use instant::Instant;
+use std::{collections::VecDeque, marker::PhantomData};
+
+/// Samples kept when the buffer is not drained by `interpolation_system`.
+const MAX_SAMPLES: usize = 64;
+/// Server time offsets further than this from the current estimate replace it right away.
+const OFFSET_RESET_THRESHOLD: f64 = 1.0;
+const OFFSET_SMOOTHING: f64 = 0.1;
+
+/// State that can be blended between two samples.
+pub trait Interpolate: Clone + Send + Sync + 'static {
+    /// Returns `self` for `t == 0.0` and `other` for `t == 1.0`.
+    /// Values of `t` above `1.0` extrapolate past `other`.
+    fn interpolate(&self, other: &Self, t: f32) -> Self;
+}
+
+impl Interpolate for f32 {
+    fn interpolate(&self, other: &Self, t: f32) -> Self {
+        self + (other - self) * t
+    }
+}
+
+impl Interpolate for Vec2 {
+    fn interpolate(&self, other: &Self, t: f32) -> Self {
+        self.lerp(*other, t)
+    }
+}
+
+impl Interpolate for Vec3 {
+    fn interpolate(&self, other: &Self, t: f32) -> Self {
+        self.lerp(*other, t)
+    }
+}
+
+impl Interpolate for Quat {
+    fn interpolate(&self, other: &Self, t: f32) -> Self {
+        // take the short way around
+        if self.dot(*other) < 0.0 {
+            (-*self).slerp(*other, t)
+        } else {
+            self.slerp(*other, t)
+        }
+    }
+}
+
+impl Interpolate for Transform {
+    fn interpolate(&self, other: &Self, t: f32) -> Self {
+        Transform {
+            translation: self.translation.interpolate(&other.translation, t),
+            rotation: self.rotation.interpolate(&other.rotation, t),
+            scale: self.scale.interpolate(&other.scale, t),
+        }
+    }
+}
+
+/// Timestamped server states of a single entity.
+pub struct InterpolationBuffer<T> {
+    samples: VecDeque<(f64, T)>,
+    epoch: Instant,
+    /// Estimated server time minus local time, in seconds.
+    offset: Option<f64>,
+}
+
+impl<T> Default for InterpolationBuffer<T> {
+    fn default() -> Self {
+        InterpolationBuffer {
+            samples: VecDeque::new(),
+            epoch: Instant::now(),
+            offset: None,
+        }
+    }
+}
+
+impl<T: Interpolate> InterpolationBuffer<T> {
+    /// Buffers `state` the server had at `server_time` seconds.
+    /// Samples older than the newest one are inserted in order, duplicates are dropped.
+    pub fn push(&mut self, server_time: f64, state: T) {
+        let offset = server_time - self.local_time();
+        self.offset = Some(match self.offset {
+            Some(current) if (offset - current).abs() < OFFSET_RESET_THRESHOLD => {
+                current + (offset - current) * OFFSET_SMOOTHING
+            }
+            _ => offset,
+        });
+
+        // unreliable channels may deliver out of order
+        let index = self
+            .samples
+            .iter()
+            .rposition(|(time, _)| *time <= server_time)
+            .map_or(0, |index| index + 1);
+        if index > 0 && self.samples[index - 1].0 == server_time {
+            return;
+        }
+        self.samples.insert(index, (server_time, state));
+        while self.samples.len() > MAX_SAMPLES {
+            self.samples.pop_front();
+        }
+    }
+
+    /// Server time of the newest sample.
+    pub fn latest_time(&self) -> Option<f64> {
+        self.samples.back().map(|(time, _)| *time)
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.samples.clear();
+        self.offset = None;
+    }
+
+    /// Estimated server time to render now, `delay` seconds in the past.
+    pub fn render_time(&self, delay: f64) -> Option<f64> {
+        self.offset.map(|offset| self.local_time() + offset - delay)
+    }
+
+    /// State at server time `time`, extrapolating at most `max_extrapolation` seconds past the
+    /// newest sample.
+    pub fn sample(&self, time: f64, max_extrapolation: f64) -> Option<T> {
+        let (first_time, first) = self.samples.front()?;
+        if time <= *first_time || self.samples.len() == 1 {
+            return Some(first.clone());
+        }
+
+        let index = self
+            .samples
+            .iter()
+            .position(|(sample_time, _)| *sample_time > time)
+            // past the newest sample, extrapolate from the last two
+            .unwrap_or(self.samples.len() - 1);
+        let (from_time, from) = &self.samples[index - 1];
+        let (to_time, to) = &self.samples[index];
+        let time = time.min(to_time + max_extrapolation);
+        let t = (time - from_time) / (to_time - from_time);
+        Some(from.interpolate(to, t as f32))
+    }
+
+    /// Drops samples no longer needed to render `time`.
+    fn prune(&mut self, time: f64) {
+        while self.samples.len() > 2 && self.samples[1].0 <= time {
+            self.samples.pop_front();
+        }
+    }
+
+    fn local_time(&self) -> f64 {
+        self.epoch.elapsed().as_secs_f64()
+    }
+}
+
+pub struct InterpolationSettings<T> {
+    /// Seconds the rendered state lags behind the newest server state.
+    /// Should cover a couple of server send intervals plus network jitter.
+    pub delay: f64,
+    /// Seconds past the newest sample to extrapolate for, when packets are late or lost.
+    pub max_extrapolation: f64,
+    marker: PhantomData<fn() -> T>,
+}
+
+impl<T> InterpolationSettings<T> {
+    pub fn new(delay: f64, max_extrapolation: f64) -> Self {
+        InterpolationSettings {
+            delay,
+            max_extrapolation,
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<T> Clone for InterpolationSettings<T> {
+    fn clone(&self) -> Self {
+        InterpolationSettings::new(self.delay, self.max_extrapolation)
+    }
+}
+
+impl<T> Default for InterpolationSettings<T> {
+    fn default() -> Self {
+        InterpolationSettings::new(0.1, 0.25)
+    }
+}
+
+pub fn interpolation_system<T: Interpolate>(
+    settings: Res<InterpolationSettings<T>>,
+    mut query: Query<(&mut InterpolationBuffer<T>, &mut T)>,
+) {
+    for (mut buffer, mut state) in query.iter_mut() {
+        let time = match buffer.render_time(settings.delay) {
+            Some(time) => time,
+            None => continue,
+        };
+        buffer.prune(time);
+        if let Some(sampled) = buffer.sample(time, settings.max_extrapolation) {
+            *state = sampled;
+        }
+    }
+}
+
+/// Renders component `T` of entities with an `InterpolationBuffer<T>` from the buffered states.
+pub struct InterpolationPlugin<T> {
+    pub settings: InterpolationSettings<T>,
+}
+
+impl<T> InterpolationPlugin<T> {
+    pub fn new(settings: InterpolationSettings<T>) -> Self {
+        InterpolationPlugin { settings }
+    }
+}
+
+impl<T> Default for InterpolationPlugin<T> {
+    fn default() -> Self {
+        InterpolationPlugin {
+            settings: InterpolationSettings::default(),
+        }
+    }
+}
+
+impl<T: Interpolate> Plugin for InterpolationPlugin<T> {
+    fn build(&self, app: &mut AppBuilder) {
+        app.insert_resource(self.settings.clone())
+            .add_system_to_stage(
+                CoreStage::PostUpdate,
+                interpolation_system::<T>
+                    .system()
+                    .before(TransformSystem::TransformPropagate),
+            );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn buffer(samples: &[(f64, f32)]) -> InterpolationBuffer<f32> {
+        let mut buffer = InterpolationBuffer::default();
+        for (time, state) in samples {
+            buffer.push(*time, *state);
+        }
+        buffer
+    }
+
+    #[test]
+    fn sample_interpolates_between_samples() {
+        let buffer = buffer(&[(1.0, 10.0), (2.0, 20.0), (3.0, 40.0)]);
+        assert_eq!(buffer.sample(1.5, 0.0), Some(15.0));
+        assert_eq!(buffer.sample(2.5, 0.0), Some(30.0));
+        assert_eq!(buffer.sample(2.0, 0.0), Some(20.0));
+    }
+
+    #[test]
+    fn sample_clamps_before_first_sample() {
+        let buffer = buffer(&[(1.0, 10.0), (2.0, 20.0)]);
+        assert_eq!(buffer.sample(0.0, 1.0), Some(10.0));
+        assert_eq!(buffer.sample(1.0, 1.0), Some(10.0));
+    }
+
+    #[test]
+    fn sample_extrapolates_up_to_limit_after_last_sample() {
+        let buffer = buffer(&[(1.0, 10.0), (2.0, 20.0)]);
+        assert_eq!(buffer.sample(2.5, 1.0), Some(25.0));
+        assert_eq!(buffer.sample(5.0, 0.5), Some(25.0));
+        assert_eq!(buffer.sample(5.0, 0.0), Some(20.0));
+    }
+
+    #[test]
+    fn sample_of_single_or_no_sample() {
+        assert_eq!(buffer(&[]).sample(1.0, 1.0), None);
+        assert_eq!(buffer(&[(1.0, 10.0)]).sample(3.0, 1.0), Some(10.0));
+    }
+
+    #[test]
+    fn push_orders_samples_and_drops_duplicates() {
+        let mut buffer = buffer(&[(1.0, 10.0), (3.0, 30.0), (2.0, 20.0), (3.0, 99.0)]);
+        assert_eq!(buffer.len(), 3);
+        assert_eq!(buffer.latest_time(), Some(3.0));
+        assert_eq!(buffer.sample(2.5, 0.0), Some(25.0));
+
+        buffer.prune(2.5);
+        assert_eq!(buffer.len(), 2);
+        assert_eq!(buffer.sample(2.5, 0.0), Some(25.0));
+    }
+}
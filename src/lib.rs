@@ -1,11 +1,7 @@
-use bevy_app::{AppBuilder, Events, Plugin};
+use bevy_app::{AppBuilder, CoreStage, Events, Plugin, StartupStage};
 use bevy_ecs::prelude::*;
 use bevy_tasks::{IoTaskPool, TaskPool};
 
-#[cfg(not(target_arch = "wasm32"))]
-use crossbeam_channel::{unbounded, Receiver, Sender};
-#[cfg(not(target_arch = "wasm32"))]
-use std::sync::RwLock;
 use std::{
     collections::HashMap,
     error::Error,
@@ -33,20 +29,116 @@ pub use turbulence::{
     reliable_channel::Settings as ReliableChannelSettings,
 };
 
+mod access;
+mod address;
+pub mod blob;
 mod channels;
+pub mod clock;
+pub mod codec;
+#[cfg(feature = "compression")]
+pub mod compression;
+mod control;
+#[cfg(feature = "encryption")]
+pub mod encryption;
+mod entities;
+mod handshake;
+mod hash;
+pub mod interpolation;
+mod messages;
+pub mod prediction;
+pub mod replication;
+pub mod rpc;
+mod sequence;
+#[cfg(not(target_arch = "wasm32"))]
+mod server;
+pub mod snapshot;
+pub mod token;
 mod transport;
+mod user_data;
+#[cfg(feature = "use-websocket")]
+mod websocket;
+#[cfg(not(target_arch = "wasm32"))]
+use self::{
+    access::{BanList, BanRule},
+    server::{ConnectionSlots, CookieJar, HandshakeRateLimiter, ListenerContext, ServerChannels},
+    token::ConnectTokenValidator,
+    transport::ServerPacketSender,
+};
 use self::{
     channels::{SimpleBufferPool, TaskPoolRuntime},
+    codec::{CodecRegistry, Encoded},
+    handshake::{Handshake, HANDSHAKE_MESSAGE_SETTINGS},
+    messages::MessageRegistry,
     transport::MultiplexedPacket,
+    user_data::UserData,
+};
+pub use access::{BanTarget, IpRange, IpRangeParseError};
+pub use address::{AddressParseError, NetworkAddress, Transport};
+pub use blob::{
+    BlobCancelled, BlobDirection, BlobId, BlobPlugin, BlobProgress, BlobReceived, Blobs,
+};
+pub use clock::{ClockSyncPlugin, NetworkTime, Stamped};
+pub use codec::{
+    BincodeCodec, BitPack, BitPackCodec, BitReader, BitWriter, ChannelStats, CodecError,
+    MessageCodec, VarintCodec,
+};
+#[cfg(feature = "compression")]
+pub use compression::Compressed;
+pub use control::{ListenerSettings, RejectReason};
+#[cfg(feature = "encryption")]
+pub use encryption::{EncryptedConnection, EncryptionConfig};
+pub use entities::{ConnectionEntities, ConnectionStats, NetworkConnection};
+pub use handshake::{HANDSHAKE_CHANNEL, HANDSHAKE_TIMEOUT};
+pub use interpolation::{Interpolate, InterpolationBuffer, InterpolationPlugin};
+pub use messages::{MessageMode, MessageRegistrationError};
+pub use prediction::{Authoritative, Predicted, PredictionPlugin};
+pub use replication::{
+    AppReplicationExt, Replicate, Replicated, ReplicatedEntities, ReplicationPlugin,
 };
-pub use transport::{Connection, ConnectionChannelsBuilder, Packet};
+pub use rpc::{AppRpcExt, IncomingCall, PendingCall, Rpc, RpcError, RpcPlugin, RpcRequest};
+pub use snapshot::{Delta, Snapshot, SnapshotPlugin, Snapshots};
+pub use token::{ConnectToken, ConnectTokenData, ConnectTokenError, ConnectTokenKey};
+pub use transport::{
+    Connection, ConnectionChannelsBuilder, ConnectionInfo, ConnectionRole, Packet, PacketStats,
+};
+#[cfg(feature = "use-websocket")]
+pub use websocket::WebSocketConnection;
 
 pub type ConnectionHandle = u32;
 
+/// Message channel numbers used by this crate's own plugins.
+/// Don't register your messages on these.
+pub const RESERVED_CHANNELS: std::ops::RangeInclusive<u8> = 240..=255;
+
+/// Message channel numbers assigned by `NetworkResource::register_message`.
+/// Don't register your messages on these when using it.
+pub const MESSAGE_CHANNELS: std::ops::RangeInclusive<u8> = 128..=239;
+
 #[derive(Default)]
 pub struct NetworkingPlugin {
     pub link_conditioner: Option<LinkConditionerConfig>,
     pub message_flushing_strategy: MessageFlushingStrategy,
+    /// Spawn an entity with `NetworkConnection` and `ConnectionStats` components for every
+    /// connection, despawned on disconnect. See `ConnectionEntities` to look them up by handle.
+    pub spawn_connection_entities: bool,
+    /// Channel of the connection handshake, `HANDSHAKE_CHANNEL` if `None`.
+    /// For apps with their own messages on it, both peers have to pick the same one.
+    pub handshake_channel: Option<u8>,
+    /// Limits of datagram listeners on connecting clients.
+    pub listener: ListenerSettings,
+    /// Connections accepted by listeners beyond this are refused, clients get
+    /// `NetworkError::Rejected(RejectReason::ServerFull)`.
+    pub max_connections: Option<usize>,
+    /// Encrypt and authenticate all traffic, see `EncryptedConnection`.
+    #[cfg(feature = "encryption")]
+    pub encryption: Option<EncryptionConfig>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, SystemLabel)]
+pub enum NetworkSystem {
+    /// Accepts connections and dispatches received packets, sends `NetworkEvent`s.
+    Receive,
+    ConnectionEntities,
 }
 
 impl Plugin for NetworkingPlugin {
@@ -58,32 +150,86 @@ impl Plugin for NetworkingPlugin {
             .0
             .clone();
 
-        app.insert_resource(NetworkResource::new(
+        let mut net = NetworkResource::new(
             task_pool,
             self.link_conditioner.clone(),
             self.message_flushing_strategy,
-        ))
-        .add_event::<NetworkEvent>()
-        .add_system(receive_packets.system());
+        );
+        if let Some(channel) = self.handshake_channel {
+            net.set_handshake_channel(channel);
+        }
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            net.set_listener_settings(self.listener.clone());
+            net.set_max_connections(self.max_connections);
+        }
+        #[cfg(feature = "encryption")]
+        net.set_encryption(self.encryption.clone());
+
+        app.insert_resource(net)
+            .add_event::<NetworkEvent>()
+            .add_system(receive_packets.system().label(NetworkSystem::Receive))
+            .add_system_to_stage(CoreStage::Last, flush_connections.system())
+            // after plugins and startup systems registered their channels
+            .add_startup_system_to_stage(
+                StartupStage::PostStartup,
+                handshake::check_handshake_channel.system(),
+            );
+
+        if self.spawn_connection_entities {
+            app.init_resource::<ConnectionEntities>().add_system_set(
+                SystemSet::new()
+                    .label(NetworkSystem::ConnectionEntities)
+                    .after(NetworkSystem::Receive)
+                    .with_system(entities::connection_entities.system())
+                    .with_system(entities::update_connection_stats.system()),
+            );
+        }
     }
 }
 
+type ChannelsBuilderFn = Box<dyn Fn(&mut ConnectionChannelsBuilder) + Send + Sync>;
+
 pub struct NetworkResource {
     task_pool: TaskPool,
 
     pending_connections: Arc<Mutex<Vec<Box<dyn Connection>>>>,
     connection_sequence: atomic::AtomicU32,
     pub connections: HashMap<ConnectionHandle, Box<dyn Connection>>,
+    user_data: HashMap<ConnectionHandle, UserData>,
+    // removed by `disconnect`, waiting for their `Disconnected` event
+    disconnected: Vec<ConnectionHandle>,
 
     #[cfg(not(target_arch = "wasm32"))]
     listeners: Vec<ServerListener>,
     #[cfg(not(target_arch = "wasm32"))]
-    server_channels: Arc<RwLock<HashMap<SocketAddr, Sender<Result<Packet, NetworkError>>>>>,
+    server_channels: ServerChannels,
+    #[cfg(not(target_arch = "wasm32"))]
+    connect_tokens: Option<Arc<ConnectTokenValidator>>,
+    #[cfg(not(target_arch = "wasm32"))]
+    bans: Arc<BanList>,
+    #[cfg(not(target_arch = "wasm32"))]
+    cookies: Arc<CookieJar>,
+    #[cfg(not(target_arch = "wasm32"))]
+    slots: Arc<ConnectionSlots>,
+    #[cfg(not(target_arch = "wasm32"))]
+    listener_settings: ListenerSettings,
+    #[cfg(not(target_arch = "wasm32"))]
+    rate_limiter: Arc<HandshakeRateLimiter>,
 
     runtime: TaskPoolRuntime,
     packet_pool: MuxPacketPool<BufferPacketPool<SimpleBufferPool>>,
-    channels_builder_fn: Option<Box<dyn Fn(&mut ConnectionChannelsBuilder) + Send + Sync>>,
+    channels_builder_fn: Option<ChannelsBuilderFn>,
+    // channels registered by plugins, next to the user provided ones
+    extra_channels_builder_fns: Vec<ChannelsBuilderFn>,
     message_flushing_strategy: MessageFlushingStrategy,
+    message_registry: MessageRegistry,
+    codecs: CodecRegistry,
+    // of the channels built for connections, sent in the handshake
+    channels_fingerprint: Option<u64>,
+    handshake_channel: u8,
+    #[cfg(feature = "encryption")]
+    encryption: Option<EncryptionConfig>,
 
     link_conditioner: Option<LinkConditionerConfig>,
 }
@@ -93,7 +239,7 @@ pub struct NetworkResource {
 struct ServerListener {
     receiver_task: bevy_tasks::Task<()>,
     // needed to keep receiver_task alive
-    sender: ServerSender,
+    sender: Option<ServerSender>,
     socket_address: SocketAddr,
 }
 
@@ -110,15 +256,37 @@ pub enum NetworkError {
     TurbulenceChannelError(IncomingTrySendError<MultiplexedPacket>),
     IoError(Box<dyn Error + Sync + Send>),
     Disconnected,
+    /// Peer registered different message types with `register_message`.
+    MessagesMismatch {
+        local: u64,
+        remote: u64,
+    },
+    /// Peer registered different channels, see `ConnectionChannelsBuilder::fingerprint`.
+    ChannelsMismatch {
+        local: u64,
+        remote: u64,
+    },
+    /// Channels registered on the handshake channel, see `NetworkingPlugin::handshake_channel`.
+    HandshakeChannelTaken(u8),
+    /// Peer sent no handshake within `HANDSHAKE_TIMEOUT`.
+    HandshakeTimeout,
+    /// Server refused our connect request, the connection is dropped.
+    Rejected(RejectReason),
+    /// Receive queue of the connection was full and `dropped` packets were lost since
+    /// the last report. The peer floods or the game loop does not keep up, consider `disconnect`.
+    QueueOverflow {
+        dropped: usize,
+    },
 }
 
 /// Turbulence will coalesce multiple small messages into a single packet when flush is called.
 /// the default is `OnEverySend` - flushing after each message, which bypasses the coalescing.
 /// You probably want to call flush once per tick instead, in your own system.
-#[derive(Debug, PartialEq, Clone, Copy)]
+#[derive(Debug, Default, PartialEq, Clone, Copy)]
 pub enum MessageFlushingStrategy {
     /// OnEverySend - flush immediately after calling send_message or send_broadcast.
     /// turbulence will never have a chance to coalesce multiple messages into a packet.
+    #[default]
     OnEverySend,
 
     /// Never - you will want a system in (eg) PostUpdate which calls channels.flush for every channel type
@@ -139,12 +307,6 @@ pub enum MessageFlushingStrategy {
     Never,
 }
 
-impl Default for MessageFlushingStrategy {
-    fn default() -> MessageFlushingStrategy {
-        MessageFlushingStrategy::OnEverySend
-    }
-}
-
 #[cfg(target_arch = "wasm32")]
 unsafe impl Send for NetworkResource {}
 
@@ -152,7 +314,11 @@ unsafe impl Send for NetworkResource {}
 unsafe impl Sync for NetworkResource {}
 
 impl NetworkResource {
-    pub fn new(task_pool: TaskPool, link_conditioner: Option<LinkConditionerConfig>, message_flushing_strategy: MessageFlushingStrategy) -> Self {
+    pub fn new(
+        task_pool: TaskPool,
+        link_conditioner: Option<LinkConditionerConfig>,
+        message_flushing_strategy: MessageFlushingStrategy,
+    ) -> Self {
         let runtime = TaskPoolRuntime::new(task_pool.clone());
         let packet_pool =
             MuxPacketPool::new(BufferPacketPool::new(SimpleBufferPool(MAX_PACKET_LEN)));
@@ -160,16 +326,37 @@ impl NetworkResource {
         NetworkResource {
             task_pool,
             connections: HashMap::new(),
+            user_data: HashMap::new(),
+            disconnected: Vec::new(),
             connection_sequence: atomic::AtomicU32::new(0),
             pending_connections: Arc::new(Mutex::new(Vec::new())),
             #[cfg(not(target_arch = "wasm32"))]
             listeners: Vec::new(),
             #[cfg(not(target_arch = "wasm32"))]
-            server_channels: Arc::new(RwLock::new(HashMap::new())),
+            server_channels: Arc::new(std::sync::RwLock::new(HashMap::new())),
+            #[cfg(not(target_arch = "wasm32"))]
+            connect_tokens: None,
+            #[cfg(not(target_arch = "wasm32"))]
+            bans: Arc::new(BanList::default()),
+            #[cfg(not(target_arch = "wasm32"))]
+            cookies: Arc::new(CookieJar::new()),
+            #[cfg(not(target_arch = "wasm32"))]
+            slots: Arc::new(ConnectionSlots::new(None)),
+            #[cfg(not(target_arch = "wasm32"))]
+            listener_settings: ListenerSettings::default(),
+            #[cfg(not(target_arch = "wasm32"))]
+            rate_limiter: Arc::new(HandshakeRateLimiter::new(&ListenerSettings::default())),
             runtime,
             packet_pool,
             channels_builder_fn: None,
+            extra_channels_builder_fns: Vec::new(),
             message_flushing_strategy,
+            message_registry: MessageRegistry::default(),
+            codecs: CodecRegistry::default(),
+            channels_fingerprint: None,
+            handshake_channel: HANDSHAKE_CHANNEL,
+            #[cfg(feature = "encryption")]
+            encryption: None,
 
             link_conditioner,
         }
@@ -177,19 +364,161 @@ impl NetworkResource {
 
     /// The 3 listening addresses aren't strictly necessary, you can put the same IP address with a different port for the socket address; Unless you have some configuration issues with public and private addresses that need to be connected to.
     /// They also aren't necessary if you're using UDP, so you can put anything if that's the case.
+    ///
+    /// `socket_address` may carry a transport scheme, i.e. `"ws://0.0.0.0:14193".parse()`
+    /// listens for WebSocket clients (`use-websocket` feature). WebRTC addresses are ignored then.
+    ///
+    /// With `use-webrtc` feature `"udp://..."` starts a built-in UDP listener next to
+    /// the WebRTC one, so a single server accepts both native and browser clients.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn listen<A: Into<NetworkAddress>>(
+        &mut self,
+        socket_address: A,
+        webrtc_listen_address: Option<SocketAddr>,
+        public_webrtc_address: Option<SocketAddr>,
+    ) {
+        let NetworkAddress {
+            transport,
+            socket_address,
+        } = socket_address.into();
+        match transport {
+            Transport::WebSocket => self.listen_websocket(socket_address),
+            transport if transport == Transport::default() => {
+                self.listen_naia(socket_address, webrtc_listen_address, public_webrtc_address)
+            }
+            Transport::Udp => self.listen_udp(socket_address),
+            Transport::WebRtc => log::error!(
+                "Cannot listen on {}://{}: enable `use-webrtc` feature",
+                transport,
+                socket_address
+            ),
+        }
+    }
+
+    /// Listeners started afterwards only accept clients connecting with a token
+    /// signed by `key`, see `connect_with_token`.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn require_connect_tokens(&mut self, key: ConnectTokenKey) {
+        self.connect_tokens = Some(Arc::new(ConnectTokenValidator::new(key)));
+    }
+
+    /// Applies to listeners started afterwards.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn set_listener_settings(&mut self, settings: ListenerSettings) {
+        self.rate_limiter = Arc::new(HandshakeRateLimiter::new(&settings));
+        self.listener_settings = settings;
+    }
+
+    /// Caps connections accepted by all listeners, `None` for no limit.
+    /// Connections above a lowered limit are kept.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn set_max_connections(&mut self, max_connections: Option<usize>) {
+        self.slots.set_max(max_connections);
+    }
+
+    /// Connections accepted by listeners, including pending ones.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn server_connections(&self) -> usize {
+        self.slots.used()
+    }
+
+    /// Message channel tasks still running, for diagnostics.
+    /// Finished ones are dropped, and those of a connection are cancelled when it disconnects.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn live_tasks(&self) -> usize {
+        self.runtime.live_tasks()
+    }
+
+    /// Disconnects matching peers and makes listeners drop their packets for `duration`.
+    /// Banning a handle bans the IP address of its peer.
+    /// Returns the number of disconnected connections.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn ban<T: Into<BanTarget>>(&mut self, target: T, duration: instant::Duration) -> usize {
+        let rule = match self.ban_rule(target.into()) {
+            Some(rule) => rule,
+            None => return 0,
+        };
+        self.bans.ban(rule, duration);
+
+        let banned: Vec<ConnectionHandle> = self
+            .connections
+            .iter()
+            .filter(|(_, connection)| {
+                connection.info().role == ConnectionRole::Server
+                    && connection
+                        .remote_address()
+                        .is_some_and(|address| rule.matches(address))
+            })
+            .map(|(handle, _)| *handle)
+            .collect();
+        for handle in banned.iter() {
+            self.disconnect(*handle);
+        }
+        banned.len()
+    }
+
+    /// Lifts a ban placed with the same target.
     #[cfg(not(target_arch = "wasm32"))]
-    pub fn listen(
+    pub fn unban<T: Into<BanTarget>>(&mut self, target: T) -> bool {
+        match self.ban_rule(target.into()) {
+            Some(rule) => self.bans.unban(rule),
+            None => false,
+        }
+    }
+
+    /// Whether listeners drop packets from `address`.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn is_banned(&self, address: SocketAddr) -> bool {
+        self.bans.is_banned(address)
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn ban_rule(&self, target: BanTarget) -> Option<BanRule> {
+        match target {
+            BanTarget::Handle(handle) => self
+                .connections
+                .get(&handle)
+                .and_then(|connection| connection.remote_address())
+                .map(|address| BanRule::Range(IpRange::from(address.ip()))),
+            BanTarget::Address(address) => Some(BanRule::Address(address)),
+            BanTarget::Range(range) => Some(BanRule::Range(range)),
+        }
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn listener_context(&self) -> ListenerContext {
+        ListenerContext {
+            task_pool: self.task_pool.clone(),
+            server_channels: self.server_channels.clone(),
+            pending_connections: self.pending_connections.clone(),
+            connect_tokens: self.connect_tokens.clone(),
+            bans: self.bans.clone(),
+            cookies: self.cookies.clone(),
+            slots: self.slots.clone(),
+            rate_limiter: self.rate_limiter.clone(),
+            settings: self.listener_settings.clone(),
+        }
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn listen_naia(
         &mut self,
         socket_address: SocketAddr,
         webrtc_listen_address: Option<SocketAddr>,
         public_webrtc_address: Option<SocketAddr>,
     ) {
+        let webrtc_listen_address = webrtc_listen_address.unwrap_or_else(|| {
+            let mut listen_addr = socket_address;
+            listen_addr.set_port(socket_address.port() + 1);
+            listen_addr
+        });
+        // WebRTC data flows through its own listen address, session address only signals
+        let local_address = if Transport::default() == Transport::WebRtc {
+            webrtc_listen_address
+        } else {
+            socket_address
+        };
         let mut server_socket = {
-            let webrtc_listen_address = webrtc_listen_address.unwrap_or_else(|| {
-                let mut listen_addr = socket_address;
-                listen_addr.set_port(socket_address.port() + 1);
-                listen_addr
-            });
             let public_webrtc_address = public_webrtc_address.unwrap_or(webrtc_listen_address);
             let socket = futures_lite::future::block_on(ServerSocket::listen(
                 socket_address,
@@ -204,74 +533,23 @@ impl NetworkResource {
             }
         };
         let sender = server_socket.get_sender();
-        let server_channels = self.server_channels.clone();
-        let pending_connections = self.pending_connections.clone();
-        let task_pool = self.task_pool.clone();
+        let context = self.listener_context();
 
         let receiver_task = self.task_pool.spawn(async move {
+            let mut reply_sender = ServerPacketSender::Naia(server_socket.get_sender());
             loop {
                 match server_socket.receive().await {
                     Ok(packet) => {
-                        let address = packet.address();
-                        let message = String::from_utf8_lossy(packet.payload());
-                        log::debug!(
-                            "Server recv <- {}:{}: {}",
-                            address,
-                            packet.payload().len(),
-                            message
+                        let reply = context.route_packet(
+                            Transport::default(),
+                            Some(local_address),
+                            packet.address(),
+                            packet.payload(),
+                            || ServerPacketSender::Naia(server_socket.get_sender()),
                         );
-
-                        let needs_new_channel = match server_channels
-                            .read()
-                            .expect("server channels lock is poisoned")
-                            .get(&address)
-                            .map(|channel| {
-                                channel.send(Ok(Packet::copy_from_slice(packet.payload())))
-                            }) {
-                            Some(Ok(())) => false,
-                            Some(Err(error)) => {
+                        if let Some(reply) = reply {
+                            if let Err(error) = reply_sender.send(packet.address(), &reply).await {
                                 log::error!("Server Send Error: {}", error);
-                                // If we can't send to a channel, it's disconnected.
-                                // We need to re-create the channel and re-try sending the message.
-                                true
-                            }
-                            // This is a new connection, so we need to create a channel.
-                            None => true,
-                        };
-
-                        if !needs_new_channel {
-                            continue;
-                        }
-
-                        // We try to do a write lock only in case when a channel doesn't exist or
-                        // has to be re-created. Trying to acquire a channel even for new
-                        // connections is kind of a positive prediction to avoid doing a write
-                        // lock.
-                        let mut server_channels = server_channels
-                            .write()
-                            .expect("server channels lock is poisoned");
-                        let (packet_tx, packet_rx): (
-                            Sender<Result<Packet, NetworkError>>,
-                            Receiver<Result<Packet, NetworkError>>,
-                        ) = unbounded();
-                        match packet_tx.send(Ok(Packet::copy_from_slice(packet.payload()))) {
-                            Ok(()) => {
-                                // It makes sense to store the channel only if it's healthy.
-                                pending_connections.lock().unwrap().push(Box::new(
-                                    transport::ServerConnection::new(
-                                        task_pool.clone(),
-                                        packet_rx,
-                                        server_socket.get_sender(),
-                                        address,
-                                    ),
-                                ));
-                                server_channels.insert(address, packet_tx);
-                            }
-                            Err(error) => {
-                                // This branch is unlikely to get called the second time (after
-                                // re-creating a channel), but if for some strange reason it does,
-                                // we'll just lose the message this time.
-                                log::error!("Server Send Error (retry): {}", error);
                             }
                         }
                     }
@@ -284,12 +562,95 @@ impl NetworkResource {
 
         self.listeners.push(ServerListener {
             receiver_task,
-            sender,
+            sender: Some(sender),
+            socket_address,
+        });
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn listen_udp(&mut self, socket_address: SocketAddr) {
+        let socket =
+            match futures_lite::future::block_on(async_net::UdpSocket::bind(socket_address)) {
+                Ok(socket) => Arc::new(socket),
+                Err(error) => {
+                    log::error!("Cannot listen on udp://{}: {}", socket_address, error);
+                    return;
+                }
+            };
+        if self.link_conditioner.is_some() {
+            log::warn!(
+                "Link conditioner is not applied to udp://{}",
+                socket_address
+            );
+        }
+
+        let receiver_task = self
+            .task_pool
+            .spawn(server::listen_udp(self.listener_context(), socket));
+
+        self.listeners.push(ServerListener {
+            receiver_task,
+            sender: None,
+            socket_address,
+        });
+    }
+
+    #[cfg(all(feature = "use-websocket", not(target_arch = "wasm32")))]
+    fn listen_websocket(&mut self, socket_address: SocketAddr) {
+        let receiver_task = self
+            .task_pool
+            .spawn(websocket::listen(self.listener_context(), socket_address));
+
+        self.listeners.push(ServerListener {
+            receiver_task,
+            sender: None,
             socket_address,
         });
     }
 
-    pub fn connect(&mut self, socket_address: SocketAddr) {
+    #[cfg(all(not(feature = "use-websocket"), not(target_arch = "wasm32")))]
+    fn listen_websocket(&mut self, socket_address: SocketAddr) {
+        log::error!(
+            "Cannot listen on ws://{}: enable `use-websocket` feature",
+            socket_address
+        );
+    }
+
+    /// `socket_address` may carry a transport scheme, i.e. `"ws://192.168.1.1:14193".parse()`
+    /// dials a WebSocket server (`use-websocket` feature).
+    /// Otherwise naia client socket is used, which speaks UDP natively and WebRTC in browsers.
+    pub fn connect<A: Into<NetworkAddress>>(&mut self, socket_address: A) {
+        let NetworkAddress {
+            transport,
+            socket_address,
+        } = socket_address.into();
+        if transport == Transport::WebSocket {
+            self.connect_websocket(socket_address, None);
+            return;
+        }
+        self.connect_naia(socket_address, None);
+    }
+
+    /// Like `connect`, presenting `token` from the matchmaker to a server
+    /// requiring connect tokens. `NetworkEvent::Error` with `NetworkError::Rejected`
+    /// is sent if the server refuses it.
+    pub fn connect_with_token<A: Into<NetworkAddress>>(
+        &mut self,
+        socket_address: A,
+        token: ConnectToken,
+    ) {
+        let NetworkAddress {
+            transport,
+            socket_address,
+        } = socket_address.into();
+        if transport == Transport::WebSocket {
+            self.connect_websocket(socket_address, Some(token));
+            return;
+        }
+        self.connect_naia(socket_address, Some(token));
+    }
+
+    fn connect_naia(&mut self, socket_address: SocketAddr, token: Option<ConnectToken>) {
         let mut client_socket = {
             let socket = ClientSocket::connect(socket_address);
 
@@ -301,16 +662,160 @@ impl NetworkResource {
         };
         let sender = client_socket.get_sender();
 
+        let mut connection = transport::ClientConnection::new(
+            self.task_pool.clone(),
+            client_socket,
+            sender,
+            socket_address,
+        );
+        if let Some(token) = token {
+            connection = connection.with_connect_token(token);
+        }
         self.pending_connections
             .lock()
             .unwrap()
-            .push(Box::new(transport::ClientConnection::new(
+            .push(Box::new(connection));
+    }
+
+    #[cfg(feature = "use-websocket")]
+    fn connect_websocket(&mut self, socket_address: SocketAddr, token: Option<ConnectToken>) {
+        self.pending_connections.lock().unwrap().push(Box::new(
+            websocket::WebSocketConnection::connect(
                 self.task_pool.clone(),
-                client_socket,
-                sender,
+                socket_address,
+                token,
+                self.listener_settings.receive_queue_len,
+            ),
+        ));
+    }
+
+    /// Dials the WebSocket server at `url` from a browser, i.e. `"wss://example.com/game"`.
+    /// Pages served over HTTPS may only open `wss://` sockets, which `connect` cannot express.
+    #[cfg(all(feature = "use-websocket", target_arch = "wasm32"))]
+    pub fn connect_url(&mut self, url: &str, token: Option<ConnectToken>) {
+        match websocket::WebSocketConnection::connect_url(
+            self.task_pool.clone(),
+            url,
+            token,
+            self.listener_settings.receive_queue_len,
+        ) {
+            Ok(connection) => self
+                .pending_connections
+                .lock()
+                .unwrap()
+                .push(Box::new(connection)),
+            Err(error) => log::error!("Cannot connect to {}: {}", url, error),
+        }
+    }
+
+    #[cfg(not(feature = "use-websocket"))]
+    fn connect_websocket(&mut self, socket_address: SocketAddr, _token: Option<ConnectToken>) {
+        log::error!(
+            "Cannot connect to ws://{}: enable `use-websocket` feature",
+            socket_address
+        );
+    }
+
+    /// Encrypts connections established from now on, see `EncryptedConnection`.
+    #[cfg(feature = "encryption")]
+    pub fn set_encryption(&mut self, encryption: Option<EncryptionConfig>) {
+        self.encryption = encryption;
+    }
+
+    /// Transport the connection runs over.
+    pub fn transport(&self, handle: ConnectionHandle) -> Option<Transport> {
+        self.connection_info(handle).map(|info| info.transport)
+    }
+
+    pub fn connection_info(&self, handle: ConnectionHandle) -> Option<&ConnectionInfo> {
+        self.connections
+            .get(&handle)
+            .map(|connection| connection.info())
+    }
+
+    /// Drops the connection together with its user data.
+    /// `NetworkEvent::Disconnected` is sent for it on the next `receive_packets` run.
+    pub fn disconnect(&mut self, handle: ConnectionHandle) -> bool {
+        if self.remove_connection(handle) {
+            self.disconnected.push(handle);
+            true
+        } else {
+            false
+        }
+    }
+
+    fn remove_connection(&mut self, handle: ConnectionHandle) -> bool {
+        self.user_data.remove(&handle);
+        self.runtime.cancel_connection(handle);
+        #[cfg_attr(target_arch = "wasm32", allow(unused_variables))]
+        let connection = match self.connections.remove(&handle) {
+            Some(connection) => connection,
+            None => return false,
+        };
+        #[cfg(not(target_arch = "wasm32"))]
+        self.release_connection(connection.info(), connection.remote_address());
+        true
+    }
+
+    /// Gives back the slot of a server connection and its datagram route.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn release_connection(&self, info: &ConnectionInfo, remote_address: Option<SocketAddr>) {
+        if info.role != ConnectionRole::Server {
+            return;
+        }
+        self.slots.release();
+        // forget the datagram route, so the peer gets a fresh connection if it comes back
+        if info.transport != Transport::WebSocket {
+            if let Some(address) = remote_address {
+                self.server_channels
+                    .write()
+                    .expect("server channels lock is poisoned")
+                    .remove(&address);
+            }
+        }
+    }
+
+    /// Attaches `data` to the connection, replacing and returning the previous value of that type.
+    /// User data is dropped when the connection is removed.
+    pub fn set_user_data<T: Send + Sync + 'static>(
+        &mut self,
+        handle: ConnectionHandle,
+        data: T,
+    ) -> Result<Option<T>, Box<dyn Error + Sync + Send + 'static>> {
+        if !self.connections.contains_key(&handle) {
+            return Err(Box::new(std::io::Error::new(
+                // FIXME: move to enum Error
+                std::io::ErrorKind::NotFound,
+                "No such connection",
             )));
+        }
+        Ok(self.user_data.entry(handle).or_default().insert(data))
     }
 
+    pub fn user_data<T: Send + Sync + 'static>(&self, handle: ConnectionHandle) -> Option<&T> {
+        self.user_data.get(&handle).and_then(|data| data.get())
+    }
+
+    pub fn user_data_mut<T: Send + Sync + 'static>(
+        &mut self,
+        handle: ConnectionHandle,
+    ) -> Option<&mut T> {
+        self.user_data
+            .get_mut(&handle)
+            .and_then(|data| data.get_mut())
+    }
+
+    pub fn remove_user_data<T: Send + Sync + 'static>(
+        &mut self,
+        handle: ConnectionHandle,
+    ) -> Option<T> {
+        self.user_data
+            .get_mut(&handle)
+            .and_then(|data| data.remove())
+    }
+
+    /// Fails also when the connection cannot take more packets right now,
+    /// i.e. its send queue is full with a slow peer, or its socket is closed.
     pub fn send(
         &mut self,
         handle: ConnectionHandle,
@@ -326,10 +831,16 @@ impl NetworkResource {
         }
     }
 
-    pub fn broadcast(&mut self, payload: Packet) {
-        for (_handle, connection) in self.connections.iter_mut() {
-            connection.send(payload.clone()).unwrap();
+    /// Sends the packet to every connection, returns those it failed for, see `send`.
+    pub fn broadcast(&mut self, payload: Packet) -> Vec<ConnectionHandle> {
+        let mut failed = Vec::new();
+        for (handle, connection) in self.connections.iter_mut() {
+            if let Err(error) = connection.send(payload.clone()) {
+                log::warn!("Broadcast to [{}] failed: {}", handle, error);
+                failed.push(*handle);
+            }
         }
+        failed
     }
 
     pub fn set_channels_builder<F>(&mut self, builder: F)
@@ -339,6 +850,51 @@ impl NetworkResource {
         self.channels_builder_fn = Some(Box::new(builder));
     }
 
+    /// Registers additional channels on every connection, on top of `set_channels_builder`.
+    /// Meant for plugins building on message channels, which use the reserved channel
+    /// numbers from `RESERVED_CHANNELS`.
+    pub fn add_channels_builder<F>(&mut self, builder: F)
+    where
+        F: Fn(&mut ConnectionChannelsBuilder) + Send + Sync + 'static,
+    {
+        self.extra_channels_builder_fns.push(Box::new(builder));
+    }
+
+    /// Moves the connection handshake off `HANDSHAKE_CHANNEL`, for connections established
+    /// from now on. Both peers have to use the same channel.
+    pub fn set_handshake_channel(&mut self, channel: u8) {
+        self.handshake_channel = channel;
+    }
+
+    fn has_channels(&self) -> bool {
+        self.channels_builder_fn.is_some() || !self.extra_channels_builder_fns.is_empty()
+    }
+
+    // channels of no particular connection, to check what was registered
+    fn channels_builder(&self) -> Result<ConnectionChannelsBuilder, NetworkError> {
+        self.channels_builder_with(self.runtime.clone())
+    }
+
+    // registers the handshake last, so a channel taken by the app is reported as such
+    fn channels_builder_with(
+        &self,
+        runtime: TaskPoolRuntime,
+    ) -> Result<ConnectionChannelsBuilder, NetworkError> {
+        let mut builder = ConnectionChannelsBuilder::new(runtime, self.packet_pool.clone());
+        if let Some(channels_builder_fn) = self.channels_builder_fn.as_ref() {
+            channels_builder_fn(&mut builder);
+        }
+        for extra_channels_builder_fn in self.extra_channels_builder_fns.iter() {
+            extra_channels_builder_fn(&mut builder);
+        }
+        let mut handshake_settings = HANDSHAKE_MESSAGE_SETTINGS;
+        handshake_settings.channel = self.handshake_channel;
+        builder
+            .register::<Handshake>(handshake_settings)
+            .map_err(|_| NetworkError::HandshakeChannelTaken(self.handshake_channel))?;
+        Ok(builder)
+    }
+
     pub fn send_message<M: ChannelMessage + Debug + Clone>(
         &mut self,
         handle: ConnectionHandle,
@@ -347,6 +903,15 @@ impl NetworkResource {
         match self.connections.get_mut(&handle) {
             Some(connection) => {
                 let channels = connection.channels().unwrap();
+                if let Some(codec) = self.codecs.get::<M>() {
+                    let encoded = Encoded::encode(&*codec, &message)
+                        .map_err(|error| Box::new(error) as Box<dyn Error + Send>)?;
+                    let unsent = channels.send(encoded).map(|_| message);
+                    if self.message_flushing_strategy == MessageFlushingStrategy::OnEverySend {
+                        channels.flush::<Encoded<M>>();
+                    }
+                    return Ok(unsent);
+                }
                 let unsent = channels.send(message);
                 if self.message_flushing_strategy == MessageFlushingStrategy::OnEverySend {
                     channels.flush::<M>();
@@ -363,6 +928,18 @@ impl NetworkResource {
 
     pub fn broadcast_message<M: ChannelMessage + Debug + Clone>(&mut self, message: M) {
         // log::info!("Broadcast:\n{:?}", message);
+        if let Some(codec) = self.codecs.get::<M>() {
+            match Encoded::encode(&*codec, &message) {
+                // encoded once for all connections
+                Ok(encoded) => self.broadcast_channel_message(encoded),
+                Err(error) => log::error!("Failed to encode broadcast: {}", error),
+            }
+        } else {
+            self.broadcast_channel_message(message);
+        }
+    }
+
+    fn broadcast_channel_message<M: ChannelMessage + Debug + Clone>(&mut self, message: M) {
         for (handle, connection) in self.connections.iter_mut() {
             let channels = connection.channels().unwrap();
             let result = channels.send(message.clone());
@@ -382,6 +959,17 @@ impl NetworkResource {
         match self.connections.get_mut(&handle) {
             Some(connection) => {
                 let channels = connection.channels().unwrap();
+                if let Some(codec) = self.codecs.get::<M>() {
+                    while let Some(encoded) = channels.recv::<Encoded<M>>() {
+                        match encoded.decode(&*codec) {
+                            Ok(message) => return Some(message),
+                            Err(error) => {
+                                log::error!("Failed to decode on [{}]: {}", handle, error)
+                            }
+                        }
+                    }
+                    return None;
+                }
                 channels.recv()
             }
             None => None,
@@ -395,21 +983,57 @@ pub fn receive_packets(
 ) {
     let pending_connections: Vec<Box<dyn Connection>> =
         net.pending_connections.lock().unwrap().drain(..).collect();
-    for mut conn in pending_connections {
+    for conn in pending_connections {
+        #[cfg(feature = "encryption")]
+        let conn: Box<dyn Connection> = match net.encryption.as_ref() {
+            Some(config) => {
+                #[cfg_attr(target_arch = "wasm32", allow(unused_variables))]
+                let (info, remote_address) = (conn.info().clone(), conn.remote_address());
+                match EncryptedConnection::new(conn, net.task_pool.clone(), config) {
+                    Ok(encrypted) => Box::new(encrypted),
+                    Err(error) => {
+                        log::error!("Cannot encrypt connection: {}", error);
+                        #[cfg(not(target_arch = "wasm32"))]
+                        net.release_connection(&info, remote_address);
+                        continue;
+                    }
+                }
+            }
+            None => conn,
+        };
+        let mut conn = conn;
         let handle: ConnectionHandle = net
             .connection_sequence
             .fetch_add(1, atomic::Ordering::Relaxed);
-        if let Some(channels_builder_fn) = net.channels_builder_fn.as_ref() {
-            conn.build_channels(
-                channels_builder_fn,
-                net.runtime.clone(),
-                net.packet_pool.clone(),
-            );
+        let mut channels_fingerprint = None;
+        let mut channels_error = None;
+        if net.has_channels() {
+            match net.channels_builder_with(net.runtime.for_connection(handle)) {
+                Ok(builder) => {
+                    channels_fingerprint = Some(builder.fingerprint());
+                    conn.build_channels(builder);
+                }
+                Err(error) => channels_error = Some(error),
+            }
         }
         net.connections.insert(handle, conn);
+        if let Some(channels_fingerprint) = channels_fingerprint {
+            net.channels_fingerprint = Some(channels_fingerprint);
+            net.send_handshake(handle, channels_fingerprint);
+        }
         network_events.send(NetworkEvent::Connected(handle));
+        if let Some(error) = channels_error {
+            log::error!("Failed to build channels of [{}]: {:?}", handle, error);
+            network_events.send(NetworkEvent::Error(handle, error));
+            net.disconnect(handle);
+        }
+    }
+
+    for handle in net.disconnected.drain(..) {
+        network_events.send(NetworkEvent::Disconnected(handle));
     }
 
+    let mut disconnected = Vec::new();
     let packet_pool = net.packet_pool.clone();
     for (handle, connection) in net.connections.iter_mut() {
         while let Some(result) = connection.receive() {
@@ -421,7 +1045,7 @@ pub fn receive_packets(
                         log::debug!("Processing as message");
                         let mut pool_packet = packet_pool.acquire();
                         pool_packet.resize(packet.len(), 0);
-                        pool_packet[..].copy_from_slice(&*packet);
+                        pool_packet[..].copy_from_slice(&packet);
                         match channels_rx.try_send(pool_packet) {
                             Ok(()) => {
                                 // cool
@@ -439,6 +1063,18 @@ pub fn receive_packets(
                         network_events.send(NetworkEvent::Packet(*handle, packet));
                     }
                 }
+                Err(NetworkError::Disconnected) => {
+                    log::debug!("Disconnected [{}]", handle);
+                    disconnected.push(*handle);
+                    break;
+                }
+                Err(NetworkError::Rejected(reason)) => {
+                    log::warn!("Connection [{}] rejected: {}", handle, reason);
+                    network_events
+                        .send(NetworkEvent::Error(*handle, NetworkError::Rejected(reason)));
+                    disconnected.push(*handle);
+                    break;
+                }
                 Err(err) => {
                     log::error!("Receive Error: {:?}", err);
                     network_events.send(NetworkEvent::Error(*handle, err));
@@ -446,4 +1082,130 @@ pub fn receive_packets(
             }
         }
     }
+
+    for handle in disconnected {
+        net.remove_connection(handle);
+        network_events.send(NetworkEvent::Disconnected(handle));
+    }
+
+    net.verify_handshakes(&mut network_events);
+}
+
+/// Lets connections send out what they buffered during the frame.
+pub fn flush_connections(mut net: ResMut<NetworkResource>) {
+    for (_handle, connection) in net.connections.iter_mut() {
+        connection.flush();
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::{collections::VecDeque, sync::RwLock};
+    use turbulence::{
+        message_channels::MessageChannels, packet_multiplexer::IncomingMultiplexedPackets,
+    };
+
+    /// Connection fed by the test instead of a socket.
+    pub(crate) struct TestConnection {
+        pub info: ConnectionInfo,
+        pub incoming: VecDeque<Result<Packet, NetworkError>>,
+        pub stats: Arc<RwLock<PacketStats>>,
+    }
+
+    impl TestConnection {
+        pub fn new(role: ConnectionRole) -> Self {
+            TestConnection {
+                info: ConnectionInfo::new(Transport::default(), role, None),
+                incoming: VecDeque::new(),
+                stats: Arc::new(RwLock::new(PacketStats::default())),
+            }
+        }
+    }
+
+    impl Connection for TestConnection {
+        fn remote_address(&self) -> Option<SocketAddr> {
+            None
+        }
+
+        fn info(&self) -> &ConnectionInfo {
+            &self.info
+        }
+
+        fn send(&mut self, _payload: Packet) -> Result<(), Box<dyn Error + Sync + Send>> {
+            Ok(())
+        }
+
+        fn receive(&mut self) -> Option<Result<Packet, NetworkError>> {
+            self.incoming.pop_front()
+        }
+
+        fn build_channels(&mut self, _builder: ConnectionChannelsBuilder) {}
+
+        fn channels(&mut self) -> Option<&mut MessageChannels> {
+            None
+        }
+
+        fn channels_rx(&mut self) -> Option<&mut IncomingMultiplexedPackets<MultiplexedPacket>> {
+            None
+        }
+
+        fn stats(&self) -> PacketStats {
+            self.stats.read().unwrap().clone()
+        }
+
+        fn last_packet_timings(&self) -> (u128, u128) {
+            (0, 0)
+        }
+    }
+
+    pub(crate) fn network() -> NetworkResource {
+        NetworkResource::new(TaskPool::new(), None, MessageFlushingStrategy::default())
+    }
+
+    pub(crate) fn add_connection(
+        net: &mut NetworkResource,
+        connection: TestConnection,
+    ) -> ConnectionHandle {
+        let handle = net
+            .connection_sequence
+            .fetch_add(1, atomic::Ordering::Relaxed);
+        net.connections.insert(handle, Box::new(connection));
+        handle
+    }
+
+    #[test]
+    fn user_data_needs_a_connection() {
+        let mut net = network();
+        assert!(net.set_user_data(0, 1u32).is_err());
+        assert_eq!(net.user_data::<u32>(0), None);
+    }
+
+    #[test]
+    fn user_data_is_kept_per_connection() {
+        let mut net = network();
+        let first = add_connection(&mut net, TestConnection::new(ConnectionRole::Server));
+        let second = add_connection(&mut net, TestConnection::new(ConnectionRole::Server));
+
+        assert_eq!(net.set_user_data(first, 1u32).unwrap(), None);
+        assert_eq!(net.set_user_data(first, 2u32).unwrap(), Some(1));
+        assert_eq!(net.user_data::<u32>(first), Some(&2));
+        assert_eq!(net.user_data::<u32>(second), None);
+
+        *net.user_data_mut::<u32>(first).unwrap() += 1;
+        assert_eq!(net.remove_user_data::<u32>(first), Some(3));
+        assert_eq!(net.user_data::<u32>(first), None);
+    }
+
+    #[test]
+    fn user_data_is_dropped_with_the_connection() {
+        let mut net = network();
+        let handle = add_connection(&mut net, TestConnection::new(ConnectionRole::Server));
+        net.set_user_data(handle, 1u32).unwrap();
+
+        assert!(net.disconnect(handle));
+        assert_eq!(net.user_data::<u32>(handle), None);
+        assert!(net.set_user_data(handle, 1u32).is_err());
+        assert!(!net.disconnect(handle));
+    }
 }
@@ -0,0 +1,277 @@
+//! Message types registered without hand-written channel settings.
+//!
+//! `NetworkResource::register_message` assigns channels from `MESSAGE_CHANNELS` in registration
+//! order, so client and server have to register the same types in the same order. The fingerprint
+//! of registered names and settings is compared in the connection handshake, connections of peers
+//! that differ are dropped with `NetworkError::MessagesMismatch`.
+
+use instant::Duration;
+use std::{
+    any::TypeId,
+    error::Error,
+    fmt::{self, Debug},
+};
+use turbulence::message_channels::ChannelMessage;
+
+use super::{
+    codec::MessageCodec,
+    hash::Fnv1a,
+    transport::{copy_channel_mode, copy_channel_settings, hash_channel_settings},
+    ConnectionChannelsBuilder, MessageChannelMode, MessageChannelSettings, NetworkResource,
+    ReliableChannelSettings, MESSAGE_CHANNELS,
+};
+
+const DEFAULT_RELIABILITY_SETTINGS: ReliableChannelSettings = ReliableChannelSettings {
+    bandwidth: 4096,
+    recv_window_size: 1024,
+    send_window_size: 1024,
+    burst_bandwidth: 1024,
+    init_send: 512,
+    wakeup_time: Duration::from_millis(100),
+    initial_rtt: Duration::from_millis(200),
+    max_rtt: Duration::from_secs(2),
+    rtt_update_factor: 0.1,
+    rtt_resend_factor: 1.5,
+};
+
+/// Delivery of a registered message type.
+#[derive(Debug)]
+pub enum MessageMode {
+    /// Unreliable channel with small buffers, for frequent state updates.
+    Unreliable,
+    /// Reliable channel for messages up to 4096 bytes.
+    Reliable,
+    /// Explicit channel mode, buffers sized by its reliability.
+    Custom(MessageChannelMode),
+}
+
+impl MessageMode {
+    fn settings(&self, channel: u8) -> MessageChannelSettings {
+        let channel_mode = match self {
+            MessageMode::Unreliable => MessageChannelMode::Unreliable,
+            MessageMode::Reliable => MessageChannelMode::Reliable {
+                reliability_settings: DEFAULT_RELIABILITY_SETTINGS,
+                max_message_len: 4096,
+            },
+            MessageMode::Custom(channel_mode) => copy_channel_mode(channel_mode),
+        };
+        let buffer_size = match channel_mode {
+            MessageChannelMode::Unreliable => 8,
+            MessageChannelMode::Reliable { .. } | MessageChannelMode::Compressed { .. } => 64,
+        };
+        MessageChannelSettings {
+            channel,
+            channel_mode,
+            message_buffer_size: buffer_size,
+            packet_buffer_size: buffer_size,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MessageRegistrationError {
+    /// The message type or its name was registered before.
+    AlreadyRegistered(&'static str),
+    /// All of `MESSAGE_CHANNELS` are taken.
+    OutOfChannels,
+}
+
+impl fmt::Display for MessageRegistrationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MessageRegistrationError::AlreadyRegistered(name) => {
+                write!(f, "message {} is registered already", name)
+            }
+            MessageRegistrationError::OutOfChannels => {
+                write!(f, "no channels left for registered messages")
+            }
+        }
+    }
+}
+
+impl Error for MessageRegistrationError {}
+
+/// Message types registered with `NetworkResource::register_message`, in order.
+#[derive(Default)]
+pub(crate) struct MessageRegistry {
+    registered: Vec<(TypeId, &'static str, MessageChannelSettings)>,
+}
+
+impl MessageRegistry {
+    pub fn fingerprint(&self) -> u64 {
+        let mut hasher = Fnv1a::default();
+        for (_, name, settings) in self.registered.iter() {
+            hasher.write(&(name.len() as u64).to_le_bytes());
+            hasher.write(name.as_bytes());
+            hash_channel_settings(&mut hasher, settings);
+        }
+        hasher.finish()
+    }
+}
+
+impl NetworkResource {
+    /// Registers message type `M` on the next free channel of `MESSAGE_CHANNELS` and returns
+    /// the channel. Has to be called before connections are established, in the same order on
+    /// all peers. `name` identifies the type in the handshake, both peers have to use the same one.
+    pub fn register_message<M: ChannelMessage + Debug + Clone>(
+        &mut self,
+        name: &'static str,
+        mode: MessageMode,
+    ) -> Result<u8, MessageRegistrationError> {
+        let settings = self.next_message_settings::<M>(name, mode)?;
+        let channel = settings.channel;
+        self.add_channels_builder(move |builder: &mut ConnectionChannelsBuilder| {
+            // only fails when `set_channels_builder` took a channel of `MESSAGE_CHANNELS`
+            if let Err(error) = builder.register_named::<M>(name, copy_channel_settings(&settings))
+            {
+                log::error!(
+                    "Failed to register message {} on channel {}: {}",
+                    name,
+                    settings.channel,
+                    error
+                );
+            }
+        });
+        Ok(channel)
+    }
+
+    /// Same as `register_message`, with messages encoded by `codec`.
+    pub fn register_message_with_codec<M, C>(
+        &mut self,
+        name: &'static str,
+        mode: MessageMode,
+        codec: C,
+    ) -> Result<u8, MessageRegistrationError>
+    where
+        M: Send + Sync + 'static,
+        C: MessageCodec<M>,
+    {
+        let settings = self.next_message_settings::<M>(name, mode)?;
+        let channel = settings.channel;
+        self.add_codec(Some(name), settings, codec);
+        Ok(channel)
+    }
+
+    fn next_message_settings<M: 'static>(
+        &mut self,
+        name: &'static str,
+        mode: MessageMode,
+    ) -> Result<MessageChannelSettings, MessageRegistrationError> {
+        let registered = &self.message_registry.registered;
+        if registered.iter().any(|(type_id, registered_name, _)| {
+            *type_id == TypeId::of::<M>() || *registered_name == name
+        }) {
+            return Err(MessageRegistrationError::AlreadyRegistered(name));
+        }
+        let channel = *MESSAGE_CHANNELS.start() as usize + registered.len();
+        if channel > *MESSAGE_CHANNELS.end() as usize {
+            return Err(MessageRegistrationError::OutOfChannels);
+        }
+        let settings = mode.settings(channel as u8);
+        self.message_registry.registered.push((
+            TypeId::of::<M>(),
+            name,
+            copy_channel_settings(&settings),
+        ));
+        Ok(settings)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::network;
+    use serde::{Deserialize, Serialize};
+    use turbulence::message_channels::ChannelAlreadyRegistered;
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    struct Chat(String);
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    struct Move(f32, f32);
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    struct Probe;
+
+    fn settings(channel: u8) -> MessageChannelSettings {
+        MessageMode::Unreliable.settings(channel)
+    }
+
+    #[test]
+    fn registered_messages_do_not_collide() {
+        let mut net = network();
+        net.set_channels_builder(|builder: &mut ConnectionChannelsBuilder| {
+            builder.register::<String>(settings(0)).unwrap();
+        });
+        let chat = net
+            .register_message::<Chat>("chat", MessageMode::Reliable)
+            .unwrap();
+        let moves = net
+            .register_message::<Move>("move", MessageMode::Unreliable)
+            .unwrap();
+        assert_eq!(chat, *MESSAGE_CHANNELS.start());
+        assert_eq!(moves, chat + 1);
+
+        assert_eq!(
+            net.register_message::<Chat>("chat2", MessageMode::Unreliable),
+            Err(MessageRegistrationError::AlreadyRegistered("chat2"))
+        );
+        assert_eq!(
+            net.register_message::<Probe>("chat", MessageMode::Unreliable),
+            Err(MessageRegistrationError::AlreadyRegistered("chat"))
+        );
+        assert_eq!(
+            net.register_message::<Probe>("probe", MessageMode::Unreliable),
+            Ok(moves + 1)
+        );
+
+        // every type got a channel of its own
+        let mut builder = net.channels_builder().unwrap();
+        let registered = |result| matches!(result, Err(ChannelAlreadyRegistered::MessageType));
+        assert!(registered(builder.register::<Chat>(settings(1))));
+        assert!(registered(builder.register::<Move>(settings(2))));
+        assert!(registered(builder.register::<Probe>(settings(3))));
+        assert!(matches!(
+            builder.register::<u32>(settings(chat)),
+            Err(ChannelAlreadyRegistered::Channel)
+        ));
+        assert!(builder.register::<u32>(settings(4)).is_ok());
+    }
+
+    #[test]
+    fn registered_messages_run_out_of_channels() {
+        let mut net = network();
+        for _ in MESSAGE_CHANNELS {
+            net.message_registry
+                .registered
+                .push((TypeId::of::<()>(), "filler", settings(0)));
+        }
+        assert_eq!(
+            net.register_message::<Chat>("chat", MessageMode::Unreliable),
+            Err(MessageRegistrationError::OutOfChannels)
+        );
+    }
+
+    #[test]
+    fn fingerprint_covers_names_and_settings() {
+        let fingerprint = |name, mode: MessageMode| {
+            let mut registry = MessageRegistry::default();
+            registry
+                .registered
+                .push((TypeId::of::<Chat>(), name, mode.settings(128)));
+            registry.fingerprint()
+        };
+        let reliable = fingerprint("chat", MessageMode::Reliable);
+        assert_eq!(reliable, fingerprint("chat", MessageMode::Reliable));
+        assert_ne!(reliable, fingerprint("chat2", MessageMode::Reliable));
+        assert_ne!(reliable, fingerprint("chat", MessageMode::Unreliable));
+        let compressed = MessageChannelMode::Compressed {
+            reliability_settings: DEFAULT_RELIABILITY_SETTINGS,
+            max_chunk_len: 4096,
+        };
+        assert_ne!(
+            reliable,
+            fingerprint("chat", MessageMode::Custom(compressed))
+        );
+    }
+}
@@ -0,0 +1,447 @@
+//! Client side prediction with server reconciliation.
+//!
+//! The client applies its inputs locally right away with `Predicted::predict`, numbering them
+//! with consecutive ticks, and sends all not yet confirmed inputs to the server every frame.
+//! The server runs the same simulation on its own copy of the state in `Authoritative`, and
+//! sends that back with the tick of the last input it processed. On arrival the client resets
+//! to the authoritative state and replays inputs the server did not process yet.
+//!
+//! Both sides use the same `fn(&mut State, &Input)` simulation step passed to `PredictionPlugin`.
+
+use bevy_app::{AppBuilder, CoreStage, Plugin};
+use bevy_ecs::prelude::*;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Debug,
+    marker::PhantomData,
+};
+
+use super::{
+    sequence::sequence_greater_than, ConnectionChannelsBuilder, ConnectionHandle, ConnectionRole,
+    MessageChannelMode, MessageChannelSettings, NetworkResource,
+};
+
+pub const PREDICTION_INPUT_CHANNEL: u8 = 247;
+pub const PREDICTION_STATE_CHANNEL: u8 = 246;
+
+pub trait PredictionData:
+    Clone + Debug + Serialize + DeserializeOwned + Send + Sync + 'static
+{
+}
+
+impl<T> PredictionData for T where
+    T: Clone + Debug + Serialize + DeserializeOwned + Send + Sync + 'static
+{
+}
+
+/// Simulation step, applying a single input to the state.
+pub type SimulateFn<I, S> = fn(&mut S, &I);
+
+/// Not yet confirmed client inputs, oldest first.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(bound = "I: PredictionData")]
+pub struct InputMessage<I: PredictionData> {
+    pub inputs: Vec<(u32, I)>,
+}
+
+/// Authoritative state after processing input `tick`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(bound = "S: PredictionData")]
+pub struct StateMessage<S: PredictionData> {
+    pub tick: u32,
+    pub state: S,
+}
+
+/// Client side predicted state.
+pub struct Predicted<I, S> {
+    simulate: SimulateFn<I, S>,
+    max_pending: usize,
+    tick: u32,
+    state: S,
+    pending: VecDeque<(u32, I)>,
+    confirmed_tick: Option<u32>,
+    corrections: usize,
+}
+
+impl<I: PredictionData, S: PredictionData> Predicted<I, S> {
+    pub fn state(&self) -> &S {
+        &self.state
+    }
+
+    /// Tick of the last predicted input.
+    pub fn tick(&self) -> u32 {
+        self.tick
+    }
+
+    /// Tick of the last input processed by the server.
+    pub fn confirmed_tick(&self) -> Option<u32> {
+        self.confirmed_tick
+    }
+
+    /// Number of reconciliations where the replayed state differed from the prediction.
+    pub fn corrections(&self) -> usize {
+        self.corrections
+    }
+
+    /// Applies `input` to the predicted state and queues it for the server.
+    pub fn predict(&mut self, input: I) {
+        self.tick = self.tick.wrapping_add(1);
+        (self.simulate)(&mut self.state, &input);
+        self.pending.push_back((self.tick, input));
+        while self.pending.len() > self.max_pending {
+            // server is not keeping up, do not grow the input message forever
+            self.pending.pop_front();
+        }
+    }
+
+    fn reconcile(&mut self, tick: u32, state: S)
+    where
+        S: PartialEq,
+    {
+        if self
+            .confirmed_tick
+            .is_some_and(|confirmed| !sequence_greater_than(tick, confirmed))
+        {
+            return;
+        }
+        self.confirmed_tick = Some(tick);
+        while matches!(self.pending.front(), Some((pending_tick, _)) if !sequence_greater_than(*pending_tick, tick))
+        {
+            self.pending.pop_front();
+        }
+
+        let mut replayed = state;
+        for (_, input) in self.pending.iter() {
+            (self.simulate)(&mut replayed, input);
+        }
+        if replayed != self.state {
+            self.corrections += 1;
+        }
+        self.state = replayed;
+    }
+}
+
+/// Server side authoritative states, one per client.
+pub struct Authoritative<I, S> {
+    simulate: SimulateFn<I, S>,
+    peers: HashMap<ConnectionHandle, AuthoritativePeer<S>>,
+}
+
+struct AuthoritativePeer<S> {
+    state: S,
+    tick: Option<u32>,
+}
+
+impl<I: PredictionData, S: PredictionData + Default> Authoritative<I, S> {
+    pub fn state(&self, handle: ConnectionHandle) -> Option<&S> {
+        self.peers.get(&handle).map(|peer| &peer.state)
+    }
+
+    /// Server side adjustments, sent to the client with the next state message.
+    pub fn state_mut(&mut self, handle: ConnectionHandle) -> Option<&mut S> {
+        self.peers.get_mut(&handle).map(|peer| &mut peer.state)
+    }
+
+    /// Tick of the last input processed for `handle`.
+    pub fn tick(&self, handle: ConnectionHandle) -> Option<u32> {
+        self.peers.get(&handle).and_then(|peer| peer.tick)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (ConnectionHandle, &S)> {
+        self.peers
+            .iter()
+            .map(|(handle, peer)| (*handle, &peer.state))
+    }
+}
+
+pub fn prediction_server_receive_system<I, S>(
+    mut net: ResMut<NetworkResource>,
+    mut authoritative: ResMut<Authoritative<I, S>>,
+) where
+    I: PredictionData,
+    S: PredictionData + Default,
+{
+    let authoritative = &mut *authoritative;
+    authoritative
+        .peers
+        .retain(|handle, _| net.connections.contains_key(handle));
+
+    for (handle, connection) in net.connections.iter_mut() {
+        if connection.info().role != ConnectionRole::Server {
+            continue;
+        }
+        let channels = match connection.channels() {
+            Some(channels) => channels,
+            None => continue,
+        };
+        let peer = authoritative
+            .peers
+            .entry(*handle)
+            .or_insert_with(|| AuthoritativePeer {
+                state: S::default(),
+                tick: None,
+            });
+        while let Some(message) = channels.recv::<InputMessage<I>>() {
+            for (tick, input) in message.inputs.iter() {
+                // inputs are resent until confirmed, skip the ones already processed
+                if peer
+                    .tick
+                    .is_none_or(|processed| sequence_greater_than(*tick, processed))
+                {
+                    (authoritative.simulate)(&mut peer.state, input);
+                    peer.tick = Some(*tick);
+                }
+            }
+        }
+    }
+}
+
+pub fn prediction_server_send_system<I, S>(
+    mut net: ResMut<NetworkResource>,
+    authoritative: Res<Authoritative<I, S>>,
+) where
+    I: PredictionData,
+    S: PredictionData + Default,
+{
+    for (handle, peer) in authoritative.peers.iter() {
+        let tick = match peer.tick {
+            Some(tick) => tick,
+            None => continue,
+        };
+        if let Some(channels) = net
+            .connections
+            .get_mut(handle)
+            .and_then(|connection| connection.channels())
+        {
+            let message = StateMessage {
+                tick,
+                state: peer.state.clone(),
+            };
+            if channels.send(message).is_some() {
+                log::debug!("Prediction state channel of [{}] is full", handle);
+            }
+            channels.flush::<StateMessage<S>>();
+        }
+    }
+}
+
+pub fn prediction_client_receive_system<I, S>(
+    mut net: ResMut<NetworkResource>,
+    mut predicted: ResMut<Predicted<I, S>>,
+) where
+    I: PredictionData,
+    S: PredictionData + PartialEq,
+{
+    for (_handle, connection) in net.connections.iter_mut() {
+        if connection.info().role != ConnectionRole::Client {
+            continue;
+        }
+        if let Some(channels) = connection.channels() {
+            // unreliable channel, only the newest state matters
+            let mut newest: Option<StateMessage<S>> = None;
+            while let Some(message) = channels.recv::<StateMessage<S>>() {
+                if newest
+                    .as_ref()
+                    .is_none_or(|newest| sequence_greater_than(message.tick, newest.tick))
+                {
+                    newest = Some(message);
+                }
+            }
+            if let Some(message) = newest {
+                predicted.reconcile(message.tick, message.state);
+            }
+        }
+    }
+}
+
+pub fn prediction_client_send_system<I, S>(
+    mut net: ResMut<NetworkResource>,
+    predicted: Res<Predicted<I, S>>,
+) where
+    I: PredictionData,
+    S: PredictionData,
+{
+    if predicted.pending.is_empty() {
+        return;
+    }
+    let message = InputMessage {
+        inputs: predicted.pending.iter().cloned().collect(),
+    };
+    for (handle, connection) in net.connections.iter_mut() {
+        if connection.info().role != ConnectionRole::Client {
+            continue;
+        }
+        if let Some(channels) = connection.channels() {
+            if channels.send(message.clone()).is_some() {
+                log::debug!("Prediction input channel of [{}] is full", handle);
+            }
+            channels.flush::<InputMessage<I>>();
+        }
+    }
+}
+
+/// Predicts state `S` driven by inputs `I` on clients and reconciles it with the server.
+pub struct PredictionPlugin<I, S> {
+    pub simulate: SimulateFn<I, S>,
+    pub input_channel: u8,
+    pub state_channel: u8,
+    /// Unconfirmed inputs kept on the client, also the redundancy of input messages.
+    pub max_pending_inputs: usize,
+    marker: PhantomData<fn() -> (I, S)>,
+}
+
+impl<I, S> PredictionPlugin<I, S> {
+    pub fn new(simulate: SimulateFn<I, S>) -> Self {
+        PredictionPlugin {
+            simulate,
+            input_channel: PREDICTION_INPUT_CHANNEL,
+            state_channel: PREDICTION_STATE_CHANNEL,
+            max_pending_inputs: 64,
+            marker: PhantomData,
+        }
+    }
+
+    pub fn with_channels(mut self, input_channel: u8, state_channel: u8) -> Self {
+        self.input_channel = input_channel;
+        self.state_channel = state_channel;
+        self
+    }
+
+    pub fn with_max_pending_inputs(mut self, max_pending_inputs: usize) -> Self {
+        self.max_pending_inputs = max_pending_inputs;
+        self
+    }
+}
+
+impl<I, S> Plugin for PredictionPlugin<I, S>
+where
+    I: PredictionData,
+    S: PredictionData + Default + PartialEq,
+{
+    fn build(&self, app: &mut AppBuilder) {
+        let input_channel = self.input_channel;
+        let state_channel = self.state_channel;
+        app.world_mut()
+            .get_resource_mut::<NetworkResource>()
+            .expect("`NetworkingPlugin` has to be added before `PredictionPlugin`")
+            .add_channels_builder(move |builder: &mut ConnectionChannelsBuilder| {
+                builder
+                    .register::<InputMessage<I>>(MessageChannelSettings {
+                        channel: input_channel,
+                        channel_mode: MessageChannelMode::Unreliable,
+                        message_buffer_size: 32,
+                        packet_buffer_size: 32,
+                    })
+                    .unwrap();
+                builder
+                    .register::<StateMessage<S>>(MessageChannelSettings {
+                        channel: state_channel,
+                        channel_mode: MessageChannelMode::Unreliable,
+                        message_buffer_size: 32,
+                        packet_buffer_size: 32,
+                    })
+                    .unwrap();
+            });
+
+        app.insert_resource(Predicted::<I, S> {
+            simulate: self.simulate,
+            max_pending: self.max_pending_inputs,
+            tick: 0,
+            state: S::default(),
+            pending: VecDeque::new(),
+            confirmed_tick: None,
+            corrections: 0,
+        })
+        .insert_resource(Authoritative::<I, S> {
+            simulate: self.simulate,
+            peers: HashMap::new(),
+        })
+        .add_system_to_stage(
+            CoreStage::PreUpdate,
+            prediction_server_receive_system::<I, S>.system(),
+        )
+        .add_system_to_stage(
+            CoreStage::PreUpdate,
+            prediction_client_receive_system::<I, S>.system(),
+        )
+        .add_system_to_stage(
+            CoreStage::PostUpdate,
+            prediction_server_send_system::<I, S>.system(),
+        )
+        .add_system_to_stage(
+            CoreStage::PostUpdate,
+            prediction_client_send_system::<I, S>.system(),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn add(state: &mut i32, input: &i32) {
+        *state += input;
+    }
+
+    fn predicted(max_pending: usize) -> Predicted<i32, i32> {
+        Predicted {
+            simulate: add,
+            max_pending,
+            tick: 0,
+            state: 0,
+            pending: VecDeque::new(),
+            confirmed_tick: None,
+            corrections: 0,
+        }
+    }
+
+    fn pending_ticks(predicted: &Predicted<i32, i32>) -> Vec<u32> {
+        predicted.pending.iter().map(|(tick, _)| *tick).collect()
+    }
+
+    #[test]
+    fn reconcile_drops_acked_inputs_and_replays_the_rest() {
+        let mut predicted = predicted(64);
+        for input in [1, 2, 4, 8].iter() {
+            predicted.predict(*input);
+        }
+        assert_eq!(predicted.state(), &15);
+
+        // server processed 1 and 2, and added 100 on its own
+        predicted.reconcile(2, 103);
+        assert_eq!(pending_ticks(&predicted), vec![3, 4]);
+        assert_eq!(predicted.state(), &115);
+        assert_eq!(predicted.confirmed_tick(), Some(2));
+        assert_eq!(predicted.corrections(), 1);
+
+        predicted.reconcile(4, 115);
+        assert!(predicted.pending.is_empty());
+        assert_eq!(predicted.state(), &115);
+        assert_eq!(predicted.corrections(), 1);
+    }
+
+    #[test]
+    fn reconcile_ignores_stale_states() {
+        let mut predicted = predicted(64);
+        for input in [1, 2, 4].iter() {
+            predicted.predict(*input);
+        }
+        predicted.reconcile(2, 3);
+        predicted.reconcile(1, 1);
+        assert_eq!(predicted.confirmed_tick(), Some(2));
+        assert_eq!(pending_ticks(&predicted), vec![3]);
+        assert_eq!(predicted.state(), &7);
+        assert_eq!(predicted.corrections(), 0);
+    }
+
+    #[test]
+    fn predict_keeps_max_pending_inputs() {
+        let mut predicted = predicted(2);
+        for input in [1, 2, 4].iter() {
+            predicted.predict(*input);
+        }
+        assert_eq!(predicted.tick(), 3);
+        assert_eq!(pending_ticks(&predicted), vec![2, 3]);
+    }
+}
@@ -0,0 +1,751 @@
+//! Replication of ECS components from server to clients.
+//!
+//! Entities with the `Replicate` marker get their registered components serialized when Bevy
+//! change detection flags them, and diffed against the state each client already has. New clients
+//! get the full state once. Updates travel over a dedicated reliable channel, which delivers in
+//! order or drops the connection, so there are no acknowledgements: the next diff is taken against
+//! whatever the channel accepted.
+//!
+//! Clients spawn a local entity for every replicated server entity, tagged with `Replicated`,
+//! see `ReplicatedEntities` for mapping server entities to local ones.
+//!
+//! Both sides must register the same component types in the same order:
+//!
+//! ```ignore
+//! app.add_plugin(NetworkingPlugin::default())
+//!     .add_plugin(ReplicationPlugin)
+//!     .replicate::<Position>()
+//!     .replicate::<Health>();
+//! ```
+
+use bevy_app::{AppBuilder, CoreStage, Plugin};
+use bevy_ecs::{component::Component, prelude::*};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    time::Duration,
+};
+
+use super::{
+    ConnectionChannelsBuilder, ConnectionHandle, ConnectionRole, MessageChannelMode,
+    MessageChannelSettings, NetworkResource, ReliableChannelSettings,
+};
+
+pub const REPLICATION_CHANNEL: u8 = 250;
+
+/// Bigger updates are split into multiple messages, single components bigger than that
+/// are not replicated.
+const MAX_MESSAGE_LEN: usize = 8192;
+// leaves space for the message envelope
+const MESSAGE_BUDGET: usize = MAX_MESSAGE_LEN - 256;
+
+const REPLICATION_MESSAGE_SETTINGS: MessageChannelSettings = MessageChannelSettings {
+    channel: REPLICATION_CHANNEL,
+    channel_mode: MessageChannelMode::Reliable {
+        reliability_settings: ReliableChannelSettings {
+            bandwidth: 65536,
+            recv_window_size: 65536,
+            send_window_size: 65536,
+            burst_bandwidth: 16384,
+            init_send: 4096,
+            wakeup_time: Duration::from_millis(100),
+            initial_rtt: Duration::from_millis(200),
+            max_rtt: Duration::from_secs(2),
+            rtt_update_factor: 0.1,
+            rtt_resend_factor: 1.5,
+        },
+        max_message_len: MAX_MESSAGE_LEN,
+    },
+    message_buffer_size: 64,
+    packet_buffer_size: 64,
+};
+
+/// Index of a component type in `ReplicationRegistry`.
+pub type ComponentKind = u16;
+
+/// Marks a server entity for replication.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Replicate;
+
+/// Marks a client entity mirroring a server entity.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Replicated {
+    pub handle: ConnectionHandle,
+    pub server_entity: Entity,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ReplicationMessage {
+    pub updates: Vec<EntityUpdate>,
+    pub despawned: Vec<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EntityUpdate {
+    pub entity: u64,
+    pub changed: Vec<(ComponentKind, Vec<u8>)>,
+    pub removed: Vec<ComponentKind>,
+}
+
+// bincode encoded lengths, vector lengths take 8 bytes
+const UPDATE_LEN: usize = 24;
+const CHANGED_LEN: usize = 10;
+const REMOVED_LEN: usize = 2;
+const DESPAWNED_LEN: usize = 8;
+
+impl EntityUpdate {
+    fn len(&self) -> usize {
+        UPDATE_LEN
+            + self
+                .changed
+                .iter()
+                .map(|(_, bytes)| CHANGED_LEN + bytes.len())
+                .sum::<usize>()
+            + REMOVED_LEN * self.removed.len()
+    }
+}
+
+type SerializeFn = fn(&World, Entity) -> Option<Vec<u8>>;
+type ApplyFn = fn(&mut World, Entity, &[u8]) -> Result<(), bincode::Error>;
+type RemoveFn = fn(&mut World, Entity);
+
+struct ReplicatedComponent {
+    name: &'static str,
+    serialize: SerializeFn,
+    apply: ApplyFn,
+    remove: RemoveFn,
+}
+
+/// Component types being replicated, in registration order.
+#[derive(Default)]
+pub struct ReplicationRegistry {
+    components: Vec<ReplicatedComponent>,
+}
+
+impl ReplicationRegistry {
+    /// Returns `false` if `C` was registered already.
+    pub fn register<C: Component + Serialize + DeserializeOwned>(&mut self) -> bool {
+        let name = std::any::type_name::<C>();
+        if self
+            .components
+            .iter()
+            .any(|component| component.name == name)
+        {
+            log::warn!("{} is already replicated", name);
+            return false;
+        }
+        assert!(
+            self.components.len() < ComponentKind::MAX as usize,
+            "too many replicated components"
+        );
+        self.components.push(ReplicatedComponent {
+            name,
+            serialize: serialize_component::<C>,
+            apply: apply_component::<C>,
+            remove: remove_component::<C>,
+        });
+        true
+    }
+
+    /// Names of registered component types, in registration order.
+    pub fn names(&self) -> impl Iterator<Item = &'static str> + '_ {
+        self.components.iter().map(|component| component.name)
+    }
+}
+
+fn serialize_component<C: Component + Serialize>(world: &World, entity: Entity) -> Option<Vec<u8>> {
+    let component = world.get::<C>(entity)?;
+    match bincode::serialize(component) {
+        Ok(bytes) => Some(bytes),
+        Err(error) => {
+            log::error!("Cannot serialize {}: {}", std::any::type_name::<C>(), error);
+            None
+        }
+    }
+}
+
+fn apply_component<C: Component + DeserializeOwned>(
+    world: &mut World,
+    entity: Entity,
+    bytes: &[u8],
+) -> Result<(), bincode::Error> {
+    let component: C = bincode::deserialize(bytes)?;
+    if let Some(mut entity) = world.get_entity_mut(entity) {
+        entity.insert(component);
+    }
+    Ok(())
+}
+
+fn remove_component<C: Component>(world: &mut World, entity: Entity) {
+    if let Some(mut entity) = world.get_entity_mut(entity) {
+        entity.remove::<C>();
+    }
+}
+
+pub trait AppReplicationExt {
+    /// Replicates component `C` of entities marked with `Replicate`.
+    fn replicate<C: Component + Serialize + DeserializeOwned>(&mut self) -> &mut Self;
+}
+
+impl AppReplicationExt for AppBuilder {
+    fn replicate<C: Component + Serialize + DeserializeOwned>(&mut self) -> &mut Self {
+        let registered = self
+            .world_mut()
+            .get_resource_or_insert_with(ReplicationRegistry::default)
+            .register::<C>();
+        if registered {
+            self.init_resource::<ReplicationChanges>()
+                .add_system_to_stage(CoreStage::PostUpdate, track_changes::<C>.system());
+        }
+        self
+    }
+}
+
+/// Server entities to look at in the next `replication_server_system` run.
+#[derive(Default)]
+struct ReplicationChanges {
+    entities: HashSet<Entity>,
+}
+
+fn track_changes<C: Component>(
+    mut changes: ResMut<ReplicationChanges>,
+    changed: Query<Entity, (With<Replicate>, Changed<C>)>,
+    removed: RemovedComponents<C>,
+) {
+    changes.entities.extend(changed.iter());
+    changes.entities.extend(removed.iter());
+}
+
+// new replicated entities, and despawned ones
+fn track_replicate(
+    mut changes: ResMut<ReplicationChanges>,
+    added: Query<Entity, Added<Replicate>>,
+    removed: RemovedComponents<Replicate>,
+) {
+    changes.entities.extend(added.iter());
+    changes.entities.extend(removed.iter());
+}
+
+/// Client side mapping of server entities to local ones.
+#[derive(Debug, Default)]
+pub struct ReplicatedEntities {
+    entities: HashMap<(ConnectionHandle, u64), Entity>,
+}
+
+impl ReplicatedEntities {
+    pub fn client_entity(&self, handle: ConnectionHandle, server_entity: Entity) -> Option<Entity> {
+        self.entities
+            .get(&(handle, server_entity.to_bits()))
+            .copied()
+    }
+}
+
+/// Server side view of what a client has, kept as connection user data.
+#[derive(Default)]
+struct ReplicationPeer {
+    // components accepted by the channel
+    sent: HashMap<u64, HashMap<ComponentKind, Vec<u8>>>,
+    // not sent for a full channel, diffed again next time
+    pending: HashSet<u64>,
+}
+
+/// Serialized components of server entities, in registration order.
+/// Entities looked at but missing are gone for clients.
+type WorldState = HashMap<u64, Vec<Option<Vec<u8>>>>;
+
+impl ReplicationPeer {
+    fn diff<'a>(
+        &self,
+        state: &WorldState,
+        entities: impl Iterator<Item = &'a u64>,
+    ) -> Vec<ReplicationMessage> {
+        let mut updates = Vec::new();
+        let mut despawned = Vec::new();
+        for entity in entities {
+            let sent = self.sent.get(entity);
+            let components = match state.get(entity) {
+                Some(components) => components,
+                None => {
+                    if sent.is_some() {
+                        despawned.push(*entity);
+                    }
+                    continue;
+                }
+            };
+            let mut update = EntityUpdate {
+                entity: *entity,
+                changed: Vec::new(),
+                removed: Vec::new(),
+            };
+            for (kind, component) in components.iter().enumerate() {
+                let kind = kind as ComponentKind;
+                let previous = sent.and_then(|components| components.get(&kind));
+                match (component, previous) {
+                    (Some(bytes), Some(previous)) if bytes == previous => {}
+                    (Some(bytes), _) => update.changed.push((kind, bytes.clone())),
+                    (None, Some(_)) => update.removed.push(kind),
+                    (None, None) => {}
+                }
+            }
+            if sent.is_some() && update.changed.is_empty() && update.removed.is_empty() {
+                continue;
+            }
+            updates.push(update);
+        }
+        pack(updates, despawned)
+    }
+
+    fn mark_sent(&mut self, message: &ReplicationMessage) {
+        for update in message.updates.iter() {
+            let components = self.sent.entry(update.entity).or_default();
+            for (kind, bytes) in update.changed.iter() {
+                components.insert(*kind, bytes.clone());
+            }
+            for kind in update.removed.iter() {
+                components.remove(kind);
+            }
+        }
+        for entity in message.despawned.iter() {
+            self.sent.remove(entity);
+        }
+    }
+}
+
+/// Fills messages up to `MESSAGE_BUDGET`, splitting entity updates that do not fit in one.
+fn pack(updates: Vec<EntityUpdate>, despawned: Vec<u64>) -> Vec<ReplicationMessage> {
+    let mut messages = vec![ReplicationMessage::default()];
+    let mut message_len = 0;
+    let mut push = |update: EntityUpdate, messages: &mut Vec<ReplicationMessage>| {
+        let update_len = update.len();
+        if message_len + update_len > MESSAGE_BUDGET && message_len > 0 {
+            messages.push(ReplicationMessage::default());
+            message_len = 0;
+        }
+        message_len += update_len;
+        messages.last_mut().unwrap().updates.push(update);
+    };
+
+    for update in updates {
+        if update.len() <= MESSAGE_BUDGET {
+            push(update, &mut messages);
+            continue;
+        }
+        let entity = update.entity;
+        let mut part = EntityUpdate {
+            entity,
+            changed: Vec::new(),
+            removed: update.removed,
+        };
+        for (kind, bytes) in update.changed {
+            if UPDATE_LEN + CHANGED_LEN + bytes.len() > MESSAGE_BUDGET {
+                log::error!(
+                    "Replicated component {} of {:?} is too large: {} bytes",
+                    kind,
+                    Entity::from_bits(entity),
+                    bytes.len()
+                );
+                continue;
+            }
+            if part.len() + CHANGED_LEN + bytes.len() > MESSAGE_BUDGET {
+                let full = std::mem::replace(
+                    &mut part,
+                    EntityUpdate {
+                        entity,
+                        changed: Vec::new(),
+                        removed: Vec::new(),
+                    },
+                );
+                push(full, &mut messages);
+            }
+            part.changed.push((kind, bytes));
+        }
+        if !part.changed.is_empty() || !part.removed.is_empty() {
+            push(part, &mut messages);
+        }
+    }
+    for entity in despawned {
+        if message_len + DESPAWNED_LEN > MESSAGE_BUDGET {
+            messages.push(ReplicationMessage::default());
+            message_len = 0;
+        }
+        message_len += DESPAWNED_LEN;
+        messages.last_mut().unwrap().despawned.push(entity);
+    }
+
+    messages.retain(|message| !message.updates.is_empty() || !message.despawned.is_empty());
+    messages
+}
+
+fn serialize_entities(
+    world: &World,
+    serializers: &[SerializeFn],
+    entities: impl Iterator<Item = u64>,
+) -> WorldState {
+    entities
+        .filter_map(|bits| {
+            let entity = Entity::from_bits(bits);
+            world.get::<Replicate>(entity)?;
+            let components = serializers
+                .iter()
+                .map(|serialize| serialize(world, entity))
+                .collect();
+            Some((bits, components))
+        })
+        .collect()
+}
+
+pub fn replication_server_system(world: &mut World) {
+    let serializers: Vec<SerializeFn> = match world.get_resource::<ReplicationRegistry>() {
+        Some(registry) => registry
+            .components
+            .iter()
+            .map(|component| component.serialize)
+            .collect(),
+        None => return,
+    };
+    let changed: HashSet<u64> = match world.get_resource_mut::<ReplicationChanges>() {
+        Some(mut changes) => changes.entities.drain().map(Entity::to_bits).collect(),
+        None => HashSet::new(),
+    };
+
+    let mut net = world
+        .get_resource_mut::<NetworkResource>()
+        .expect("NetworkResource not found");
+    let handles: Vec<ConnectionHandle> = net
+        .connections
+        .iter()
+        .filter(|(_, connection)| connection.info().role == ConnectionRole::Server)
+        .map(|(handle, _)| *handle)
+        .collect();
+    // `None` for new clients, getting everything
+    let peers: Vec<(ConnectionHandle, Option<ReplicationPeer>)> = handles
+        .into_iter()
+        .map(|handle| (handle, net.remove_user_data::<ReplicationPeer>(handle)))
+        .collect();
+
+    let everything = peers.iter().any(|(_, peer)| peer.is_none());
+    let state = if everything {
+        let mut query = world.query_filtered::<Entity, With<Replicate>>();
+        let entities: Vec<u64> = query.iter(world).map(Entity::to_bits).collect();
+        serialize_entities(world, &serializers, entities.into_iter())
+    } else {
+        let pending = peers
+            .iter()
+            .filter_map(|(_, peer)| peer.as_ref())
+            .flat_map(|peer| peer.pending.iter().copied());
+        let entities: HashSet<u64> = changed.iter().copied().chain(pending).collect();
+        serialize_entities(world, &serializers, entities.into_iter())
+    };
+
+    let mut net = world
+        .get_resource_mut::<NetworkResource>()
+        .expect("NetworkResource not found");
+    for (handle, peer) in peers {
+        let messages = match peer.as_ref() {
+            Some(peer) => peer.diff(
+                &state,
+                changed.iter().chain(peer.pending.difference(&changed)),
+            ),
+            None => ReplicationPeer::default().diff(&state, state.keys()),
+        };
+        let mut peer = peer.unwrap_or_default();
+        peer.pending.clear();
+        if let Some(channels) = net
+            .connections
+            .get_mut(&handle)
+            .and_then(|connection| connection.channels())
+        {
+            let mut messages = messages.into_iter();
+            for message in &mut messages {
+                match channels.send(message.clone()) {
+                    None => peer.mark_sent(&message),
+                    Some(_) => {
+                        // channel is full, the rest goes with the next diff
+                        log::debug!("Replication channel of [{}] is full", handle);
+                        peer.pending.extend(unsent_entities(&message));
+                        break;
+                    }
+                }
+            }
+            for message in messages {
+                peer.pending.extend(unsent_entities(&message));
+            }
+            channels.flush::<ReplicationMessage>();
+        }
+        let _ = net.set_user_data(handle, peer);
+    }
+}
+
+fn unsent_entities(message: &ReplicationMessage) -> impl Iterator<Item = u64> + '_ {
+    message
+        .updates
+        .iter()
+        .map(|update| update.entity)
+        .chain(message.despawned.iter().copied())
+}
+
+pub fn replication_client_system(world: &mut World) {
+    let mut received = Vec::new();
+    let mut live_handles = HashSet::new();
+    {
+        let mut net = world
+            .get_resource_mut::<NetworkResource>()
+            .expect("NetworkResource not found");
+        for (handle, connection) in net.connections.iter_mut() {
+            if connection.info().role != ConnectionRole::Client {
+                continue;
+            }
+            live_handles.insert(*handle);
+            if let Some(channels) = connection.channels() {
+                while let Some(message) = channels.recv::<ReplicationMessage>() {
+                    received.push((*handle, message));
+                }
+            }
+        }
+    }
+
+    let appliers: Vec<(&'static str, ApplyFn, RemoveFn)> =
+        match world.get_resource::<ReplicationRegistry>() {
+            Some(registry) => registry
+                .components
+                .iter()
+                .map(|component| (component.name, component.apply, component.remove))
+                .collect(),
+            None => Vec::new(),
+        };
+    let mut entities = world
+        .remove_resource::<ReplicatedEntities>()
+        .unwrap_or_default();
+
+    // the server is gone, so are its entities
+    entities.entities.retain(|(handle, _), entity| {
+        if live_handles.contains(handle) {
+            true
+        } else {
+            world.despawn(*entity);
+            false
+        }
+    });
+
+    for (handle, message) in received {
+        for update in message.updates {
+            let entity = *entities
+                .entities
+                .entry((handle, update.entity))
+                .or_insert_with(|| {
+                    world
+                        .spawn()
+                        .insert(Replicated {
+                            handle,
+                            server_entity: Entity::from_bits(update.entity),
+                        })
+                        .id()
+                });
+            for (kind, bytes) in update.changed {
+                match appliers.get(kind as usize) {
+                    Some((name, apply, _)) => {
+                        if let Err(error) = apply(world, entity, &bytes) {
+                            log::error!("Cannot apply replicated {}: {}", name, error);
+                        }
+                    }
+                    None => log::error!("Unknown replicated component kind {}", kind),
+                }
+            }
+            for kind in update.removed {
+                if let Some((_, _, remove)) = appliers.get(kind as usize) {
+                    remove(world, entity);
+                }
+            }
+        }
+        for server_entity in message.despawned {
+            if let Some(entity) = entities.entities.remove(&(handle, server_entity)) {
+                world.despawn(entity);
+            }
+        }
+    }
+
+    world.insert_resource(entities);
+}
+
+/// Replicates entities marked with `Replicate` from server to clients.
+/// Requires `NetworkingPlugin`, component types are registered with `AppReplicationExt::replicate`.
+pub struct ReplicationPlugin;
+
+impl Plugin for ReplicationPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.world_mut()
+            .get_resource_mut::<NetworkResource>()
+            .expect("`NetworkingPlugin` has to be added before `ReplicationPlugin`")
+            .add_channels_builder(|builder: &mut ConnectionChannelsBuilder| {
+                builder
+                    .register::<ReplicationMessage>(REPLICATION_MESSAGE_SETTINGS)
+                    .unwrap();
+            });
+
+        app.init_resource::<ReplicationRegistry>()
+            .init_resource::<ReplicatedEntities>()
+            .init_resource::<ReplicationChanges>()
+            .add_system_to_stage(
+                CoreStage::PreUpdate,
+                replication_client_system.exclusive_system(),
+            )
+            .add_system_to_stage(CoreStage::PostUpdate, track_replicate.system())
+            // after the change tracking systems of the stage
+            .add_system_to_stage(
+                CoreStage::PostUpdate,
+                replication_server_system.exclusive_system().at_end(),
+            );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn diff(
+        peer: &ReplicationPeer,
+        state: &WorldState,
+        entities: &[u64],
+    ) -> Vec<ReplicationMessage> {
+        peer.diff(state, entities.iter())
+    }
+
+    fn update(entity: u64, changed: Vec<(ComponentKind, Vec<u8>)>) -> EntityUpdate {
+        EntityUpdate {
+            entity,
+            changed,
+            removed: Vec::new(),
+        }
+    }
+
+    fn assert_fits(messages: &[ReplicationMessage]) {
+        for message in messages {
+            let len = bincode::serialize(message).unwrap().len();
+            assert!(len <= MAX_MESSAGE_LEN, "{} bytes message", len);
+        }
+    }
+
+    #[test]
+    fn diff_sends_new_entities_whole() {
+        let peer = ReplicationPeer::default();
+        let state = vec![(1, vec![Some(vec![1]), None, Some(vec![3])])]
+            .into_iter()
+            .collect();
+        let messages = diff(&peer, &state, &[1]);
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].updates.len(), 1);
+        assert_eq!(
+            messages[0].updates[0].changed,
+            vec![(0, vec![1]), (2, vec![3])]
+        );
+        assert!(messages[0].updates[0].removed.is_empty());
+        assert!(messages[0].despawned.is_empty());
+    }
+
+    #[test]
+    fn diff_skips_what_was_sent() {
+        let mut peer = ReplicationPeer::default();
+        let state = vec![(1, vec![Some(vec![1])])].into_iter().collect();
+        for message in diff(&peer, &state, &[1]) {
+            peer.mark_sent(&message);
+        }
+        assert!(diff(&peer, &state, &[1]).is_empty());
+    }
+
+    #[test]
+    fn diff_sends_changed_and_removed_components() {
+        let mut peer = ReplicationPeer::default();
+        let state = vec![(1, vec![Some(vec![1]), Some(vec![2]), Some(vec![3])])]
+            .into_iter()
+            .collect();
+        for message in diff(&peer, &state, &[1]) {
+            peer.mark_sent(&message);
+        }
+
+        let state = vec![(1, vec![Some(vec![1]), Some(vec![4]), None])]
+            .into_iter()
+            .collect();
+        let messages = diff(&peer, &state, &[1]);
+        assert_eq!(messages.len(), 1);
+        let update = &messages[0].updates[0];
+        assert_eq!(update.changed, vec![(1, vec![4])]);
+        assert_eq!(update.removed, vec![2]);
+    }
+
+    #[test]
+    fn diff_despawns_entities_gone_from_the_state() {
+        let mut peer = ReplicationPeer::default();
+        let state = vec![(1, vec![Some(vec![1])])].into_iter().collect();
+        for message in diff(&peer, &state, &[1]) {
+            peer.mark_sent(&message);
+        }
+
+        // 2 was never sent, so there is nothing to despawn
+        let messages = diff(&peer, &WorldState::new(), &[1, 2]);
+        assert_eq!(messages.len(), 1);
+        assert!(messages[0].updates.is_empty());
+        assert_eq!(messages[0].despawned, vec![1]);
+
+        peer.mark_sent(&messages[0]);
+        assert!(peer.sent.is_empty());
+        assert!(diff(&peer, &WorldState::new(), &[1]).is_empty());
+    }
+
+    #[test]
+    fn pack_fills_messages_up_to_the_limit() {
+        let updates = (0..200).map(|entity| update(entity, vec![(0, vec![7; 100])]));
+        let messages = pack(updates.collect(), vec![1000, 1001]);
+        assert!(messages.len() > 1);
+        assert_fits(&messages);
+
+        let entities: Vec<u64> = messages
+            .iter()
+            .flat_map(|message| message.updates.iter().map(|update| update.entity))
+            .collect();
+        assert_eq!(entities, (0..200).collect::<Vec<_>>());
+        assert_eq!(messages.last().unwrap().despawned, vec![1000, 1001]);
+    }
+
+    #[test]
+    fn pack_splits_entity_updates_over_the_limit() {
+        let changed = (0..5).map(|kind| (kind, vec![kind as u8; 3000])).collect();
+        let messages = pack(vec![update(1, changed)], Vec::new());
+        assert_eq!(messages.len(), 3);
+        assert_fits(&messages);
+
+        let kinds: Vec<ComponentKind> = messages
+            .iter()
+            .flat_map(|message| message.updates.iter())
+            .inspect(|update| assert_eq!(update.entity, 1))
+            .flat_map(|update| update.changed.iter().map(|(kind, _)| *kind))
+            .collect();
+        assert_eq!(kinds, vec![0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn pack_drops_components_larger_than_a_message() {
+        let changed = vec![(0, vec![0; MAX_MESSAGE_LEN]), (1, vec![1; 10])];
+        let messages = pack(vec![update(1, changed)], Vec::new());
+        assert_eq!(messages.len(), 1);
+        assert_fits(&messages);
+        assert_eq!(messages[0].updates[0].changed, vec![(1, vec![1; 10])]);
+    }
+
+    #[test]
+    fn pack_splits_despawned_entities_over_the_limit() {
+        let despawned: Vec<u64> = (0..2000).collect();
+        let messages = pack(vec![update(1, vec![(0, vec![0; 100])])], despawned.clone());
+        assert_eq!(messages.len(), 3);
+        assert_fits(&messages);
+        let all: Vec<u64> = messages
+            .iter()
+            .flat_map(|message| message.despawned.iter().copied())
+            .collect();
+        assert_eq!(all, despawned);
+    }
+
+    #[test]
+    fn pack_skips_empty_messages() {
+        assert!(pack(Vec::new(), Vec::new()).is_empty());
+    }
+}
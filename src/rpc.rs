@@ -0,0 +1,539 @@
+//! Remote procedure calls over a reliable message channel.
+//!
+//! A request type declares its response type by implementing `RpcRequest`, and is registered on
+//! both peers with `AppRpcExt::add_rpc`. `Rpc::call` sends the request and returns a
+//! `PendingCall`, resolved when the response arrives, the call times out, or the connection is
+//! gone. The receiving side takes requests with `Rpc::take_calls` and answers them with
+//! `Rpc::respond`. Requests not taken yet are queued up to `RpcPlugin::max_queued_calls` per
+//! connection and method, the ones over that are answered with `RpcError::QueueFull`.
+//!
+//! Methods are identified by a hash of `RpcRequest::METHOD`, so both peers have to declare
+//! request types under the same names.
+
+use bevy_app::{AppBuilder, CoreStage, Plugin};
+use bevy_ecs::prelude::*;
+use futures::channel::oneshot;
+use instant::{Duration, Instant};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    error::Error,
+    fmt,
+    future::Future,
+    marker::PhantomData,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use super::{
+    hash::fnv1a, ConnectionChannelsBuilder, ConnectionHandle, MessageChannelMode,
+    MessageChannelSettings, NetworkResource, ReliableChannelSettings,
+};
+
+pub const RPC_CHANNEL: u8 = 244;
+
+const RPC_MESSAGE_SETTINGS: MessageChannelSettings = MessageChannelSettings {
+    channel: RPC_CHANNEL,
+    channel_mode: MessageChannelMode::Reliable {
+        reliability_settings: ReliableChannelSettings {
+            bandwidth: 65536,
+            recv_window_size: 65536,
+            send_window_size: 65536,
+            burst_bandwidth: 16384,
+            init_send: 4096,
+            wakeup_time: Duration::from_millis(100),
+            initial_rtt: Duration::from_millis(200),
+            max_rtt: Duration::from_secs(2),
+            rtt_update_factor: 0.1,
+            rtt_resend_factor: 1.5,
+        },
+        max_message_len: 16384,
+    },
+    message_buffer_size: 64,
+    packet_buffer_size: 64,
+};
+
+/// Request of a remote procedure, answered with `Response`.
+pub trait RpcRequest: Serialize + DeserializeOwned + Send + Sync + 'static {
+    type Response: Serialize + DeserializeOwned + Send + Sync + 'static;
+    /// Name of the method, unique among requests and the same on both peers, i.e. `"chat/send"`.
+    const METHOD: &'static str;
+}
+
+fn method_id<R: RpcRequest>() -> u64 {
+    fnv1a(R::METHOD.as_bytes())
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum RpcError {
+    /// No response within `RpcPlugin::timeout`.
+    Timeout,
+    /// The connection is gone, or was never there.
+    Disconnected,
+    /// The RPC channel send buffer is full, the request was not sent.
+    ChannelFull,
+    /// The remote peer did not register the request type.
+    UnknownMethod,
+    /// The remote peer has too many requests of this type waiting to be handled.
+    QueueFull,
+    /// Request or response could not be (de)serialized.
+    Encoding(String),
+    /// Error returned by the remote handler.
+    Remote(String),
+}
+
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RpcError::Timeout => write!(f, "RPC timed out"),
+            RpcError::Disconnected => write!(f, "RPC connection is gone"),
+            RpcError::ChannelFull => write!(f, "RPC channel is full"),
+            RpcError::UnknownMethod => write!(f, "RPC method is unknown to the remote peer"),
+            RpcError::QueueFull => write!(f, "RPC queue of the remote peer is full"),
+            RpcError::Encoding(error) => write!(f, "RPC encoding error: {}", error),
+            RpcError::Remote(error) => write!(f, "RPC failed remotely: {}", error),
+        }
+    }
+}
+
+impl Error for RpcError {}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum RpcBody {
+    Request(Vec<u8>),
+    Response(Vec<u8>),
+    Error(RpcError),
+}
+
+/// Envelope of all requests and responses.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RpcMessage {
+    /// Correlates the response with the request, unique per caller.
+    pub id: u32,
+    pub method: u64,
+    pub body: RpcBody,
+}
+
+type CallResult = Result<Vec<u8>, RpcError>;
+// ids and bodies of requests of a single method from a single connection
+type QueuedCalls = VecDeque<(u32, Vec<u8>)>;
+
+struct OutgoingCall {
+    deadline: Instant,
+    sender: oneshot::Sender<CallResult>,
+}
+
+/// Calls in flight and requests waiting for `take_calls`, inserted by `RpcPlugin`.
+pub struct Rpc {
+    next_id: u32,
+    timeout: Duration,
+    max_queued_calls: usize,
+    methods: HashSet<u64>,
+    outgoing: HashMap<(ConnectionHandle, u32), OutgoingCall>,
+    incoming: HashMap<u64, HashMap<ConnectionHandle, QueuedCalls>>,
+}
+
+impl Default for Rpc {
+    fn default() -> Self {
+        Rpc {
+            next_id: 0,
+            timeout: Duration::from_secs(10),
+            max_queued_calls: 64,
+            methods: HashSet::new(),
+            outgoing: HashMap::new(),
+            incoming: HashMap::new(),
+        }
+    }
+}
+
+/// Response of a call in flight.
+/// Poll it with `try_recv` from a system, or `.await` it.
+pub struct PendingCall<R> {
+    receiver: oneshot::Receiver<CallResult>,
+    marker: PhantomData<fn() -> R>,
+}
+
+fn decode_response<R: DeserializeOwned>(
+    result: Result<CallResult, oneshot::Canceled>,
+) -> Result<R, RpcError> {
+    match result {
+        Ok(Ok(bytes)) => {
+            bincode::deserialize(&bytes).map_err(|error| RpcError::Encoding(error.to_string()))
+        }
+        Ok(Err(error)) => Err(error),
+        // dropped together with the connection
+        Err(oneshot::Canceled) => Err(RpcError::Disconnected),
+    }
+}
+
+impl<R: DeserializeOwned> PendingCall<R> {
+    /// Returns the result once it is there. Calling it again afterwards gives `Disconnected`.
+    pub fn try_recv(&mut self) -> Option<Result<R, RpcError>> {
+        match self.receiver.try_recv() {
+            Ok(Some(result)) => Some(decode_response(Ok(result))),
+            Ok(None) => None,
+            Err(canceled) => Some(decode_response(Err(canceled))),
+        }
+    }
+}
+
+impl<R: DeserializeOwned> Future for PendingCall<R> {
+    type Output = Result<R, RpcError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.receiver).poll(cx).map(decode_response)
+    }
+}
+
+/// Request received from `handle`, to be answered with `Rpc::respond`.
+#[derive(Debug)]
+pub struct IncomingCall<R> {
+    pub handle: ConnectionHandle,
+    pub request: R,
+    id: u32,
+}
+
+impl Rpc {
+    /// Sends `request` to `handle`.
+    pub fn call<R: RpcRequest>(
+        &mut self,
+        net: &mut NetworkResource,
+        handle: ConnectionHandle,
+        request: R,
+    ) -> Result<PendingCall<R::Response>, RpcError> {
+        let body =
+            bincode::serialize(&request).map_err(|error| RpcError::Encoding(error.to_string()))?;
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        send_rpc(
+            net,
+            handle,
+            RpcMessage {
+                id,
+                method: method_id::<R>(),
+                body: RpcBody::Request(body),
+            },
+        )?;
+        Ok(self.pending_call(handle, id))
+    }
+
+    /// Takes the received requests of type `R`.
+    /// Requests that fail to deserialize are answered with an error right away.
+    pub fn take_calls<R: RpcRequest>(&mut self, net: &mut NetworkResource) -> Vec<IncomingCall<R>> {
+        let method = method_id::<R>();
+        let queued = match self.incoming.get_mut(&method) {
+            Some(queued) => std::mem::take(queued),
+            None => return Vec::new(),
+        };
+
+        let mut calls = Vec::new();
+        for (handle, queued) in queued {
+            for (id, body) in queued {
+                match bincode::deserialize(&body) {
+                    Ok(request) => calls.push(IncomingCall {
+                        handle,
+                        request,
+                        id,
+                    }),
+                    Err(error) => {
+                        let error = RpcError::Encoding(error.to_string());
+                        send_rpc_response(net, handle, id, method, RpcBody::Error(error));
+                    }
+                }
+            }
+        }
+        calls
+    }
+
+    /// Answers `call`, with `Err` reaching the caller as `RpcError::Remote`.
+    pub fn respond<R: RpcRequest>(
+        &self,
+        net: &mut NetworkResource,
+        call: &IncomingCall<R>,
+        response: Result<R::Response, String>,
+    ) {
+        let body = match response {
+            Ok(response) => match bincode::serialize(&response) {
+                Ok(bytes) => RpcBody::Response(bytes),
+                Err(error) => RpcBody::Error(RpcError::Encoding(error.to_string())),
+            },
+            Err(error) => RpcBody::Error(RpcError::Remote(error)),
+        };
+        send_rpc_response(net, call.handle, call.id, method_id::<R>(), body);
+    }
+
+    fn pending_call<R>(&mut self, handle: ConnectionHandle, id: u32) -> PendingCall<R> {
+        let (sender, receiver) = oneshot::channel();
+        self.outgoing.insert(
+            (handle, id),
+            OutgoingCall {
+                deadline: Instant::now() + self.timeout,
+                sender,
+            },
+        );
+        PendingCall {
+            receiver,
+            marker: PhantomData,
+        }
+    }
+
+    /// Queues requests and resolves calls, returns the error to answer `message` with.
+    fn receive(&mut self, handle: ConnectionHandle, message: RpcMessage) -> Option<RpcBody> {
+        match message.body {
+            RpcBody::Request(body) => {
+                if !self.methods.contains(&message.method) {
+                    log::debug!("Unknown RPC method {:x} from [{}]", message.method, handle);
+                    return Some(RpcBody::Error(RpcError::UnknownMethod));
+                }
+                let queued = self
+                    .incoming
+                    .entry(message.method)
+                    .or_default()
+                    .entry(handle)
+                    .or_default();
+                if queued.len() >= self.max_queued_calls {
+                    log::debug!("RPC queue of [{}] is full", handle);
+                    return Some(RpcBody::Error(RpcError::QueueFull));
+                }
+                queued.push_back((message.id, body));
+            }
+            RpcBody::Response(body) => self.resolve(handle, message.id, Ok(body)),
+            RpcBody::Error(error) => self.resolve(handle, message.id, Err(error)),
+        }
+        None
+    }
+
+    fn resolve(&mut self, handle: ConnectionHandle, id: u32, result: CallResult) {
+        if let Some(call) = self.outgoing.remove(&(handle, id)) {
+            let _ = call.sender.send(result);
+        }
+    }
+
+    /// Times out calls past their deadline and drops state of connections that are gone.
+    fn expire(&mut self, now: Instant, is_connected: impl Fn(&ConnectionHandle) -> bool) {
+        // dropping the sender resolves the call with `Disconnected`
+        self.outgoing.retain(|(handle, _), _| is_connected(handle));
+        let timed_out: Vec<(ConnectionHandle, u32)> = self
+            .outgoing
+            .iter()
+            .filter(|(_, call)| call.deadline <= now)
+            .map(|(key, _)| *key)
+            .collect();
+        for (handle, id) in timed_out {
+            self.resolve(handle, id, Err(RpcError::Timeout));
+        }
+        for queued in self.incoming.values_mut() {
+            queued.retain(|handle, _| is_connected(handle));
+        }
+    }
+}
+
+fn send_rpc_response(
+    net: &mut NetworkResource,
+    handle: ConnectionHandle,
+    id: u32,
+    method: u64,
+    body: RpcBody,
+) {
+    if let Err(error) = send_rpc(net, handle, RpcMessage { id, method, body }) {
+        log::debug!("Failed to answer RPC {} of [{}]: {}", id, handle, error);
+    }
+}
+
+fn send_rpc(
+    net: &mut NetworkResource,
+    handle: ConnectionHandle,
+    message: RpcMessage,
+) -> Result<(), RpcError> {
+    match net.send_message(handle, message) {
+        Ok(None) => Ok(()),
+        Ok(Some(_)) => Err(RpcError::ChannelFull),
+        Err(_) => Err(RpcError::Disconnected),
+    }
+}
+
+pub fn rpc_system(mut net: ResMut<NetworkResource>, mut rpc: ResMut<Rpc>) {
+    let mut replies = Vec::new();
+
+    for (handle, connection) in net.connections.iter_mut() {
+        let channels = match connection.channels() {
+            Some(channels) => channels,
+            None => continue,
+        };
+        // sent since the last run, with `MessageFlushingStrategy::Never`
+        channels.flush::<RpcMessage>();
+        while let Some(message) = channels.recv::<RpcMessage>() {
+            let (id, method) = (message.id, message.method);
+            if let Some(body) = rpc.receive(*handle, message) {
+                replies.push((*handle, id, method, body));
+            }
+        }
+    }
+
+    for (handle, id, method, body) in replies {
+        send_rpc_response(&mut net, handle, id, method, body);
+    }
+
+    let connections = &net.connections;
+    rpc.expire(Instant::now(), |handle| connections.contains_key(handle));
+}
+
+pub trait AppRpcExt {
+    /// Accepts requests of type `R`, and calls to it.
+    fn add_rpc<R: RpcRequest>(&mut self) -> &mut Self;
+}
+
+impl AppRpcExt for AppBuilder {
+    fn add_rpc<R: RpcRequest>(&mut self) -> &mut Self {
+        self.world_mut()
+            .get_resource_or_insert_with(Rpc::default)
+            .methods
+            .insert(method_id::<R>());
+        self
+    }
+}
+
+/// Delivers RPC requests and responses.
+pub struct RpcPlugin {
+    /// Time to wait for a response before resolving the call with `RpcError::Timeout`.
+    pub timeout: Duration,
+    /// Requests waiting for `Rpc::take_calls`, per connection and method.
+    pub max_queued_calls: usize,
+}
+
+impl Default for RpcPlugin {
+    fn default() -> Self {
+        RpcPlugin {
+            timeout: Duration::from_secs(10),
+            max_queued_calls: 64,
+        }
+    }
+}
+
+impl Plugin for RpcPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.world_mut()
+            .get_resource_mut::<NetworkResource>()
+            .expect("`NetworkingPlugin` has to be added before `RpcPlugin`")
+            .add_channels_builder(|builder: &mut ConnectionChannelsBuilder| {
+                builder
+                    .register::<RpcMessage>(RPC_MESSAGE_SETTINGS)
+                    .unwrap();
+            });
+        // `add_rpc` may have inserted it already
+        let mut rpc = app.world_mut().get_resource_or_insert_with(Rpc::default);
+        rpc.timeout = self.timeout;
+        rpc.max_queued_calls = self.max_queued_calls;
+
+        app.add_system_to_stage(CoreStage::PreUpdate, rpc_system.system());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Serialize, Deserialize)]
+    struct Echo(u32);
+
+    impl RpcRequest for Echo {
+        type Response = u32;
+        const METHOD: &'static str = "test/echo";
+    }
+
+    fn response(id: u32, response: u32) -> RpcMessage {
+        RpcMessage {
+            id,
+            method: method_id::<Echo>(),
+            body: RpcBody::Response(bincode::serialize(&response).unwrap()),
+        }
+    }
+
+    fn request(id: u32) -> RpcMessage {
+        RpcMessage {
+            id,
+            method: method_id::<Echo>(),
+            body: RpcBody::Request(bincode::serialize(&Echo(id)).unwrap()),
+        }
+    }
+
+    #[test]
+    fn responses_resolve_calls_by_connection_and_id() {
+        let mut rpc = Rpc::default();
+        let mut first = rpc.pending_call::<u32>(0, 1);
+        let mut second = rpc.pending_call::<u32>(0, 2);
+        let mut other = rpc.pending_call::<u32>(1, 1);
+
+        assert!(rpc.receive(0, response(2, 20)).is_none());
+        assert!(first.try_recv().is_none());
+        assert_eq!(second.try_recv(), Some(Ok(20)));
+
+        // same id from another connection, and an id nobody waits for
+        rpc.receive(1, response(1, 11));
+        rpc.receive(0, response(3, 30));
+        assert!(first.try_recv().is_none());
+        assert_eq!(other.try_recv(), Some(Ok(11)));
+
+        let error = RpcMessage {
+            id: 1,
+            method: method_id::<Echo>(),
+            body: RpcBody::Error(RpcError::Remote("nope".to_string())),
+        };
+        rpc.receive(0, error);
+        assert_eq!(
+            first.try_recv(),
+            Some(Err(RpcError::Remote("nope".to_string())))
+        );
+        assert!(rpc.outgoing.is_empty());
+    }
+
+    #[test]
+    fn calls_time_out_or_disconnect() {
+        let mut rpc = Rpc::default();
+        let mut timed_out = rpc.pending_call::<u32>(0, 1);
+        let mut disconnected = rpc.pending_call::<u32>(1, 1);
+
+        rpc.expire(Instant::now(), |_| true);
+        assert!(timed_out.try_recv().is_none());
+
+        rpc.expire(Instant::now() + rpc.timeout, |handle| *handle == 0);
+        assert_eq!(timed_out.try_recv(), Some(Err(RpcError::Timeout)));
+        assert_eq!(disconnected.try_recv(), Some(Err(RpcError::Disconnected)));
+        assert!(rpc.outgoing.is_empty());
+    }
+
+    #[test]
+    fn unknown_methods_are_rejected() {
+        let mut rpc = Rpc::default();
+        assert!(matches!(
+            rpc.receive(0, request(1)),
+            Some(RpcBody::Error(RpcError::UnknownMethod))
+        ));
+        assert!(rpc.incoming.is_empty());
+    }
+
+    #[test]
+    fn queued_calls_are_capped_per_connection() {
+        let mut rpc = Rpc {
+            max_queued_calls: 2,
+            ..Rpc::default()
+        };
+        rpc.methods.insert(method_id::<Echo>());
+
+        assert!(rpc.receive(0, request(1)).is_none());
+        assert!(rpc.receive(0, request(2)).is_none());
+        assert!(matches!(
+            rpc.receive(0, request(3)),
+            Some(RpcBody::Error(RpcError::QueueFull))
+        ));
+        assert!(rpc.receive(1, request(1)).is_none());
+
+        let queued = &rpc.incoming[&method_id::<Echo>()];
+        let ids: Vec<u32> = queued[&0].iter().map(|(id, _)| *id).collect();
+        assert_eq!(ids, vec![1, 2]);
+        assert_eq!(queued[&1].len(), 1);
+
+        rpc.expire(Instant::now(), |handle| *handle == 0);
+        assert!(!rpc.incoming[&method_id::<Echo>()].contains_key(&1));
+    }
+}
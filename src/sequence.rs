@@ -0,0 +1,7 @@
+//! Ordering of wrapping sequence numbers and ticks.
+
+/// Whether `a` is newer than `b`, with `a` counted as wrapped around when it is more than half
+/// the range behind.
+pub(crate) fn sequence_greater_than(a: u32, b: u32) -> bool {
+    a != b && a.wrapping_sub(b) < 1 << 31
+}
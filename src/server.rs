@@ -0,0 +1,526 @@
+use bevy_tasks::TaskPool;
+use crossbeam_channel::{bounded, Sender, TrySendError};
+use hmac::{Hmac, Mac, NewMac};
+use instant::Instant;
+use sha2::Sha256;
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, RwLock,
+    },
+};
+
+use async_net::UdpSocket;
+
+use super::{
+    access::BanList,
+    control::{ControlPacket, Cookie, ListenerSettings, RejectReason, MIN_CONNECT_REQUEST_LEN},
+    token::{unix_time, ConnectToken, ConnectTokenData, ConnectTokenValidator},
+    transport::{ServerConnection, ServerPacketSender},
+    Connection, NetworkError, Packet, PacketStats, Transport,
+};
+
+pub(crate) type ServerChannels = Arc<RwLock<HashMap<SocketAddr, ServerRoute>>>;
+
+/// Receive queue of a `ServerConnection`, fed by the listener.
+pub(crate) struct ServerRoute {
+    packet_tx: Sender<Result<Packet, NetworkError>>,
+    stats: Arc<RwLock<PacketStats>>,
+}
+
+impl ServerRoute {
+    /// Queues the packet, dropping it when the connection does not keep up.
+    fn forward(&self, payload: &[u8]) -> Result<(), TrySendError<Result<Packet, NetworkError>>> {
+        match self
+            .packet_tx
+            .try_send(Ok(Packet::copy_from_slice(payload)))
+        {
+            Err(TrySendError::Full(_)) => {
+                self.stats
+                    .write()
+                    .expect("stats lock poisoned")
+                    .add_dropped();
+                Ok(())
+            }
+            result => result,
+        }
+    }
+}
+
+/// Largest datagram the built-in UDP listener accepts.
+const UDP_RECV_BUFFER_LEN: usize = 65536;
+
+/// Challenge cookies older than this are answered with a fresh challenge.
+const COOKIE_LIFETIME_SECS: u64 = 10;
+
+/// Addresses tracked by `HandshakeRateLimiter`, so spoofed floods cannot grow it forever.
+const MAX_RATE_LIMITED_ADDRESSES: usize = 4096;
+
+/// Issues and checks challenge cookies, HMAC of the client address and issue time.
+pub(crate) struct CookieJar {
+    secret: [u8; 32],
+}
+
+impl CookieJar {
+    pub fn new() -> Self {
+        let mut secret = [0; 32];
+        getrandom::getrandom(&mut secret).expect("cannot generate cookie secret");
+        CookieJar { secret }
+    }
+
+    fn mac(&self, address: SocketAddr, issued_at: u64) -> Hmac<Sha256> {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.secret).expect("HMAC takes any key length");
+        mac.update(address.to_string().as_bytes());
+        mac.update(&issued_at.to_le_bytes());
+        mac
+    }
+
+    fn issue(&self, address: SocketAddr, now: u64) -> Cookie {
+        let mut mac = [0; 32];
+        mac.copy_from_slice(&self.mac(address, now).finalize().into_bytes());
+        Cookie {
+            issued_at: now,
+            mac,
+        }
+    }
+
+    /// `None` for forged cookies, `Some(false)` for expired ones.
+    fn verify(&self, address: SocketAddr, cookie: &Cookie, now: u64) -> Option<bool> {
+        self.mac(address, cookie.issued_at)
+            .verify(&cookie.mac)
+            .ok()?;
+        Some(cookie.issued_at <= now && now - cookie.issued_at <= COOKIE_LIFETIME_SECS)
+    }
+}
+
+/// Counts connections accepted by listeners against `NetworkingPlugin::max_connections`.
+pub(crate) struct ConnectionSlots {
+    max: AtomicUsize,
+    used: AtomicUsize,
+}
+
+impl ConnectionSlots {
+    pub fn new(max: Option<usize>) -> Self {
+        ConnectionSlots {
+            max: AtomicUsize::new(max.unwrap_or(usize::MAX)),
+            used: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn set_max(&self, max: Option<usize>) {
+        self.max.store(max.unwrap_or(usize::MAX), Ordering::Relaxed);
+    }
+
+    /// Takes a slot, unless the server is full.
+    pub fn try_acquire(&self) -> bool {
+        let max = self.max.load(Ordering::Relaxed);
+        self.used
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| {
+                if used < max {
+                    Some(used + 1)
+                } else {
+                    None
+                }
+            })
+            .is_ok()
+    }
+
+    pub fn release(&self) {
+        let _ = self
+            .used
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| {
+                used.checked_sub(1)
+            });
+    }
+
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+}
+
+struct TokenBucket {
+    tokens: f32,
+    updated: Instant,
+}
+
+/// Limits handshake packets per source IP, so the listener is not used to flood a spoofed one.
+pub(crate) struct HandshakeRateLimiter {
+    rate: f32,
+    burst: f32,
+    buckets: Mutex<HashMap<IpAddr, TokenBucket>>,
+}
+
+impl HandshakeRateLimiter {
+    pub fn new(settings: &ListenerSettings) -> Self {
+        HandshakeRateLimiter {
+            rate: settings.handshake_rate,
+            burst: settings.handshake_burst,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn allow(&self, ip: IpAddr, now: Instant) -> bool {
+        let mut buckets = self.buckets.lock().expect("rate limiter lock is poisoned");
+        if !buckets.contains_key(&ip) && buckets.len() >= MAX_RATE_LIMITED_ADDRESSES {
+            // forget addresses which would be back at full burst anyway
+            let (rate, burst) = (self.rate, self.burst);
+            buckets.retain(|_, bucket| {
+                bucket.tokens + now.duration_since(bucket.updated).as_secs_f32() * rate < burst
+            });
+            if buckets.len() >= MAX_RATE_LIMITED_ADDRESSES {
+                return false;
+            }
+        }
+
+        let bucket = buckets.entry(ip).or_insert(TokenBucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        let refill = now.duration_since(bucket.updated).as_secs_f32() * self.rate;
+        bucket.tokens = (bucket.tokens + refill).min(self.burst);
+        bucket.updated = now;
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+}
+
+/// State shared by all datagram listeners of a `NetworkResource`, so connections
+/// accepted on different transports end up in the same `connections` map.
+#[derive(Clone)]
+pub(crate) struct ListenerContext {
+    pub task_pool: TaskPool,
+    pub server_channels: ServerChannels,
+    pub pending_connections: Arc<Mutex<Vec<Box<dyn Connection>>>>,
+    pub connect_tokens: Option<Arc<ConnectTokenValidator>>,
+    pub bans: Arc<BanList>,
+    pub cookies: Arc<CookieJar>,
+    pub slots: Arc<ConnectionSlots>,
+    pub rate_limiter: Arc<HandshakeRateLimiter>,
+    pub settings: ListenerSettings,
+}
+
+impl ListenerContext {
+    /// Checks the connect token of a client at `address`, if the server requires tokens.
+    pub fn validate_token(
+        &self,
+        token: Option<ConnectToken>,
+        address: SocketAddr,
+        now: u64,
+    ) -> Result<Option<ConnectTokenData>, RejectReason> {
+        match (self.connect_tokens.as_ref(), token) {
+            (Some(validator), Some(token)) => match validator.validate(&token, address, now) {
+                Ok(data) => Ok(Some(data)),
+                Err(error) => {
+                    log::debug!("Rejected connection from {}: {}", address, error);
+                    Err(error.into())
+                }
+            },
+            (Some(_), None) => {
+                log::debug!("Rejected connection from {}: no connect token", address);
+                Err(RejectReason::InvalidToken)
+            }
+            // tokens are not required, but clients carrying one are welcome
+            (None, _) => Ok(None),
+        }
+    }
+
+    /// Whether packets from `address` are let in, checking bans and the allowlist.
+    pub fn admits(&self, address: SocketAddr) -> bool {
+        let allowed = match self.settings.allowlist.as_ref() {
+            Some(allowlist) => allowlist.iter().any(|range| range.contains(address.ip())),
+            None => true,
+        };
+        allowed && !self.bans.is_banned(address)
+    }
+
+    /// Hands a datagram over to the connection of its source address.
+    /// Unseen addresses have to pass the cookie challenge first, then get
+    /// a new `ServerConnection`, sending with `sender`.
+    /// Returns a control packet to answer with, if any.
+    pub fn route_packet<F>(
+        &self,
+        transport: Transport,
+        local_address: Option<SocketAddr>,
+        address: SocketAddr,
+        payload: &[u8],
+        sender: F,
+    ) -> Option<Vec<u8>>
+    where
+        F: FnOnce() -> ServerPacketSender,
+    {
+        let message = String::from_utf8_lossy(payload);
+        log::debug!("Server recv <- {}:{}: {}", address, payload.len(), message);
+
+        if !self.admits(address) {
+            log::debug!("Dropped packet from banned or not allowed {}", address);
+            return None;
+        }
+
+        if ControlPacket::is_control(payload) {
+            return self.route_control(transport, local_address, address, payload, sender);
+        }
+
+        let needs_new_channel = match self
+            .server_channels
+            .read()
+            .expect("server channels lock is poisoned")
+            .get(&address)
+            .map(|route| route.forward(payload))
+        {
+            Some(Ok(())) => false,
+            Some(Err(error)) => {
+                log::error!("Server Send Error: {}", error);
+                // If we can't send to a channel, it's disconnected.
+                // We need to re-create the channel and re-try sending the message.
+                true
+            }
+            // This is a new connection, so we need to create a channel.
+            None => true,
+        };
+
+        if needs_new_channel {
+            log::debug!("Dropped packet from unconnected {}", address);
+        }
+        None
+    }
+
+    fn route_control<F>(
+        &self,
+        transport: Transport,
+        local_address: Option<SocketAddr>,
+        address: SocketAddr,
+        payload: &[u8],
+        sender: F,
+    ) -> Option<Vec<u8>>
+    where
+        F: FnOnce() -> ServerPacketSender,
+    {
+        if !self.rate_limiter.allow(address.ip(), Instant::now()) {
+            log::debug!("Rate limited control packet from {}", address);
+            return None;
+        }
+
+        let connected = self
+            .server_channels
+            .read()
+            .expect("server channels lock is poisoned")
+            .contains_key(&address);
+        let now = unix_time();
+        let (cookie, token) = match ControlPacket::decode(payload) {
+            Some(ControlPacket::ConnectRequest) | Some(ControlPacket::ChallengeResponse { .. })
+                if connected =>
+            {
+                // our answer got lost
+                return Some(ControlPacket::Accepted.encode());
+            }
+            Some(ControlPacket::ConnectRequest) if payload.len() >= MIN_CONNECT_REQUEST_LEN => {
+                let cookie = self.cookies.issue(address, now);
+                return Some(ControlPacket::Challenge { cookie }.encode());
+            }
+            Some(ControlPacket::ChallengeResponse { cookie, token }) => (cookie, token),
+            _ => {
+                log::debug!("Dropped control packet from {}", address);
+                return None;
+            }
+        };
+
+        match self.cookies.verify(address, &cookie, now) {
+            Some(true) => {}
+            Some(false) => {
+                let cookie = self.cookies.issue(address, now);
+                return Some(ControlPacket::Challenge { cookie }.encode());
+            }
+            None => {
+                log::debug!("Dropped forged cookie from {}", address);
+                return None;
+            }
+        }
+
+        let token = match self.validate_token(token, address, now) {
+            Ok(token) => token,
+            Err(reason) => return Some(ControlPacket::Rejected(reason).encode()),
+        };
+
+        if !self.slots.try_acquire() {
+            log::debug!("Rejected connection from {}: server is full", address);
+            return Some(ControlPacket::Rejected(RejectReason::ServerFull).encode());
+        }
+        if self.accept(transport, local_address, address, token, sender) {
+            Some(ControlPacket::Accepted.encode())
+        } else {
+            self.slots.release();
+            // the client retries
+            None
+        }
+    }
+
+    fn accept<F>(
+        &self,
+        transport: Transport,
+        local_address: Option<SocketAddr>,
+        address: SocketAddr,
+        connect_token: Option<ConnectTokenData>,
+        sender: F,
+    ) -> bool
+    where
+        F: FnOnce() -> ServerPacketSender,
+    {
+        let mut pending_connections = self.pending_connections.lock().unwrap();
+        if pending_connections.len() >= self.settings.max_pending_connections {
+            log::warn!("Too many pending connections, dropped {}", address);
+            return false;
+        }
+
+        let mut server_channels = self
+            .server_channels
+            .write()
+            .expect("server channels lock is poisoned");
+        let (packet_tx, packet_rx) = bounded(self.settings.receive_queue_len);
+        let stats = Arc::new(RwLock::new(PacketStats::default()));
+        pending_connections.push(Box::new(ServerConnection::new(
+            self.task_pool.clone(),
+            packet_rx,
+            sender(),
+            address,
+            transport,
+            local_address,
+            connect_token,
+            stats.clone(),
+        )));
+        server_channels.insert(address, ServerRoute { packet_tx, stats });
+        true
+    }
+}
+
+/// Receive loop of the built-in UDP listener, used when naia-socket is compiled for WebRTC.
+pub(crate) async fn listen_udp(context: ListenerContext, socket: Arc<UdpSocket>) {
+    let local_address = socket.local_addr().ok();
+    let mut buffer = vec![0; UDP_RECV_BUFFER_LEN];
+    let mut reply_sender = ServerPacketSender::Udp(socket.clone());
+    loop {
+        match socket.recv_from(&mut buffer).await {
+            Ok((len, address)) => {
+                let reply = context.route_packet(
+                    Transport::Udp,
+                    local_address,
+                    address,
+                    &buffer[..len],
+                    || ServerPacketSender::Udp(socket.clone()),
+                );
+                if let Some(reply) = reply {
+                    if let Err(error) = reply_sender.send(address, &reply).await {
+                        log::error!("Server Send Error: {}", error);
+                    }
+                }
+            }
+            Err(error) => {
+                log::error!("Server Receive Error: {}", error);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use instant::Duration;
+
+    fn address(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn cookies_expire() {
+        let cookies = CookieJar::new();
+        let cookie = cookies.issue(address(1), 100);
+        assert_eq!(cookies.verify(address(1), &cookie, 100), Some(true));
+        assert_eq!(
+            cookies.verify(address(1), &cookie, 100 + COOKIE_LIFETIME_SECS),
+            Some(true)
+        );
+        assert_eq!(
+            cookies.verify(address(1), &cookie, 101 + COOKIE_LIFETIME_SECS),
+            Some(false)
+        );
+        // issued in the future
+        assert_eq!(cookies.verify(address(1), &cookie, 99), Some(false));
+    }
+
+    #[test]
+    fn forged_cookies_are_refused() {
+        let cookies = CookieJar::new();
+        let cookie = cookies.issue(address(1), 100);
+        assert_eq!(cookies.verify(address(2), &cookie, 100), None);
+
+        let mut tampered = cookie.clone();
+        tampered.issued_at += 1;
+        assert_eq!(cookies.verify(address(1), &tampered, 101), None);
+
+        // secrets differ between listeners
+        assert_eq!(CookieJar::new().verify(address(1), &cookie, 100), None);
+    }
+
+    #[test]
+    fn rate_limiter_refills() {
+        let limiter = HandshakeRateLimiter::new(&ListenerSettings {
+            handshake_rate: 2.0,
+            handshake_burst: 3.0,
+            ..ListenerSettings::default()
+        });
+        let ip = address(1).ip();
+        let start = Instant::now();
+        for _ in 0..3 {
+            assert!(limiter.allow(ip, start));
+        }
+        assert!(!limiter.allow(ip, start));
+        // other addresses have their own burst
+        assert!(limiter.allow(IpAddr::from([127, 0, 0, 2]), start));
+
+        assert!(limiter.allow(ip, start + Duration::from_millis(500)));
+        assert!(!limiter.allow(ip, start + Duration::from_millis(500)));
+
+        // refills up to the burst only
+        let later = start + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert!(limiter.allow(ip, later));
+        }
+        assert!(!limiter.allow(ip, later));
+    }
+
+    #[test]
+    fn connection_slots_run_out() {
+        let slots = ConnectionSlots::new(Some(2));
+        assert!(slots.try_acquire());
+        assert!(slots.try_acquire());
+        assert!(!slots.try_acquire());
+        assert_eq!(slots.used(), 2);
+
+        // raising the limit frees room without releasing
+        slots.set_max(Some(3));
+        assert!(slots.try_acquire());
+        assert!(!slots.try_acquire());
+    }
+
+    #[test]
+    fn released_connection_slots_are_reused() {
+        let slots = ConnectionSlots::new(Some(1));
+        assert!(slots.try_acquire());
+        assert!(!slots.try_acquire());
+        slots.release();
+        assert_eq!(slots.used(), 0);
+        assert!(slots.try_acquire());
+
+        // releasing more than acquired does not underflow
+        slots.release();
+        slots.release();
+        assert_eq!(slots.used(), 0);
+        assert!(slots.try_acquire());
+        assert!(!slots.try_acquire());
+    }
+}
@@ -0,0 +1,510 @@
+//! Delta compressed snapshots.
+//!
+//! The server pushes a snapshot of its state every tick with `Snapshots::push`. Every connection
+//! keeps a ring buffer of snapshots sent to it, clients acknowledge the ones they received and
+//! the newest acknowledged snapshot becomes the baseline for the next one. Only entities whose
+//! `Delta::diff` against the baseline is not empty are sent, over an unreliable channel.
+//! Without an acknowledged baseline in the ring buffer a full snapshot is sent.
+//!
+//! Snapshot messages are not fragmented, keep them within a single packet.
+
+use bevy_app::{AppBuilder, CoreStage, Plugin};
+use bevy_ecs::prelude::*;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Debug,
+    marker::PhantomData,
+    sync::Arc,
+};
+
+use super::{
+    sequence::sequence_greater_than, ConnectionChannelsBuilder, ConnectionHandle, ConnectionRole,
+    MessageChannelMode, MessageChannelSettings, NetworkResource,
+};
+
+pub const SNAPSHOT_CHANNEL: u8 = 249;
+pub const SNAPSHOT_ACK_CHANNEL: u8 = 248;
+
+/// State of a single entity, that can be sent as a difference against an older version.
+pub trait Delta: Clone + Debug + Serialize + DeserializeOwned + Send + Sync + 'static {
+    type Delta: Clone + Debug + Serialize + DeserializeOwned + Send + Sync + 'static;
+
+    /// Fields changed since `baseline`, `None` when nothing changed.
+    fn diff(&self, baseline: &Self) -> Option<Self::Delta>;
+
+    /// Returns the state `delta` was taken for, with `self` as the baseline.
+    fn apply(&self, delta: &Self::Delta) -> Self;
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(bound = "T: Delta")]
+pub enum EntityState<T: Delta> {
+    Full(T),
+    Delta(T::Delta),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(bound = "T: Delta")]
+pub struct SnapshotMessage<T: Delta> {
+    pub sequence: u32,
+    /// Snapshot the deltas are taken against, `None` for a full snapshot.
+    pub baseline: Option<u32>,
+    /// Entities changed since baseline.
+    pub entities: Vec<(u64, EntityState<T>)>,
+    /// Entities gone since baseline.
+    pub removed: Vec<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SnapshotAck<T> {
+    pub sequence: u32,
+    #[serde(skip)]
+    marker: PhantomData<fn() -> T>,
+}
+
+impl<T> SnapshotAck<T> {
+    fn new(sequence: u32) -> Self {
+        SnapshotAck {
+            sequence,
+            marker: PhantomData,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Snapshot<T> {
+    pub sequence: u32,
+    pub entities: HashMap<u64, T>,
+}
+
+/// Snapshots to send (on server) and received (on client).
+pub struct Snapshots<T: Delta> {
+    history_len: usize,
+    sequence: u32,
+    pending: Option<HashMap<u64, T>>,
+    latest: HashMap<ConnectionHandle, Arc<Snapshot<T>>>,
+}
+
+impl<T: Delta> Snapshots<T> {
+    fn new(history_len: usize) -> Self {
+        Snapshots {
+            history_len,
+            sequence: 0,
+            pending: None,
+            latest: HashMap::new(),
+        }
+    }
+
+    /// Queues the current server state, sent to all clients at the end of the frame.
+    pub fn push(&mut self, entities: HashMap<u64, T>) {
+        self.pending = Some(entities);
+    }
+
+    /// Newest snapshot received from the server on `handle`.
+    pub fn latest(&self, handle: ConnectionHandle) -> Option<&Snapshot<T>> {
+        self.latest.get(&handle).map(|snapshot| &**snapshot)
+    }
+}
+
+/// Server side ring buffer of snapshots sent to a connection, kept as connection user data.
+struct SentSnapshots<T> {
+    sent: VecDeque<Arc<Snapshot<T>>>,
+    acked: Option<u32>,
+}
+
+impl<T> Default for SentSnapshots<T> {
+    fn default() -> Self {
+        SentSnapshots {
+            sent: VecDeque::new(),
+            acked: None,
+        }
+    }
+}
+
+/// Client side ring buffer of received snapshots, usable as baselines.
+struct ReceivedSnapshots<T> {
+    received: VecDeque<Arc<Snapshot<T>>>,
+}
+
+impl<T> Default for ReceivedSnapshots<T> {
+    fn default() -> Self {
+        ReceivedSnapshots {
+            received: VecDeque::new(),
+        }
+    }
+}
+
+fn diff_snapshot<T: Delta>(
+    snapshot: &Snapshot<T>,
+    baseline: Option<&Snapshot<T>>,
+) -> SnapshotMessage<T> {
+    let baseline = match baseline {
+        Some(baseline) => baseline,
+        None => {
+            return SnapshotMessage {
+                sequence: snapshot.sequence,
+                baseline: None,
+                entities: snapshot
+                    .entities
+                    .iter()
+                    .map(|(id, state)| (*id, EntityState::Full(state.clone())))
+                    .collect(),
+                removed: Vec::new(),
+            }
+        }
+    };
+
+    let entities = snapshot
+        .entities
+        .iter()
+        .filter_map(|(id, state)| match baseline.entities.get(id) {
+            Some(previous) => state
+                .diff(previous)
+                .map(|delta| (*id, EntityState::Delta(delta))),
+            None => Some((*id, EntityState::Full(state.clone()))),
+        })
+        .collect();
+    let removed = baseline
+        .entities
+        .keys()
+        .filter(|id| !snapshot.entities.contains_key(id))
+        .copied()
+        .collect();
+
+    SnapshotMessage {
+        sequence: snapshot.sequence,
+        baseline: Some(baseline.sequence),
+        entities,
+        removed,
+    }
+}
+
+fn apply_snapshot<T: Delta>(
+    message: SnapshotMessage<T>,
+    baseline: Option<&Snapshot<T>>,
+) -> Option<Snapshot<T>> {
+    let mut entities = match baseline {
+        Some(baseline) => baseline.entities.clone(),
+        None => HashMap::new(),
+    };
+    for id in message.removed.iter() {
+        entities.remove(id);
+    }
+    for (id, state) in message.entities {
+        let state = match state {
+            EntityState::Full(state) => state,
+            EntityState::Delta(delta) => match entities.get(&id) {
+                Some(previous) => previous.apply(&delta),
+                None => {
+                    log::error!(
+                        "Snapshot {} has delta for unknown entity {}",
+                        message.sequence,
+                        id
+                    );
+                    return None;
+                }
+            },
+        };
+        entities.insert(id, state);
+    }
+    Some(Snapshot {
+        sequence: message.sequence,
+        entities,
+    })
+}
+
+pub fn snapshot_server_system<T: Delta>(
+    mut net: ResMut<NetworkResource>,
+    mut snapshots: ResMut<Snapshots<T>>,
+) {
+    let handles: Vec<ConnectionHandle> = net
+        .connections
+        .iter()
+        .filter(|(_, connection)| connection.info().role == ConnectionRole::Server)
+        .map(|(handle, _)| *handle)
+        .collect();
+
+    let snapshot = snapshots.pending.take().map(|entities| {
+        snapshots.sequence = snapshots.sequence.wrapping_add(1);
+        Arc::new(Snapshot {
+            sequence: snapshots.sequence,
+            entities,
+        })
+    });
+
+    for handle in handles {
+        let mut peer = net
+            .remove_user_data::<SentSnapshots<T>>(handle)
+            .unwrap_or_default();
+        let channels = match net
+            .connections
+            .get_mut(&handle)
+            .and_then(|connection| connection.channels())
+        {
+            Some(channels) => channels,
+            None => continue,
+        };
+
+        while let Some(ack) = channels.recv::<SnapshotAck<T>>() {
+            // acks are unreliable, so may come out of order
+            if peer
+                .acked
+                .is_none_or(|acked| sequence_greater_than(ack.sequence, acked))
+            {
+                peer.acked = Some(ack.sequence);
+            }
+        }
+
+        if let Some(snapshot) = snapshot.as_ref() {
+            let baseline = peer.acked.and_then(|acked| {
+                peer.sent
+                    .iter()
+                    .find(|sent| sent.sequence == acked)
+                    .map(|sent| &**sent)
+            });
+            let message = diff_snapshot(snapshot, baseline);
+            if channels.send(message).is_some() {
+                log::debug!("Snapshot channel of [{}] is full", handle);
+            }
+            channels.flush::<SnapshotMessage<T>>();
+
+            peer.sent.push_back(snapshot.clone());
+            while peer.sent.len() > snapshots.history_len {
+                peer.sent.pop_front();
+            }
+        }
+
+        let _ = net.set_user_data(handle, peer);
+    }
+}
+
+pub fn snapshot_client_system<T: Delta>(
+    mut net: ResMut<NetworkResource>,
+    mut snapshots: ResMut<Snapshots<T>>,
+) {
+    let handles: Vec<ConnectionHandle> = net
+        .connections
+        .iter()
+        .filter(|(_, connection)| connection.info().role == ConnectionRole::Client)
+        .map(|(handle, _)| *handle)
+        .collect();
+    let snapshots = &mut *snapshots;
+    snapshots
+        .latest
+        .retain(|handle, _| handles.contains(handle));
+
+    for handle in handles {
+        let mut peer = net
+            .remove_user_data::<ReceivedSnapshots<T>>(handle)
+            .unwrap_or_default();
+        let channels = match net
+            .connections
+            .get_mut(&handle)
+            .and_then(|connection| connection.channels())
+        {
+            Some(channels) => channels,
+            None => continue,
+        };
+
+        let mut acked = false;
+        while let Some(message) = channels.recv::<SnapshotMessage<T>>() {
+            let latest = snapshots.latest.get(&handle).map(|latest| latest.sequence);
+            if latest.is_some_and(|latest| !sequence_greater_than(message.sequence, latest)) {
+                // older than what we have already
+                continue;
+            }
+            let baseline = match message.baseline {
+                Some(baseline) => match peer
+                    .received
+                    .iter()
+                    .find(|received| received.sequence == baseline)
+                {
+                    Some(received) => Some(received.clone()),
+                    None => {
+                        log::debug!(
+                            "Snapshot {} baseline {} is gone",
+                            message.sequence,
+                            baseline
+                        );
+                        continue;
+                    }
+                },
+                None => None,
+            };
+            let snapshot = match apply_snapshot(message, baseline.as_deref()) {
+                Some(snapshot) => Arc::new(snapshot),
+                None => continue,
+            };
+
+            if channels
+                .send(SnapshotAck::<T>::new(snapshot.sequence))
+                .is_some()
+            {
+                log::debug!("Snapshot ack channel of [{}] is full", handle);
+            }
+            acked = true;
+
+            peer.received.push_back(snapshot.clone());
+            while peer.received.len() > snapshots.history_len {
+                peer.received.pop_front();
+            }
+            snapshots.latest.insert(handle, snapshot);
+        }
+        if acked {
+            channels.flush::<SnapshotAck<T>>();
+        }
+
+        let _ = net.set_user_data(handle, peer);
+    }
+}
+
+/// Sends `Snapshots<T>` from server to clients.
+/// Each snapshot type needs its own pair of channels.
+pub struct SnapshotPlugin<T> {
+    pub channel: u8,
+    pub ack_channel: u8,
+    /// Snapshots kept for use as baselines, on both sides.
+    pub history_len: usize,
+    marker: PhantomData<fn() -> T>,
+}
+
+impl<T> SnapshotPlugin<T> {
+    pub fn new(channel: u8, ack_channel: u8) -> Self {
+        SnapshotPlugin {
+            channel,
+            ack_channel,
+            history_len: 32,
+            marker: PhantomData,
+        }
+    }
+
+    pub fn with_history_len(mut self, history_len: usize) -> Self {
+        self.history_len = history_len;
+        self
+    }
+}
+
+impl<T> Default for SnapshotPlugin<T> {
+    fn default() -> Self {
+        SnapshotPlugin::new(SNAPSHOT_CHANNEL, SNAPSHOT_ACK_CHANNEL)
+    }
+}
+
+impl<T: Delta> Plugin for SnapshotPlugin<T> {
+    fn build(&self, app: &mut AppBuilder) {
+        let channel = self.channel;
+        let ack_channel = self.ack_channel;
+        app.world_mut()
+            .get_resource_mut::<NetworkResource>()
+            .expect("`NetworkingPlugin` has to be added before `SnapshotPlugin`")
+            .add_channels_builder(move |builder: &mut ConnectionChannelsBuilder| {
+                builder
+                    .register::<SnapshotMessage<T>>(MessageChannelSettings {
+                        channel,
+                        channel_mode: MessageChannelMode::Unreliable,
+                        message_buffer_size: 8,
+                        packet_buffer_size: 8,
+                    })
+                    .unwrap();
+                builder
+                    .register::<SnapshotAck<T>>(MessageChannelSettings {
+                        channel: ack_channel,
+                        channel_mode: MessageChannelMode::Unreliable,
+                        message_buffer_size: 32,
+                        packet_buffer_size: 32,
+                    })
+                    .unwrap();
+            });
+
+        app.insert_resource(Snapshots::<T>::new(self.history_len))
+            .add_system_to_stage(CoreStage::PreUpdate, snapshot_client_system::<T>.system())
+            .add_system_to_stage(CoreStage::PostUpdate, snapshot_server_system::<T>.system());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    struct Position {
+        x: i32,
+        y: i32,
+    }
+
+    impl Delta for Position {
+        type Delta = (Option<i32>, Option<i32>);
+
+        fn diff(&self, baseline: &Self) -> Option<Self::Delta> {
+            let x = Some(self.x).filter(|x| *x != baseline.x);
+            let y = Some(self.y).filter(|y| *y != baseline.y);
+            if x.is_none() && y.is_none() {
+                None
+            } else {
+                Some((x, y))
+            }
+        }
+
+        fn apply(&self, delta: &Self::Delta) -> Self {
+            Position {
+                x: delta.0.unwrap_or(self.x),
+                y: delta.1.unwrap_or(self.y),
+            }
+        }
+    }
+
+    fn snapshot(sequence: u32, entities: &[(u64, i32, i32)]) -> Snapshot<Position> {
+        Snapshot {
+            sequence,
+            entities: entities
+                .iter()
+                .map(|(id, x, y)| (*id, Position { x: *x, y: *y }))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn full_snapshot_round_trip() {
+        let snapshot = snapshot(1, &[(1, 0, 0), (2, 5, 6)]);
+        let message = diff_snapshot(&snapshot, None);
+        assert_eq!(message.baseline, None);
+        assert!(message
+            .entities
+            .iter()
+            .all(|(_, state)| matches!(state, EntityState::Full(_))));
+
+        let applied = apply_snapshot(message, None).unwrap();
+        assert_eq!(applied.sequence, 1);
+        assert_eq!(applied.entities, snapshot.entities);
+    }
+
+    #[test]
+    fn delta_snapshot_round_trip() {
+        let baseline = snapshot(1, &[(1, 0, 0), (2, 5, 6), (3, 7, 8)]);
+        let snapshot = snapshot(2, &[(1, 0, 0), (2, 5, 9), (4, 1, 1)]);
+        let mut message = diff_snapshot(&snapshot, Some(&baseline));
+        assert_eq!(message.baseline, Some(1));
+        assert_eq!(message.removed, vec![3]);
+
+        message.entities.sort_by_key(|(id, _)| *id);
+        assert_eq!(message.entities.len(), 2);
+        assert!(matches!(
+            message.entities[0],
+            (2, EntityState::Delta((None, Some(9))))
+        ));
+        assert!(matches!(message.entities[1], (4, EntityState::Full(_))));
+
+        let applied = apply_snapshot(message, Some(&baseline)).unwrap();
+        assert_eq!(applied.sequence, 2);
+        assert_eq!(applied.entities, snapshot.entities);
+    }
+
+    #[test]
+    fn delta_without_baseline_entity_is_rejected() {
+        let baseline = snapshot(1, &[(1, 0, 0)]);
+        let message = diff_snapshot(&snapshot(2, &[(1, 3, 0)]), Some(&baseline));
+        assert!(apply_snapshot(message.clone(), None).is_none());
+        assert!(apply_snapshot(message, Some(&snapshot(1, &[(2, 0, 0)]))).is_none());
+    }
+}
@@ -0,0 +1,287 @@
+//! Signed connect tokens, netcode.io style.
+//!
+//! A matchmaker sharing `ConnectTokenKey` with dedicated servers issues tokens to players along
+//! with the server address. The client passes the token to `NetworkResource::connect_with_token`.
+//! A server started with `NetworkResource::require_connect_tokens` only creates connections for
+//! valid tokens. Tokens expire, and each one is accepted only once.
+
+use std::{collections::HashMap, fmt, net::SocketAddr, sync::Mutex};
+
+use hmac::{Hmac, Mac, NewMac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+/// Largest accepted encoded token, with its user data.
+pub const MAX_CONNECT_TOKEN_LEN: usize = 1024;
+
+/// What the matchmaker tells the server about the client.
+/// Available from `ConnectionInfo::connect_token` of the accepted connection.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ConnectTokenData {
+    pub client_id: u64,
+    /// Seconds since UNIX epoch.
+    pub expires_at: u64,
+    /// Unique per token, used to refuse replays.
+    pub nonce: u64,
+    pub user_data: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ConnectToken {
+    pub data: ConnectTokenData,
+    mac: [u8; 32],
+}
+
+impl ConnectToken {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        bincode::serialize(self).expect("connect token serializes")
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ConnectTokenError> {
+        if bytes.len() > MAX_CONNECT_TOKEN_LEN {
+            return Err(ConnectTokenError::Malformed);
+        }
+        bincode::deserialize(bytes).map_err(|_| ConnectTokenError::Malformed)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectTokenError {
+    Malformed,
+    InvalidSignature,
+    Expired,
+    /// Token was already used from another address.
+    Reused,
+}
+
+impl fmt::Display for ConnectTokenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectTokenError::Malformed => write!(f, "Malformed connect token"),
+            ConnectTokenError::InvalidSignature => write!(f, "Invalid connect token signature"),
+            ConnectTokenError::Expired => write!(f, "Connect token expired"),
+            ConnectTokenError::Reused => write!(f, "Connect token already used"),
+        }
+    }
+}
+
+impl std::error::Error for ConnectTokenError {}
+
+/// Private key shared by the matchmaker and servers.
+///
+/// Tokens are signed together with `protocol_id`, so they are only accepted by servers using
+/// the same one. Pick a distinct id per game and protocol version.
+#[derive(Clone)]
+pub struct ConnectTokenKey {
+    protocol_id: u64,
+    key: [u8; 32],
+}
+
+impl ConnectTokenKey {
+    pub fn new(protocol_id: u64, key: [u8; 32]) -> Self {
+        ConnectTokenKey { protocol_id, key }
+    }
+
+    fn mac(&self, data: &ConnectTokenData) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC takes any key length");
+        mac.update(&self.protocol_id.to_le_bytes());
+        mac.update(&bincode::serialize(data).expect("connect token data serializes"));
+        mac
+    }
+
+    pub fn sign(&self, data: ConnectTokenData) -> ConnectToken {
+        let mut mac = [0; 32];
+        mac.copy_from_slice(&self.mac(&data).finalize().into_bytes());
+        ConnectToken { data, mac }
+    }
+
+    /// Signs a token for `client_id`, valid for `valid_for` from now.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn issue(
+        &self,
+        client_id: u64,
+        nonce: u64,
+        valid_for: std::time::Duration,
+        user_data: Vec<u8>,
+    ) -> ConnectToken {
+        self.sign(ConnectTokenData {
+            client_id,
+            expires_at: unix_time() + valid_for.as_secs(),
+            nonce,
+            user_data,
+        })
+    }
+
+    /// Checks the signature and expiry, `now` in seconds since UNIX epoch.
+    pub fn verify<'a>(
+        &self,
+        token: &'a ConnectToken,
+        now: u64,
+    ) -> Result<&'a ConnectTokenData, ConnectTokenError> {
+        self.mac(&token.data)
+            .verify(&token.mac)
+            .map_err(|_| ConnectTokenError::InvalidSignature)?;
+        if token.data.expires_at <= now {
+            return Err(ConnectTokenError::Expired);
+        }
+        Ok(&token.data)
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn unix_time() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or(0)
+}
+
+/// Server side token checks, remembering used tokens until they expire.
+pub(crate) struct ConnectTokenValidator {
+    key: ConnectTokenKey,
+    // nonce -> (expires_at, address using it)
+    used: Mutex<HashMap<u64, (u64, SocketAddr)>>,
+}
+
+impl ConnectTokenValidator {
+    pub fn new(key: ConnectTokenKey) -> Self {
+        ConnectTokenValidator {
+            key,
+            used: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Accepts the token once, and again only from the same address.
+    pub fn validate(
+        &self,
+        token: &ConnectToken,
+        address: SocketAddr,
+        now: u64,
+    ) -> Result<ConnectTokenData, ConnectTokenError> {
+        let data = self.key.verify(token, now)?;
+
+        let mut used = self.used.lock().expect("connect tokens lock is poisoned");
+        used.retain(|_, (expires_at, _)| *expires_at > now);
+        match used.get(&data.nonce) {
+            Some((_, used_by)) if *used_by != address => Err(ConnectTokenError::Reused),
+            _ => {
+                used.insert(data.nonce, (data.expires_at, address));
+                Ok(data.clone())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1_600_000_000;
+    const PROTOCOL_ID: u64 = 0x6e65_7467_616d_6501;
+
+    fn token(key: &ConnectTokenKey, nonce: u64, expires_at: u64) -> ConnectToken {
+        key.sign(ConnectTokenData {
+            client_id: 7,
+            expires_at,
+            nonce,
+            user_data: vec![1, 2, 3],
+        })
+    }
+
+    fn address(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn validator_accepts_valid_tokens() {
+        let key = ConnectTokenKey::new(PROTOCOL_ID, [1; 32]);
+        let validator = ConnectTokenValidator::new(key.clone());
+        let token = token(&key, 1, NOW + 30);
+        assert_eq!(validator.validate(&token, address(1), NOW), Ok(token.data));
+    }
+
+    #[test]
+    fn validator_refuses_expired_tokens() {
+        let key = ConnectTokenKey::new(PROTOCOL_ID, [1; 32]);
+        let validator = ConnectTokenValidator::new(key.clone());
+        let token = token(&key, 1, NOW);
+        assert_eq!(
+            validator.validate(&token, address(1), NOW),
+            Err(ConnectTokenError::Expired)
+        );
+        assert_eq!(
+            validator.validate(&token, address(1), NOW + 1),
+            Err(ConnectTokenError::Expired)
+        );
+        assert!(validator.validate(&token, address(1), NOW - 1).is_ok());
+    }
+
+    #[test]
+    fn validator_refuses_reused_tokens() {
+        let key = ConnectTokenKey::new(PROTOCOL_ID, [1; 32]);
+        let validator = ConnectTokenValidator::new(key.clone());
+        let first = token(&key, 1, NOW + 30);
+        assert!(validator.validate(&first, address(1), NOW).is_ok());
+        // resent handshakes come from the same address
+        assert!(validator.validate(&first, address(1), NOW + 1).is_ok());
+        assert_eq!(
+            validator.validate(&first, address(2), NOW + 1),
+            Err(ConnectTokenError::Reused)
+        );
+        // other nonces are unaffected
+        let other = token(&key, 2, NOW + 30);
+        assert!(validator.validate(&other, address(2), NOW + 1).is_ok());
+    }
+
+    #[test]
+    fn validator_refuses_forged_tokens() {
+        let key = ConnectTokenKey::new(PROTOCOL_ID, [1; 32]);
+        let validator = ConnectTokenValidator::new(key.clone());
+
+        let other_key = ConnectTokenKey::new(PROTOCOL_ID, [2; 32]);
+        let forged = token(&other_key, 1, NOW + 30);
+        assert_eq!(
+            validator.validate(&forged, address(1), NOW),
+            Err(ConnectTokenError::InvalidSignature)
+        );
+        // same key, issued for another game
+        let other_protocol = ConnectTokenKey::new(PROTOCOL_ID + 1, [1; 32]);
+        let foreign = token(&other_protocol, 1, NOW + 30);
+        assert_eq!(
+            validator.validate(&foreign, address(1), NOW),
+            Err(ConnectTokenError::InvalidSignature)
+        );
+
+        let mut tampered = token(&key, 1, NOW + 30);
+        tampered.data.expires_at += 3600;
+        assert_eq!(
+            validator.validate(&tampered, address(1), NOW),
+            Err(ConnectTokenError::InvalidSignature)
+        );
+        let mut tampered = token(&key, 1, NOW + 30);
+        tampered.data.user_data.push(4);
+        assert_eq!(
+            validator.validate(&tampered, address(1), NOW),
+            Err(ConnectTokenError::InvalidSignature)
+        );
+
+        // a refused token does not use up the nonce
+        let token = token(&key, 1, NOW + 30);
+        assert!(validator.validate(&token, address(1), NOW).is_ok());
+    }
+
+    #[test]
+    fn tokens_round_trip_through_bytes() {
+        let key = ConnectTokenKey::new(PROTOCOL_ID, [1; 32]);
+        let token = token(&key, 1, NOW + 30);
+        assert_eq!(ConnectToken::from_bytes(&token.to_bytes()), Ok(token));
+        assert_eq!(
+            ConnectToken::from_bytes(&[0; 3]),
+            Err(ConnectTokenError::Malformed)
+        );
+        assert_eq!(
+            ConnectToken::from_bytes(&[0; MAX_CONNECT_TOKEN_LEN + 1]),
+            Err(ConnectTokenError::Malformed)
+        );
+    }
+}
@@ -2,8 +2,12 @@
 use bevy_tasks::Task;
 use bevy_tasks::TaskPool;
 use bytes::Bytes;
-use std::{error::Error, net::SocketAddr, sync::{Arc, RwLock}};
-use instant::{Instant, Duration};
+use instant::{Duration, Instant};
+use std::{
+    error::Error,
+    net::SocketAddr,
+    sync::{Arc, Mutex, RwLock},
+};
 
 use naia_client_socket::{
     ClientSocketTrait, MessageSender as ClientSender, Packet as ClientPacket,
@@ -13,32 +17,194 @@ use naia_server_socket::{MessageSender as ServerSender, Packet as ServerPacket};
 
 use turbulence::{
     buffer::BufferPacketPool,
-    message_channels::{MessageChannels, MessageChannelsBuilder},
+    message_channels::{
+        ChannelAlreadyRegistered, ChannelMessage, MessageChannelMode, MessageChannelSettings,
+        MessageChannels, MessageChannelsBuilder,
+    },
     packet::PacketPool,
     packet_multiplexer::{IncomingMultiplexedPackets, MuxPacket, MuxPacketPool, PacketMultiplexer},
 };
 
 #[cfg(not(target_arch = "wasm32"))]
-use futures_lite::future::block_on;
+use futures::{channel::mpsc, SinkExt};
 
 use futures_lite::StreamExt;
 
 use super::{
     channels::{SimpleBufferPool, TaskPoolRuntime},
-    NetworkError,
+    control::{ControlPacket, MIN_CONNECT_REQUEST_LEN},
+    hash::Fnv1a,
+    token::{ConnectToken, ConnectTokenData},
+    NetworkError, Transport,
 };
 
+// raw and channel packets waiting for the socket, per server connection
+#[cfg(not(target_arch = "wasm32"))]
+const SEND_QUEUE_LEN: usize = 256;
+
+// errors of send and channels tasks waiting for `receive`, the first few say it all
+pub(crate) const SEND_ERRORS_LEN: usize = 16;
+
+// how often a client repeats its connect request until the server accepts it
+const CONNECT_REQUEST_INTERVAL: Duration = Duration::from_millis(250);
+
+// packets a client holds back until the server accepts the connection
+const HELD_BACK_LEN: usize = 256;
+
 pub type Packet = Bytes;
 pub type MultiplexedPacket = MuxPacket<<BufferPacketPool<SimpleBufferPool> as PacketPool>::Packet>;
-pub type ConnectionChannelsBuilder =
-    MessageChannelsBuilder<TaskPoolRuntime, MuxPacketPool<BufferPacketPool<SimpleBufferPool>>>;
 
-#[derive(Debug, Clone)]
+/// `MessageChannelsBuilder` keeping track of registered channels,
+/// to verify that both ends of a connection registered the same ones.
+pub struct ConnectionChannelsBuilder {
+    builder:
+        MessageChannelsBuilder<TaskPoolRuntime, MuxPacketPool<BufferPacketPool<SimpleBufferPool>>>,
+    registered: Vec<(Option<&'static str>, MessageChannelSettings)>,
+}
+
+impl ConnectionChannelsBuilder {
+    pub(crate) fn new(
+        runtime: TaskPoolRuntime,
+        pool: MuxPacketPool<BufferPacketPool<SimpleBufferPool>>,
+    ) -> Self {
+        ConnectionChannelsBuilder {
+            builder: MessageChannelsBuilder::new(runtime, pool),
+            registered: Vec::new(),
+        }
+    }
+
+    pub fn register<M: ChannelMessage>(
+        &mut self,
+        settings: MessageChannelSettings,
+    ) -> Result<(), ChannelAlreadyRegistered> {
+        self.builder
+            .register::<M>(copy_channel_settings(&settings))?;
+        self.registered.push((None, settings));
+        Ok(())
+    }
+
+    /// Same as `register`, with `name` of the message type verified in the connection handshake.
+    /// Pick a name that stays the same across builds, unlike `std::any::type_name`.
+    pub fn register_named<M: ChannelMessage>(
+        &mut self,
+        name: &'static str,
+        settings: MessageChannelSettings,
+    ) -> Result<(), ChannelAlreadyRegistered> {
+        self.builder
+            .register::<M>(copy_channel_settings(&settings))?;
+        self.registered.push((Some(name), settings));
+        Ok(())
+    }
+
+    /// Hash of registered channel ids, names given to `register_named`, modes and settings.
+    /// Independent of the registration order.
+    pub fn fingerprint(&self) -> u64 {
+        let mut registered: Vec<&(Option<&'static str>, MessageChannelSettings)> =
+            self.registered.iter().collect();
+        registered.sort_by_key(|(_, settings)| settings.channel);
+
+        let mut hasher = Fnv1a::default();
+        for (name, settings) in registered {
+            hash_channel_settings(&mut hasher, settings);
+            let name = name.unwrap_or("");
+            hasher.write(&(name.len() as u64).to_le_bytes());
+            hasher.write(name.as_bytes());
+        }
+        hasher.finish()
+    }
+
+    pub(crate) fn build(
+        self,
+        multiplexer: &mut PacketMultiplexer<MultiplexedPacket>,
+    ) -> MessageChannels {
+        self.builder.build(multiplexer)
+    }
+}
+
+/// Feeds every field of `settings` to `hasher`, so peers agree on it regardless of formatting.
+pub(crate) fn hash_channel_settings(hasher: &mut Fnv1a, settings: &MessageChannelSettings) {
+    hasher.write(&[settings.channel]);
+    let (reliability_settings, max_len) = match &settings.channel_mode {
+        MessageChannelMode::Unreliable => {
+            hasher.write(&[0]);
+            (None, 0)
+        }
+        MessageChannelMode::Reliable {
+            reliability_settings,
+            max_message_len,
+        } => {
+            hasher.write(&[1]);
+            (Some(reliability_settings), *max_message_len)
+        }
+        MessageChannelMode::Compressed {
+            reliability_settings,
+            max_chunk_len,
+        } => {
+            hasher.write(&[2]);
+            (Some(reliability_settings), *max_chunk_len)
+        }
+    };
+    if let Some(reliability_settings) = reliability_settings {
+        for value in [
+            reliability_settings.bandwidth as u64,
+            reliability_settings.recv_window_size as u64,
+            reliability_settings.send_window_size as u64,
+            reliability_settings.burst_bandwidth as u64,
+            reliability_settings.init_send as u64,
+            reliability_settings.wakeup_time.as_nanos() as u64,
+            reliability_settings.initial_rtt.as_nanos() as u64,
+            reliability_settings.max_rtt.as_nanos() as u64,
+            reliability_settings.rtt_update_factor.to_bits(),
+            reliability_settings.rtt_resend_factor.to_bits(),
+            max_len as u64,
+        ]
+        .iter()
+        {
+            hasher.write(&value.to_le_bytes());
+        }
+    }
+    hasher.write(&(settings.message_buffer_size as u64).to_le_bytes());
+    hasher.write(&(settings.packet_buffer_size as u64).to_le_bytes());
+}
+
+/// Field by field copy of `settings`, turbulence does not implement `Clone` for them.
+pub(crate) fn copy_channel_settings(settings: &MessageChannelSettings) -> MessageChannelSettings {
+    MessageChannelSettings {
+        channel: settings.channel,
+        channel_mode: copy_channel_mode(&settings.channel_mode),
+        message_buffer_size: settings.message_buffer_size,
+        packet_buffer_size: settings.packet_buffer_size,
+    }
+}
+
+pub(crate) fn copy_channel_mode(channel_mode: &MessageChannelMode) -> MessageChannelMode {
+    match channel_mode {
+        MessageChannelMode::Unreliable => MessageChannelMode::Unreliable,
+        MessageChannelMode::Reliable {
+            reliability_settings,
+            max_message_len,
+        } => MessageChannelMode::Reliable {
+            reliability_settings: reliability_settings.clone(),
+            max_message_len: *max_message_len,
+        },
+        MessageChannelMode::Compressed {
+            reliability_settings,
+            max_chunk_len,
+        } => MessageChannelMode::Compressed {
+            reliability_settings: reliability_settings.clone(),
+            max_chunk_len: *max_chunk_len,
+        },
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct PacketStats {
     pub packets_tx: usize,
     pub packets_rx: usize,
     pub bytes_tx: usize,
     pub bytes_rx: usize,
+    /// Received packets lost to a full receive queue.
+    pub packets_dropped: usize,
     pub last_tx: Instant,
     pub last_rx: Instant,
 }
@@ -54,25 +220,39 @@ impl Default for PacketStats {
             packets_rx: 0,
             bytes_tx: 0,
             bytes_rx: 0,
+            packets_dropped: 0,
             last_tx: now,
             last_rx: now,
-         }
+        }
     }
 }
 
 impl PacketStats {
-    fn add_tx(&mut self, num_bytes: usize) {
+    pub(crate) fn add_tx(&mut self, num_bytes: usize) {
         self.packets_tx += 1;
         self.bytes_tx += num_bytes;
         self.last_tx = Instant::now();
     }
-    fn add_rx(&mut self, num_bytes: usize) {
+    pub(crate) fn add_rx(&mut self, num_bytes: usize) {
         self.packets_rx += 1;
         self.bytes_rx += num_bytes;
         self.last_rx = Instant::now();
     }
+    pub(crate) fn add_dropped(&mut self) {
+        self.packets_dropped += 1;
+    }
+    // returns `QueueOverflow` when packets were dropped since the last report
+    pub(crate) fn take_overflow(&self, reported: &mut usize) -> Option<NetworkError> {
+        if self.packets_dropped > *reported {
+            let dropped = self.packets_dropped - *reported;
+            *reported = self.packets_dropped;
+            Some(NetworkError::QueueOverflow { dropped })
+        } else {
+            None
+        }
+    }
     // returns Duration since last (rx, tx)
-    fn idle_durations(&self) -> (Duration, Duration) {
+    pub(crate) fn idle_durations(&self) -> (Duration, Duration) {
         let now = Instant::now();
         let rx = now.duration_since(self.last_rx);
         let tx = now.duration_since(self.last_tx);
@@ -80,19 +260,66 @@ impl PacketStats {
     }
 }
 
+/// Which end of the connection this side is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ConnectionRole {
+    /// Connection accepted by one of our listeners.
+    Server,
+    /// Connection we dialled.
+    Client,
+}
+
+/// Static description of a connection, for identifying peers in logs and UI.
+#[derive(Debug, Clone)]
+pub struct ConnectionInfo {
+    pub transport: Transport,
+    pub role: ConnectionRole,
+    /// Address of the local socket, if the transport exposes it.
+    /// WebSocket clients know it once the TCP connection is established.
+    pub local_address: Option<SocketAddr>,
+    pub connected_at: Instant,
+    /// Token the client connected with, see `NetworkResource::require_connect_tokens`.
+    pub connect_token: Option<ConnectTokenData>,
+}
+
+impl ConnectionInfo {
+    pub fn new(
+        transport: Transport,
+        role: ConnectionRole,
+        local_address: Option<SocketAddr>,
+    ) -> Self {
+        ConnectionInfo {
+            transport,
+            role,
+            local_address,
+            connected_at: Instant::now(),
+            connect_token: None,
+        }
+    }
+
+    /// Time since the connection was established.
+    pub fn connected_for(&self) -> Duration {
+        Instant::now().duration_since(self.connected_at)
+    }
+}
+
 pub trait Connection: Send + Sync {
     fn remote_address(&self) -> Option<SocketAddr>;
 
+    fn info(&self) -> &ConnectionInfo;
+
+    fn transport(&self) -> Transport {
+        self.info().transport
+    }
+
     fn send(&mut self, payload: Packet) -> Result<(), Box<dyn Error + Sync + Send>>;
 
     fn receive(&mut self) -> Option<Result<Packet, NetworkError>>;
 
-    fn build_channels(
-        &mut self,
-        builder_fn: &(dyn Fn(&mut ConnectionChannelsBuilder) + Send + Sync),
-        runtime: TaskPoolRuntime,
-        pool: MuxPacketPool<BufferPacketPool<SimpleBufferPool>>,
-    );
+    /// Sends out packets the connection buffered. Called once per frame, in `CoreStage::Last`.
+    fn flush(&mut self) {}
+
+    fn build_channels(&mut self, builder: ConnectionChannelsBuilder);
 
     fn channels(&mut self) -> Option<&mut MessageChannels>;
 
@@ -104,14 +331,51 @@ pub trait Connection: Send + Sync {
     fn last_packet_timings(&self) -> (u128, u128);
 }
 
+/// Socket a `ServerConnection` sends its packets through.
+#[cfg(not(target_arch = "wasm32"))]
+pub enum ServerPacketSender {
+    Naia(ServerSender),
+    Udp(Arc<async_net::UdpSocket>),
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl ServerPacketSender {
+    pub(crate) async fn send(
+        &mut self,
+        address: SocketAddr,
+        payload: &[u8],
+    ) -> Result<(), Box<dyn Error + Sync + Send>> {
+        match self {
+            ServerPacketSender::Naia(sender) => {
+                sender
+                    .send(ServerPacket::new(address, payload.to_vec()))
+                    .await
+            }
+            ServerPacketSender::Udp(socket) => socket
+                .send_to(payload, address)
+                .await
+                .map(|_| ())
+                .map_err(|error| Box::new(error) as Box<dyn Error + Sync + Send>),
+        }
+    }
+}
+
 #[cfg(not(target_arch = "wasm32"))]
 pub struct ServerConnection {
     task_pool: TaskPool,
 
     packet_rx: crossbeam_channel::Receiver<Result<Packet, NetworkError>>,
-    sender: Option<ServerSender>,
+    // drained by `send_task`, so sending never blocks the game loop
+    outgoing_tx: mpsc::Sender<Packet>,
+    errors_tx: crossbeam_channel::Sender<NetworkError>,
+    send_errors: crossbeam_channel::Receiver<NetworkError>,
+    #[allow(dead_code)]
+    send_task: Task<()>,
     client_address: SocketAddr,
+    info: ConnectionInfo,
     stats: Arc<RwLock<PacketStats>>,
+    // `stats.packets_dropped` already reported as `QueueOverflow`
+    reported_dropped: usize,
 
     channels: Option<MessageChannels>,
     channels_rx: Option<IncomingMultiplexedPackets<MultiplexedPacket>>,
@@ -121,18 +385,38 @@ pub struct ServerConnection {
 
 #[cfg(not(target_arch = "wasm32"))]
 impl ServerConnection {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         task_pool: TaskPool,
         packet_rx: crossbeam_channel::Receiver<Result<Packet, NetworkError>>,
-        sender: ServerSender,
+        sender: ServerPacketSender,
         client_address: SocketAddr,
+        transport: Transport,
+        local_address: Option<SocketAddr>,
+        connect_token: Option<ConnectTokenData>,
+        stats: Arc<RwLock<PacketStats>>,
     ) -> Self {
+        let mut info = ConnectionInfo::new(transport, ConnectionRole::Server, local_address);
+        info.connect_token = connect_token;
+        let (outgoing_tx, outgoing_rx) = mpsc::channel(SEND_QUEUE_LEN);
+        let (errors_tx, send_errors) = crossbeam_channel::bounded(SEND_ERRORS_LEN);
+        let send_task = task_pool.spawn(send_packets(
+            sender,
+            client_address,
+            outgoing_rx,
+            errors_tx.clone(),
+        ));
         ServerConnection {
             task_pool,
             packet_rx,
-            sender: Some(sender),
+            outgoing_tx,
+            errors_tx,
+            send_errors,
+            send_task,
             client_address,
-            stats: Arc::new(RwLock::new(PacketStats::default())),
+            info,
+            stats,
+            reported_dropped: 0,
             channels: None,
             channels_rx: None,
             channels_task: None,
@@ -146,33 +430,62 @@ impl Connection for ServerConnection {
         Some(self.client_address)
     }
 
+    fn info(&self) -> &ConnectionInfo {
+        &self.info
+    }
+
     fn stats(&self) -> PacketStats {
         self.stats.read().expect("stats lock poisoned").clone()
     }
 
+    /// Queues the packet for the send task, errors of the actual send come
+    /// from `receive` as `NetworkError::IoError`.
     fn send(&mut self, payload: Packet) -> Result<(), Box<dyn Error + Sync + Send>> {
-        self.stats.write().expect("stats lock poisoned").add_tx(payload.len());
-        block_on(
-            self.sender
-                .as_mut()
-                .unwrap()
-                .send(ServerPacket::new(self.client_address, payload.to_vec())),
-        )
+        let len = payload.len();
+        self.outgoing_tx.try_send(payload).map_err(|error| {
+            let message = if error.is_full() {
+                "Send queue is full"
+            } else {
+                "Send task stopped"
+            };
+            Box::new(std::io::Error::new(std::io::ErrorKind::WouldBlock, message))
+                as Box<dyn Error + Sync + Send>
+        })?;
+        self.stats.write().expect("stats lock poisoned").add_tx(len);
+        Ok(())
     }
 
     fn last_packet_timings(&self) -> (u128, u128) {
-        let (rx_dur, tx_dur) = self.stats.read().expect("stats lock poisoned").idle_durations();
+        let (rx_dur, tx_dur) = self
+            .stats
+            .read()
+            .expect("stats lock poisoned")
+            .idle_durations();
         (rx_dur.as_millis(), tx_dur.as_millis())
     }
 
     fn receive(&mut self) -> Option<Result<Packet, NetworkError>> {
+        if let Some(overflow) = self
+            .stats
+            .read()
+            .expect("stats lock poisoned")
+            .take_overflow(&mut self.reported_dropped)
+        {
+            return Some(Err(overflow));
+        }
+        if let Ok(error) = self.send_errors.try_recv() {
+            return Some(Err(error));
+        }
         match self.packet_rx.try_recv() {
             Ok(payload) => match payload {
                 Ok(packet) => {
-                    self.stats.write().expect("stats lock poisoned").add_rx(packet.len());
+                    self.stats
+                        .write()
+                        .expect("stats lock poisoned")
+                        .add_rx(packet.len());
                     Some(Ok(packet))
-                },
-                Err(err) => Some(Err(err))
+                }
+                Err(err) => Some(Err(err)),
             },
             Err(error) => match error {
                 crossbeam_channel::TryRecvError::Empty => None,
@@ -183,32 +496,33 @@ impl Connection for ServerConnection {
         }
     }
 
-    fn build_channels(
-        &mut self,
-        builder_fn: &(dyn Fn(&mut ConnectionChannelsBuilder) + Send + Sync),
-        runtime: TaskPoolRuntime,
-        pool: MuxPacketPool<BufferPacketPool<SimpleBufferPool>>,
-    ) {
-        let mut builder = MessageChannelsBuilder::new(runtime, pool);
-        builder_fn(&mut builder);
-
+    fn build_channels(&mut self, builder: ConnectionChannelsBuilder) {
         let mut multiplexer = PacketMultiplexer::new();
         self.channels = Some(builder.build(&mut multiplexer));
         let (channels_rx, mut channels_tx) = multiplexer.start();
         self.channels_rx = Some(channels_rx);
 
-        let mut sender = self.sender.take().unwrap();
-        let client_address = self.client_address;
+        let mut outgoing_tx = self.outgoing_tx.clone();
+        let errors_tx = self.errors_tx.clone();
         let stats = self.stats.clone();
         self.channels_task = Some(self.task_pool.spawn(async move {
-            loop {
-                let packet = channels_tx.next().await.unwrap();
-                stats.write().expect("stats lock poisoned").add_tx(packet.len());
-                sender
-                    .send(ServerPacket::new(client_address, (*packet).into()))
+            while let Some(packet) = channels_tx.next().await {
+                stats
+                    .write()
+                    .expect("stats lock poisoned")
+                    .add_tx(packet.len());
+                // waits for room in the queue, turbulence copes with the delay
+                if outgoing_tx
+                    .send(Packet::copy_from_slice(&packet))
                     .await
-                    .unwrap();
+                    .is_err()
+                {
+                    log::error!("Send task stopped, stopping channels");
+                    break;
+                }
             }
+            // the connection cannot exchange messages anymore
+            let _ = errors_tx.try_send(NetworkError::Disconnected);
         }));
     }
 
@@ -221,12 +535,39 @@ impl Connection for ServerConnection {
     }
 }
 
+/// Send task of a `ServerConnection`.
+#[cfg(not(target_arch = "wasm32"))]
+async fn send_packets(
+    mut sender: ServerPacketSender,
+    address: SocketAddr,
+    mut outgoing_rx: mpsc::Receiver<Packet>,
+    errors_tx: crossbeam_channel::Sender<NetworkError>,
+) {
+    while let Some(packet) = outgoing_rx.next().await {
+        if let Err(error) = sender.send(address, &packet).await {
+            log::error!("Server Send Error to {}: {}", address, error);
+            let _ = errors_tx.try_send(NetworkError::IoError(error));
+        }
+    }
+}
+
 pub struct ClientConnection {
     task_pool: TaskPool,
 
     socket: Box<dyn ClientSocketTrait>,
-    sender: Option<ClientSender>,
+    sender: ClientSender,
+    server_address: SocketAddr,
+    info: ConnectionInfo,
     stats: Arc<RwLock<PacketStats>>,
+    // failures of `channels_task`, reported by `receive`
+    errors_tx: crossbeam_channel::Sender<NetworkError>,
+    send_errors: crossbeam_channel::Receiver<NetworkError>,
+    // repeated until the server accepts the connection
+    connect_request: Option<Packet>,
+    last_connect_request: Option<Instant>,
+    connect_token: Option<ConnectToken>,
+    // the server drops payloads until it accepts us, `None` once it did
+    held_back: Arc<Mutex<Option<Vec<Packet>>>>,
 
     channels: Option<MessageChannels>,
     channels_rx: Option<IncomingMultiplexedPackets<MultiplexedPacket>>,
@@ -239,23 +580,113 @@ impl ClientConnection {
         task_pool: TaskPool,
         socket: Box<dyn ClientSocketTrait>,
         sender: ClientSender,
+        server_address: SocketAddr,
     ) -> Self {
+        // naia client socket speaks WebRTC in browsers and plain UDP natively
+        let transport = if cfg!(target_arch = "wasm32") {
+            Transport::WebRtc
+        } else {
+            Transport::Udp
+        };
+        let (errors_tx, send_errors) = crossbeam_channel::bounded(SEND_ERRORS_LEN);
         ClientConnection {
             task_pool,
             socket,
-            sender: Some(sender),
+            sender,
+            server_address,
+            info: ConnectionInfo::new(transport, ConnectionRole::Client, None),
             stats: Arc::new(RwLock::new(PacketStats::default())),
+            errors_tx,
+            send_errors,
+            connect_request: Some(
+                ControlPacket::ConnectRequest
+                    .encode_padded(MIN_CONNECT_REQUEST_LEN)
+                    .into(),
+            ),
+            last_connect_request: None,
+            connect_token: None,
+            held_back: Arc::new(Mutex::new(Some(Vec::new()))),
             channels: None,
             channels_rx: None,
             #[cfg(not(target_arch = "wasm32"))]
             channels_task: None,
         }
     }
+
+    /// Presents `token` to the server when connecting.
+    pub(crate) fn with_connect_token(mut self, token: ConnectToken) -> Self {
+        self.connect_token = Some(token);
+        self
+    }
+
+    fn send_connect_request(&mut self) {
+        let request = match self.connect_request.as_ref() {
+            Some(request) => request.clone(),
+            None => return,
+        };
+        if let Some(sent) = self.last_connect_request {
+            if sent.elapsed() < CONNECT_REQUEST_INTERVAL {
+                return;
+            }
+        }
+        self.last_connect_request = Some(Instant::now());
+        self.stats
+            .write()
+            .expect("stats lock poisoned")
+            .add_tx(request.len());
+        if let Err(error) = self.sender.send(ClientPacket::new(request.to_vec())) {
+            log::error!(
+                "Cannot send connect request to {}: {}",
+                self.server_address,
+                error
+            );
+        }
+    }
+
+    /// Sends what was held back, now that the server accepted the connection.
+    fn accepted(&mut self) {
+        self.connect_request = None;
+        let mut held_back = self
+            .held_back
+            .lock()
+            .expect("held back packets lock is poisoned");
+        for payload in held_back.take().unwrap_or_default() {
+            if let Err(error) = self.sender.send(ClientPacket::new(payload.to_vec())) {
+                log::error!("Client Send Error: {}", error);
+            }
+        }
+    }
+}
+
+/// Sends `payload` right away once the server accepted the connection, holds it back until then.
+fn send_or_hold(
+    sender: &mut ClientSender,
+    held_back: &Mutex<Option<Vec<Packet>>>,
+    payload: Packet,
+) -> Result<(), Box<dyn Error + Sync + Send>> {
+    let mut held_back = held_back
+        .lock()
+        .expect("held back packets lock is poisoned");
+    match held_back.as_mut() {
+        Some(packets) if packets.len() >= HELD_BACK_LEN => Err(Box::new(std::io::Error::new(
+            std::io::ErrorKind::WouldBlock,
+            "Connection is not accepted yet, send queue is full",
+        ))),
+        Some(packets) => {
+            packets.push(payload);
+            Ok(())
+        }
+        None => sender.send(ClientPacket::new(payload.to_vec())),
+    }
 }
 
 impl Connection for ClientConnection {
     fn remote_address(&self) -> Option<SocketAddr> {
-        None
+        Some(self.server_address)
+    }
+
+    fn info(&self) -> &ConnectionInfo {
+        &self.info
     }
 
     fn stats(&self) -> PacketStats {
@@ -263,58 +694,92 @@ impl Connection for ClientConnection {
     }
 
     fn last_packet_timings(&self) -> (u128, u128) {
-        let (rx_dur, tx_dur) = self.stats.read().expect("stats lock poisoned").idle_durations();
+        let (rx_dur, tx_dur) = self
+            .stats
+            .read()
+            .expect("stats lock poisoned")
+            .idle_durations();
         (rx_dur.as_millis(), tx_dur.as_millis())
     }
 
+    /// Packets sent before the server accepted the connection go out once it does.
     fn send(&mut self, payload: Packet) -> Result<(), Box<dyn Error + Sync + Send>> {
-        self.stats.write().expect("stats lock poisoned").add_tx(payload.len());
-        self.sender
-            .as_mut()
-            .unwrap()
-            .send(ClientPacket::new(payload.to_vec()))
+        self.stats
+            .write()
+            .expect("stats lock poisoned")
+            .add_tx(payload.len());
+        send_or_hold(&mut self.sender, &self.held_back, payload)
     }
 
     fn receive(&mut self) -> Option<Result<Packet, NetworkError>> {
-        match self.socket.receive() {
-            Ok(event) => event.map(|packet| {
-                self.stats.write().expect("stats lock poisoned").add_rx(packet.payload().len());
-                Ok(Packet::copy_from_slice(packet.payload()))
-            }),
-            Err(err) => Some(Err(NetworkError::IoError(Box::new(err)))),
+        if let Ok(error) = self.send_errors.try_recv() {
+            return Some(Err(error));
+        }
+        self.send_connect_request();
+        loop {
+            let packet = match self.socket.receive() {
+                Ok(event) => event?,
+                Err(err) => return Some(Err(NetworkError::IoError(Box::new(err)))),
+            };
+            let payload = packet.payload();
+            self.stats
+                .write()
+                .expect("stats lock poisoned")
+                .add_rx(payload.len());
+            if !ControlPacket::is_control(payload) {
+                return Some(Ok(Packet::copy_from_slice(payload)));
+            }
+            // only answers to our connect request matter
+            if self.connect_request.is_none() {
+                continue;
+            }
+            match ControlPacket::decode(payload) {
+                Some(ControlPacket::Challenge { cookie }) => {
+                    let response = ControlPacket::ChallengeResponse {
+                        cookie,
+                        token: self.connect_token.clone(),
+                    };
+                    self.connect_request = Some(response.encode().into());
+                    self.last_connect_request = None;
+                    self.send_connect_request();
+                }
+                Some(ControlPacket::Accepted) => self.accepted(),
+                Some(ControlPacket::Rejected(reason)) => {
+                    self.connect_request = None;
+                    return Some(Err(NetworkError::Rejected(reason)));
+                }
+                _ => log::debug!("Dropped control packet from {}", self.server_address),
+            }
         }
     }
 
-    fn build_channels(
-        &mut self,
-        builder_fn: &(dyn Fn(&mut ConnectionChannelsBuilder) + Send + Sync),
-        runtime: TaskPoolRuntime,
-        pool: MuxPacketPool<BufferPacketPool<SimpleBufferPool>>,
-    ) {
-        let mut builder = MessageChannelsBuilder::new(runtime, pool);
-        builder_fn(&mut builder);
-
+    fn build_channels(&mut self, builder: ConnectionChannelsBuilder) {
         let mut multiplexer = PacketMultiplexer::new();
         self.channels = Some(builder.build(&mut multiplexer));
         let (channels_rx, mut channels_tx) = multiplexer.start();
         self.channels_rx = Some(channels_rx);
 
-        let mut sender = self.sender.take().unwrap();
+        // `send` keeps its own sender, for raw packets and connect requests
+        let mut sender = self.socket.get_sender();
+        let held_back = self.held_back.clone();
+        let errors_tx = self.errors_tx.clone();
         let stats = self.stats.clone();
         #[allow(unused_variables)]
         let channels_task = self.task_pool.spawn(async move {
-            loop {
-                match channels_tx.next().await {
-                    Some(packet) => {
-                        stats.write().expect("stats lock poisoned").add_tx(packet.len());
-                        sender.send(ClientPacket::new((*packet).into())).unwrap();
-                    }
-                    None => {
-                        log::error!("Channel stream Disconnected");
-                        return; // exit task
-                    }
+            while let Some(packet) = channels_tx.next().await {
+                stats
+                    .write()
+                    .expect("stats lock poisoned")
+                    .add_tx(packet.len());
+                let payload = Packet::copy_from_slice(&packet);
+                if let Err(error) = send_or_hold(&mut sender, &held_back, payload) {
+                    log::error!("Client Send Error: {}", error);
+                    // full queue drops the error, the first ones are reported already
+                    let _ = errors_tx.try_send(NetworkError::IoError(error));
                 }
             }
+            log::error!("Channel stream Disconnected");
+            let _ = errors_tx.try_send(NetworkError::Disconnected);
         });
 
         #[cfg(not(target_arch = "wasm32"))]
@@ -0,0 +1,65 @@
+use std::{
+    any::{Any, TypeId},
+    collections::HashMap,
+};
+
+/// Values attached to a single connection, at most one per type.
+#[derive(Default)]
+pub(crate) struct UserData(HashMap<TypeId, Box<dyn Any + Send + Sync>>);
+
+impl UserData {
+    pub fn insert<T: Send + Sync + 'static>(&mut self, data: T) -> Option<T> {
+        self.0
+            .insert(TypeId::of::<T>(), Box::new(data))
+            .and_then(|previous| previous.downcast().ok())
+            .map(|previous| *previous)
+    }
+
+    pub fn get<T: Send + Sync + 'static>(&self) -> Option<&T> {
+        self.0
+            .get(&TypeId::of::<T>())
+            .and_then(|data| data.downcast_ref())
+    }
+
+    pub fn get_mut<T: Send + Sync + 'static>(&mut self) -> Option<&mut T> {
+        self.0
+            .get_mut(&TypeId::of::<T>())
+            .and_then(|data| data.downcast_mut())
+    }
+
+    pub fn remove<T: Send + Sync + 'static>(&mut self) -> Option<T> {
+        self.0
+            .remove(&TypeId::of::<T>())
+            .and_then(|data| data.downcast().ok())
+            .map(|data| *data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::UserData;
+
+    #[test]
+    fn keeps_one_value_per_type() {
+        let mut data = UserData::default();
+        assert_eq!(data.insert(1u32), None);
+        assert_eq!(data.insert("name"), None);
+        assert_eq!(data.insert(2u32), Some(1));
+
+        assert_eq!(data.get::<u32>(), Some(&2));
+        assert_eq!(data.get::<&str>(), Some(&"name"));
+        assert_eq!(data.get::<u64>(), None);
+    }
+
+    #[test]
+    fn modifies_and_removes_values() {
+        let mut data = UserData::default();
+        data.insert(vec![1u8]);
+        data.get_mut::<Vec<u8>>().unwrap().push(2);
+        assert!(data.get_mut::<u32>().is_none());
+
+        assert_eq!(data.remove::<Vec<u8>>(), Some(vec![1, 2]));
+        assert_eq!(data.remove::<Vec<u8>>(), None);
+        assert!(data.get::<Vec<u8>>().is_none());
+    }
+}
@@ -0,0 +1,853 @@
+//! WebSocket transport.
+//!
+//! Every WebSocket binary message carries exactly one packet, so raw packets and
+//! turbulence channels work on top of it the same way as on naia sockets.
+
+#[cfg(not(target_arch = "wasm32"))]
+use bevy_tasks::Task;
+use bevy_tasks::TaskPool;
+use std::{
+    error::Error,
+    net::SocketAddr,
+    sync::{Arc, RwLock},
+};
+
+use turbulence::{
+    message_channels::MessageChannels,
+    packet_multiplexer::{IncomingMultiplexedPackets, PacketMultiplexer},
+};
+
+use futures::StreamExt;
+
+use super::{
+    control::ControlPacket,
+    token::ConnectToken,
+    transport::{
+        Connection, ConnectionChannelsBuilder, ConnectionInfo, ConnectionRole, MultiplexedPacket,
+        Packet, PacketStats, SEND_ERRORS_LEN,
+    },
+    NetworkError, Transport,
+};
+#[cfg(not(target_arch = "wasm32"))]
+use super::{
+    control::RejectReason,
+    server::ListenerContext,
+    token::{unix_time, ConnectTokenData},
+};
+
+/// Error to report for a control packet received by a WebSocket connection.
+/// Servers get the `Connect` message of clients, clients may get `Rejected`.
+fn control_error(payload: &[u8]) -> Option<NetworkError> {
+    match ControlPacket::decode(payload) {
+        Some(ControlPacket::Rejected(reason)) => Some(NetworkError::Rejected(reason)),
+        _ => None,
+    }
+}
+
+// raw and channel packets waiting for the socket, per connection
+const SEND_QUEUE_LEN: usize = 256;
+
+#[cfg(not(target_arch = "wasm32"))]
+mod native {
+    use super::*;
+    use async_net::{TcpListener, TcpStream};
+    use async_tungstenite::{
+        tungstenite::{protocol::WebSocketConfig, Message},
+        WebSocketStream,
+    };
+    use futures::{
+        channel::mpsc,
+        future::Either,
+        io::{AsyncRead, AsyncWrite},
+        SinkExt,
+    };
+    use futures_timer::Delay;
+    use std::{
+        future::Future,
+        sync::atomic::{AtomicUsize, Ordering},
+        time::{Duration, Instant},
+    };
+    use turbulence::packet::MAX_PACKET_LEN;
+
+    /// Clients not done with the WebSocket upgrade by then are dropped.
+    const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+    /// Refuses messages larger than any packet, before tungstenite buffers them.
+    fn config() -> Option<WebSocketConfig> {
+        Some(WebSocketConfig {
+            max_message_size: Some(MAX_PACKET_LEN),
+            max_frame_size: Some(MAX_PACKET_LEN),
+            ..WebSocketConfig::default()
+        })
+    }
+
+    pub struct WebSocketConnection {
+        task_pool: TaskPool,
+
+        remote_address: SocketAddr,
+        info: ConnectionInfo,
+        packet_rx: crossbeam_channel::Receiver<Result<Packet, NetworkError>>,
+        packet_tx: mpsc::Sender<Packet>,
+        stats: Arc<RwLock<PacketStats>>,
+        // clients learn their local address once the TCP connection is established
+        local_address_rx: Option<crossbeam_channel::Receiver<SocketAddr>>,
+        // `stats.packets_dropped` already reported as `QueueOverflow`
+        reported_dropped: usize,
+        // failures of `channels_task`, reported by `receive`
+        errors_tx: crossbeam_channel::Sender<NetworkError>,
+        send_errors: crossbeam_channel::Receiver<NetworkError>,
+        // needed to keep the socket alive
+        #[allow(dead_code)]
+        io_task: Task<()>,
+
+        channels: Option<MessageChannels>,
+        channels_rx: Option<IncomingMultiplexedPackets<MultiplexedPacket>>,
+        channels_task: Option<Task<()>>,
+    }
+
+    impl WebSocketConnection {
+        /// Wraps a WebSocket accepted by the listener.
+        /// Up to `receive_queue_len` packets wait for `receive`, the rest is dropped.
+        fn accepted(
+            task_pool: TaskPool,
+            socket: WebSocketStream<TcpStream>,
+            remote_address: SocketAddr,
+            connect_token: Option<ConnectTokenData>,
+            receive_queue_len: usize,
+        ) -> Self {
+            let local_address = socket.get_ref().local_addr().ok();
+            let (incoming_tx, packet_rx) = crossbeam_channel::bounded(receive_queue_len);
+            let (packet_tx, outgoing_rx) = mpsc::channel(SEND_QUEUE_LEN);
+            let stats = Arc::new(RwLock::new(PacketStats::default()));
+            let io_task = task_pool.spawn(drive(socket, incoming_tx, outgoing_rx, stats.clone()));
+            let mut info =
+                ConnectionInfo::new(Transport::WebSocket, ConnectionRole::Server, local_address);
+            info.connect_token = connect_token;
+            WebSocketConnection::new(
+                task_pool,
+                remote_address,
+                info,
+                packet_rx,
+                packet_tx,
+                stats,
+                None,
+                io_task,
+            )
+        }
+
+        /// Dials a WebSocket server. Packets sent before the socket opens are queued.
+        /// `token` is presented to servers requiring connect tokens.
+        /// Up to `receive_queue_len` packets wait for `receive`, the rest is dropped.
+        pub fn connect(
+            task_pool: TaskPool,
+            remote_address: SocketAddr,
+            token: Option<ConnectToken>,
+            receive_queue_len: usize,
+        ) -> Self {
+            let (incoming_tx, packet_rx) = crossbeam_channel::bounded(receive_queue_len);
+            let (mut packet_tx, outgoing_rx) = mpsc::channel(SEND_QUEUE_LEN);
+            // goes out first, the queue is still empty
+            let _ = packet_tx.try_send(Packet::from(ControlPacket::Connect { token }.encode()));
+            let (local_address_tx, local_address_rx) = crossbeam_channel::bounded(1);
+            let stats = Arc::new(RwLock::new(PacketStats::default()));
+            let drive_stats = stats.clone();
+            let io_task = task_pool.spawn(async move {
+                let stream = match TcpStream::connect(remote_address).await {
+                    Ok(stream) => {
+                        if let Ok(local_address) = stream.local_addr() {
+                            let _ = local_address_tx.try_send(local_address);
+                        }
+                        stream
+                    }
+                    Err(error) => {
+                        log::error!("WebSocket connect error to {}: {}", remote_address, error);
+                        let _ = incoming_tx.try_send(Err(NetworkError::IoError(Box::new(error))));
+                        return;
+                    }
+                };
+                let url = format!("ws://{}", remote_address);
+                match async_tungstenite::client_async_with_config(url.as_str(), stream, config())
+                    .await
+                {
+                    Ok((socket, _response)) => {
+                        drive(socket, incoming_tx, outgoing_rx, drive_stats).await
+                    }
+                    Err(error) => {
+                        log::error!(
+                            "WebSocket handshake error with {}: {}",
+                            remote_address,
+                            error
+                        );
+                        let _ = incoming_tx.try_send(Err(NetworkError::IoError(Box::new(error))));
+                    }
+                }
+            });
+            WebSocketConnection::new(
+                task_pool,
+                remote_address,
+                ConnectionInfo::new(Transport::WebSocket, ConnectionRole::Client, None),
+                packet_rx,
+                packet_tx,
+                stats,
+                Some(local_address_rx),
+                io_task,
+            )
+        }
+
+        #[allow(clippy::too_many_arguments)]
+        fn new(
+            task_pool: TaskPool,
+            remote_address: SocketAddr,
+            info: ConnectionInfo,
+            packet_rx: crossbeam_channel::Receiver<Result<Packet, NetworkError>>,
+            packet_tx: mpsc::Sender<Packet>,
+            stats: Arc<RwLock<PacketStats>>,
+            local_address_rx: Option<crossbeam_channel::Receiver<SocketAddr>>,
+            io_task: Task<()>,
+        ) -> Self {
+            let (errors_tx, send_errors) = crossbeam_channel::bounded(SEND_ERRORS_LEN);
+            WebSocketConnection {
+                task_pool,
+                remote_address,
+                info,
+                packet_rx,
+                packet_tx,
+                stats,
+                local_address_rx,
+                reported_dropped: 0,
+                errors_tx,
+                send_errors,
+                io_task,
+                channels: None,
+                channels_rx: None,
+                channels_task: None,
+            }
+        }
+    }
+
+    /// Pumps packets between the socket and connection queues until either side closes.
+    async fn drive<S>(
+        socket: WebSocketStream<S>,
+        incoming_tx: crossbeam_channel::Sender<Result<Packet, NetworkError>>,
+        mut outgoing_rx: mpsc::Receiver<Packet>,
+        stats: Arc<RwLock<PacketStats>>,
+    ) where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let (mut sink, mut stream) = socket.split();
+        let reader = async {
+            while let Some(message) = stream.next().await {
+                match message {
+                    Ok(Message::Binary(payload)) => {
+                        match incoming_tx.try_send(Ok(Packet::from(payload))) {
+                            Ok(()) => {}
+                            Err(crossbeam_channel::TrySendError::Full(_)) => {
+                                stats.write().expect("stats lock poisoned").add_dropped();
+                            }
+                            // connection was dropped
+                            Err(crossbeam_channel::TrySendError::Disconnected(_)) => return,
+                        }
+                    }
+                    Ok(Message::Close(_)) => break,
+                    // pings are answered by tungstenite, text frames are not ours
+                    Ok(_) => {}
+                    Err(error) => {
+                        log::error!("WebSocket receive error: {}", error);
+                        break;
+                    }
+                }
+            }
+            let _ = incoming_tx.try_send(Err(NetworkError::Disconnected));
+        };
+        let writer = async {
+            while let Some(packet) = outgoing_rx.next().await {
+                if let Err(error) = sink.send(Message::Binary(packet.to_vec())).await {
+                    log::error!("WebSocket send error: {}", error);
+                    let _ = incoming_tx.try_send(Err(NetworkError::IoError(Box::new(error))));
+                    return;
+                }
+            }
+        };
+        futures::pin_mut!(reader, writer);
+        futures::future::select(reader, writer).await;
+    }
+
+    /// `None` if `future` does not complete within `duration`.
+    async fn timeout<F: Future>(duration: Duration, future: F) -> Option<F::Output> {
+        futures::pin_mut!(future);
+        match futures::future::select(future, Delay::new(duration)).await {
+            Either::Left((output, _)) => Some(output),
+            Either::Right(_) => None,
+        }
+    }
+
+    /// Handshake in flight, counted against `ListenerSettings::max_pending_connections`.
+    struct PendingHandshake(Arc<AtomicUsize>);
+
+    impl PendingHandshake {
+        fn try_new(handshakes: &Arc<AtomicUsize>, max: usize) -> Option<Self> {
+            handshakes
+                .fetch_update(Ordering::AcqRel, Ordering::Acquire, |count| {
+                    if count < max {
+                        Some(count + 1)
+                    } else {
+                        None
+                    }
+                })
+                .ok()
+                .map(|_| PendingHandshake(handshakes.clone()))
+        }
+    }
+
+    impl Drop for PendingHandshake {
+        fn drop(&mut self) {
+            self.0.fetch_sub(1, Ordering::AcqRel);
+        }
+    }
+
+    /// Accepts WebSocket connections until the returned future is dropped.
+    pub(crate) async fn listen(context: ListenerContext, socket_address: SocketAddr) {
+        let listener = match TcpListener::bind(socket_address).await {
+            Ok(listener) => listener,
+            Err(error) => {
+                log::error!("WebSocket listen error on {}: {}", socket_address, error);
+                return;
+            }
+        };
+        let handshakes = Arc::new(AtomicUsize::new(0));
+        loop {
+            match listener.accept().await {
+                Ok((stream, address)) => {
+                    // dropping the stream closes it
+                    if !context.admits(address) {
+                        log::debug!("WebSocket refused banned or not allowed {}", address);
+                        continue;
+                    }
+                    if !context.rate_limiter.allow(address.ip(), Instant::now()) {
+                        log::debug!("WebSocket rate limited {}", address);
+                        continue;
+                    }
+                    let pending = match PendingHandshake::try_new(
+                        &handshakes,
+                        context.settings.max_pending_connections,
+                    ) {
+                        Some(pending) => pending,
+                        None => {
+                            log::warn!("Too many WebSocket handshakes, dropped {}", address);
+                            continue;
+                        }
+                    };
+                    log::debug!("WebSocket accepted TCP connection from {}", address);
+                    // handshake separately, so a slow client does not stall the accept loop
+                    let context = context.clone();
+                    context
+                        .task_pool
+                        .clone()
+                        .spawn(async move {
+                            handshake(context, stream, address).await;
+                            drop(pending);
+                        })
+                        .detach();
+                }
+                Err(error) => {
+                    log::error!("WebSocket accept error: {}", error);
+                }
+            }
+        }
+    }
+
+    /// Upgrades the TCP connection and hands it over to `receive_packets`.
+    async fn handshake(context: ListenerContext, stream: TcpStream, address: SocketAddr) {
+        let read_token = context.connect_tokens.is_some();
+        let (socket, token) = match upgrade(stream, address, read_token).await {
+            Some(upgraded) => upgraded,
+            None => return,
+        };
+
+        let connect_token = match context.validate_token(token, address, unix_time()) {
+            Ok(connect_token) => connect_token,
+            Err(reason) => return reject(socket, reason).await,
+        };
+        if !context.slots.try_acquire() {
+            log::debug!("WebSocket refused {}: server is full", address);
+            return reject(socket, RejectReason::ServerFull).await;
+        }
+        let mut pending_connections = context.pending_connections.lock().unwrap();
+        if pending_connections.len() >= context.settings.max_pending_connections {
+            log::warn!("Too many pending connections, dropped {}", address);
+            context.slots.release();
+            return;
+        }
+        pending_connections.push(Box::new(WebSocketConnection::accepted(
+            context.task_pool.clone(),
+            socket,
+            address,
+            connect_token,
+            context.settings.receive_queue_len,
+        )));
+    }
+
+    /// Accepts the WebSocket upgrade. With `read_token` waits for the `Connect` message
+    /// of the client, otherwise it is skipped by `receive` later.
+    async fn upgrade(
+        stream: TcpStream,
+        address: SocketAddr,
+        read_token: bool,
+    ) -> Option<(WebSocketStream<TcpStream>, Option<ConnectToken>)> {
+        let mut socket = match timeout(
+            HANDSHAKE_TIMEOUT,
+            async_tungstenite::accept_async_with_config(stream, config()),
+        )
+        .await
+        {
+            Some(Ok(socket)) => socket,
+            Some(Err(error)) => {
+                log::error!("WebSocket handshake error from {}: {}", address, error);
+                return None;
+            }
+            None => {
+                log::debug!("WebSocket handshake with {} timed out", address);
+                return None;
+            }
+        };
+        if !read_token {
+            return Some((socket, None));
+        }
+        loop {
+            match socket.next().await {
+                Some(Ok(Message::Binary(payload))) => {
+                    let token = match ControlPacket::decode(&payload) {
+                        Some(ControlPacket::Connect { token }) => token,
+                        _ => None,
+                    };
+                    return Some((socket, token));
+                }
+                Some(Ok(Message::Close(_))) | None => return None,
+                Some(Ok(_)) => {}
+                Some(Err(error)) => {
+                    log::error!("WebSocket receive error from {}: {}", address, error);
+                    return None;
+                }
+            }
+        }
+    }
+
+    /// Tells the client why, then closes the socket.
+    async fn reject(mut socket: WebSocketStream<TcpStream>, reason: RejectReason) {
+        let rejected = Message::Binary(ControlPacket::Rejected(reason).encode());
+        let _ = socket.send(rejected).await;
+    }
+
+    impl Connection for WebSocketConnection {
+        fn remote_address(&self) -> Option<SocketAddr> {
+            Some(self.remote_address)
+        }
+
+        fn info(&self) -> &ConnectionInfo {
+            &self.info
+        }
+
+        fn stats(&self) -> PacketStats {
+            self.stats.read().expect("stats lock poisoned").clone()
+        }
+
+        fn last_packet_timings(&self) -> (u128, u128) {
+            let (rx_dur, tx_dur) = self
+                .stats
+                .read()
+                .expect("stats lock poisoned")
+                .idle_durations();
+            (rx_dur.as_millis(), tx_dur.as_millis())
+        }
+
+        fn send(&mut self, payload: Packet) -> Result<(), Box<dyn Error + Sync + Send>> {
+            let len = payload.len();
+            self.packet_tx.try_send(payload).map_err(|error| {
+                let message = if error.is_full() {
+                    "Send queue is full"
+                } else {
+                    "WebSocket closed"
+                };
+                Box::new(std::io::Error::new(std::io::ErrorKind::WouldBlock, message))
+                    as Box<dyn Error + Sync + Send>
+            })?;
+            self.stats.write().expect("stats lock poisoned").add_tx(len);
+            Ok(())
+        }
+
+        fn receive(&mut self) -> Option<Result<Packet, NetworkError>> {
+            if let Some(overflow) = self
+                .stats
+                .read()
+                .expect("stats lock poisoned")
+                .take_overflow(&mut self.reported_dropped)
+            {
+                return Some(Err(overflow));
+            }
+            if let Ok(error) = self.send_errors.try_recv() {
+                return Some(Err(error));
+            }
+            if let Some(local_address) = self
+                .local_address_rx
+                .as_ref()
+                .and_then(|local_address_rx| local_address_rx.try_recv().ok())
+            {
+                self.info.local_address = Some(local_address);
+                self.local_address_rx = None;
+            }
+            loop {
+                let packet = match self.packet_rx.try_recv() {
+                    Ok(Ok(packet)) => packet,
+                    Ok(Err(err)) => return Some(Err(err)),
+                    Err(crossbeam_channel::TryRecvError::Empty) => return None,
+                    Err(crossbeam_channel::TryRecvError::Disconnected) => {
+                        return Some(Err(NetworkError::Disconnected))
+                    }
+                };
+                if ControlPacket::is_control(&packet) {
+                    match control_error(&packet) {
+                        Some(error) => return Some(Err(error)),
+                        None => continue,
+                    }
+                }
+                self.stats
+                    .write()
+                    .expect("stats lock poisoned")
+                    .add_rx(packet.len());
+                return Some(Ok(packet));
+            }
+        }
+
+        fn build_channels(&mut self, builder: ConnectionChannelsBuilder) {
+            let mut multiplexer = PacketMultiplexer::new();
+            self.channels = Some(builder.build(&mut multiplexer));
+            let (channels_rx, mut channels_tx) = multiplexer.start();
+            self.channels_rx = Some(channels_rx);
+
+            let mut packet_tx = self.packet_tx.clone();
+            let errors_tx = self.errors_tx.clone();
+            let stats = self.stats.clone();
+            self.channels_task = Some(self.task_pool.spawn(async move {
+                while let Some(packet) = channels_tx.next().await {
+                    stats
+                        .write()
+                        .expect("stats lock poisoned")
+                        .add_tx(packet.len());
+                    // waits for room in the queue, turbulence copes with the delay
+                    if packet_tx
+                        .send(Packet::copy_from_slice(&packet))
+                        .await
+                        .is_err()
+                    {
+                        log::error!("WebSocket closed, stopping channels");
+                        break;
+                    }
+                }
+                let _ = errors_tx.try_send(NetworkError::Disconnected);
+            }));
+        }
+
+        fn channels(&mut self) -> Option<&mut MessageChannels> {
+            self.channels.as_mut()
+        }
+
+        fn channels_rx(&mut self) -> Option<&mut IncomingMultiplexedPackets<MultiplexedPacket>> {
+            self.channels_rx.as_mut()
+        }
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+mod web {
+    use super::*;
+    use std::{cell::RefCell, rc::Rc};
+    use wasm_bindgen::{closure::Closure, JsCast, JsValue};
+    use web_sys::{BinaryType, CloseEvent, ErrorEvent, MessageEvent, WebSocket};
+
+    pub struct WebSocketConnection {
+        task_pool: TaskPool,
+
+        // unknown when dialing a host name
+        remote_address: Option<SocketAddr>,
+        info: ConnectionInfo,
+        socket: WebSocket,
+        packet_rx: crossbeam_channel::Receiver<Result<Packet, NetworkError>>,
+        // packets sent before the socket opened, up to `SEND_QUEUE_LEN`
+        queued: Rc<RefCell<Vec<Packet>>>,
+        stats: Arc<RwLock<PacketStats>>,
+        // `stats.packets_dropped` already reported as `QueueOverflow`
+        reported_dropped: usize,
+        // failures of the channels task, reported by `receive`
+        errors_tx: crossbeam_channel::Sender<NetworkError>,
+        send_errors: crossbeam_channel::Receiver<NetworkError>,
+        #[allow(dead_code)]
+        callbacks: Vec<Closure<dyn FnMut(JsValue)>>,
+
+        channels: Option<MessageChannels>,
+        channels_rx: Option<IncomingMultiplexedPackets<MultiplexedPacket>>,
+    }
+
+    fn js_error(value: JsValue) -> Box<dyn Error + Sync + Send> {
+        Box::new(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            format!("{:?}", value),
+        ))
+    }
+
+    /// Address of URLs like `ws://127.0.0.1:14193/`, `None` for host names.
+    fn url_socket_address(url: &str) -> Option<SocketAddr> {
+        let authority = url.split("://").nth(1)?.split('/').next()?;
+        authority.parse().ok()
+    }
+
+    fn send_or_queue(
+        socket: &WebSocket,
+        queued: &RefCell<Vec<Packet>>,
+        payload: Packet,
+    ) -> Result<(), Box<dyn Error + Sync + Send>> {
+        match socket.ready_state() {
+            WebSocket::CONNECTING => {
+                let mut queued = queued.borrow_mut();
+                if queued.len() >= SEND_QUEUE_LEN {
+                    return Err(Box::new(std::io::Error::new(
+                        std::io::ErrorKind::WouldBlock,
+                        "Send queue is full",
+                    )));
+                }
+                queued.push(payload);
+                Ok(())
+            }
+            WebSocket::OPEN => socket.send_with_u8_array(&payload).map_err(js_error),
+            _ => Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::NotConnected,
+                "WebSocket is closed",
+            ))),
+        }
+    }
+
+    impl WebSocketConnection {
+        /// Dials a WebSocket server. Packets sent before the socket opens are queued.
+        /// `token` is presented to servers requiring connect tokens.
+        /// Up to `receive_queue_len` packets wait for `receive`, the rest is dropped.
+        pub fn connect(
+            task_pool: TaskPool,
+            remote_address: SocketAddr,
+            token: Option<ConnectToken>,
+            receive_queue_len: usize,
+        ) -> Self {
+            let url = format!("ws://{}", remote_address);
+            Self::connect_url(task_pool, &url, token, receive_queue_len)
+                .expect("URL of a socket address is valid")
+        }
+
+        /// Like `connect`, dialing a `ws://` or `wss://` URL, i.e. `"wss://example.com/game"`.
+        /// Fails if the browser refuses the URL.
+        pub fn connect_url(
+            task_pool: TaskPool,
+            url: &str,
+            token: Option<ConnectToken>,
+            receive_queue_len: usize,
+        ) -> Result<Self, Box<dyn Error + Sync + Send>> {
+            let socket = WebSocket::new(url).map_err(js_error)?;
+            let (incoming_tx, packet_rx) = crossbeam_channel::bounded(receive_queue_len);
+            let stats = Arc::new(RwLock::new(PacketStats::default()));
+            socket.set_binary_type(BinaryType::Arraybuffer);
+            // the connect message goes out first
+            let queued: Rc<RefCell<Vec<Packet>>> = Rc::new(RefCell::new(vec![Packet::from(
+                ControlPacket::Connect { token }.encode(),
+            )]));
+
+            let mut callbacks = Vec::new();
+
+            let tx = incoming_tx.clone();
+            let message_stats = stats.clone();
+            let onmessage = Closure::wrap(Box::new(move |event: JsValue| {
+                let event: MessageEvent = event.unchecked_into();
+                if let Ok(buffer) = event.data().dyn_into::<js_sys::ArrayBuffer>() {
+                    let payload = js_sys::Uint8Array::new(&buffer).to_vec();
+                    if let Err(crossbeam_channel::TrySendError::Full(_)) =
+                        tx.try_send(Ok(Packet::from(payload)))
+                    {
+                        message_stats
+                            .write()
+                            .expect("stats lock poisoned")
+                            .add_dropped();
+                    }
+                }
+            }) as Box<dyn FnMut(JsValue)>);
+            socket.set_onmessage(Some(onmessage.as_ref().unchecked_ref()));
+            callbacks.push(onmessage);
+
+            let open_socket = socket.clone();
+            let open_queued = queued.clone();
+            let onopen = Closure::wrap(Box::new(move |_event: JsValue| {
+                for payload in open_queued.borrow_mut().drain(..) {
+                    if let Err(error) = open_socket.send_with_u8_array(&payload) {
+                        log::error!("WebSocket send error: {:?}", error);
+                    }
+                }
+            }) as Box<dyn FnMut(JsValue)>);
+            socket.set_onopen(Some(onopen.as_ref().unchecked_ref()));
+            callbacks.push(onopen);
+
+            let tx = incoming_tx.clone();
+            let onerror = Closure::wrap(Box::new(move |event: JsValue| {
+                let event: ErrorEvent = event.unchecked_into();
+                log::error!("WebSocket error: {}", event.message());
+                let _ = tx.try_send(Err(NetworkError::IoError(Box::new(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    event.message(),
+                )))));
+            }) as Box<dyn FnMut(JsValue)>);
+            socket.set_onerror(Some(onerror.as_ref().unchecked_ref()));
+            callbacks.push(onerror);
+
+            let tx = incoming_tx;
+            let onclose = Closure::wrap(Box::new(move |event: JsValue| {
+                let event: CloseEvent = event.unchecked_into();
+                log::debug!("WebSocket closed: {} {}", event.code(), event.reason());
+                let _ = tx.try_send(Err(NetworkError::Disconnected));
+            }) as Box<dyn FnMut(JsValue)>);
+            socket.set_onclose(Some(onclose.as_ref().unchecked_ref()));
+            callbacks.push(onclose);
+
+            let (errors_tx, send_errors) = crossbeam_channel::bounded(SEND_ERRORS_LEN);
+            Ok(WebSocketConnection {
+                task_pool,
+                remote_address: url_socket_address(url),
+                info: ConnectionInfo::new(Transport::WebSocket, ConnectionRole::Client, None),
+                socket,
+                packet_rx,
+                queued,
+                stats,
+                reported_dropped: 0,
+                errors_tx,
+                send_errors,
+                callbacks,
+                channels: None,
+                channels_rx: None,
+            })
+        }
+    }
+
+    impl Drop for WebSocketConnection {
+        fn drop(&mut self) {
+            self.socket.set_onmessage(None);
+            self.socket.set_onopen(None);
+            self.socket.set_onerror(None);
+            self.socket.set_onclose(None);
+            let _ = self.socket.close();
+        }
+    }
+
+    impl Connection for WebSocketConnection {
+        fn remote_address(&self) -> Option<SocketAddr> {
+            self.remote_address
+        }
+
+        fn info(&self) -> &ConnectionInfo {
+            &self.info
+        }
+
+        fn stats(&self) -> PacketStats {
+            self.stats.read().expect("stats lock poisoned").clone()
+        }
+
+        fn last_packet_timings(&self) -> (u128, u128) {
+            let (rx_dur, tx_dur) = self
+                .stats
+                .read()
+                .expect("stats lock poisoned")
+                .idle_durations();
+            (rx_dur.as_millis(), tx_dur.as_millis())
+        }
+
+        fn send(&mut self, payload: Packet) -> Result<(), Box<dyn Error + Sync + Send>> {
+            let len = payload.len();
+            send_or_queue(&self.socket, &self.queued, payload)?;
+            self.stats.write().expect("stats lock poisoned").add_tx(len);
+            Ok(())
+        }
+
+        fn receive(&mut self) -> Option<Result<Packet, NetworkError>> {
+            if let Some(overflow) = self
+                .stats
+                .read()
+                .expect("stats lock poisoned")
+                .take_overflow(&mut self.reported_dropped)
+            {
+                return Some(Err(overflow));
+            }
+            if let Ok(error) = self.send_errors.try_recv() {
+                return Some(Err(error));
+            }
+            loop {
+                let packet = match self.packet_rx.try_recv() {
+                    Ok(Ok(packet)) => packet,
+                    Ok(Err(err)) => return Some(Err(err)),
+                    Err(crossbeam_channel::TryRecvError::Empty) => return None,
+                    Err(crossbeam_channel::TryRecvError::Disconnected) => {
+                        return Some(Err(NetworkError::Disconnected))
+                    }
+                };
+                if ControlPacket::is_control(&packet) {
+                    match control_error(&packet) {
+                        Some(error) => return Some(Err(error)),
+                        None => continue,
+                    }
+                }
+                self.stats
+                    .write()
+                    .expect("stats lock poisoned")
+                    .add_rx(packet.len());
+                return Some(Ok(packet));
+            }
+        }
+
+        fn build_channels(&mut self, builder: ConnectionChannelsBuilder) {
+            let mut multiplexer = PacketMultiplexer::new();
+            self.channels = Some(builder.build(&mut multiplexer));
+            let (channels_rx, mut channels_tx) = multiplexer.start();
+            self.channels_rx = Some(channels_rx);
+
+            let socket = self.socket.clone();
+            let queued = self.queued.clone();
+            let errors_tx = self.errors_tx.clone();
+            let stats = self.stats.clone();
+            self.task_pool.spawn(async move {
+                while let Some(packet) = channels_tx.next().await {
+                    stats
+                        .write()
+                        .expect("stats lock poisoned")
+                        .add_tx(packet.len());
+                    if let Err(error) =
+                        send_or_queue(&socket, &queued, Packet::copy_from_slice(&packet))
+                    {
+                        log::error!("WebSocket closed, stopping channels: {}", error);
+                        break;
+                    }
+                }
+                let _ = errors_tx.try_send(NetworkError::Disconnected);
+            });
+        }
+
+        fn channels(&mut self) -> Option<&mut MessageChannels> {
+            self.channels.as_mut()
+        }
+
+        fn channels_rx(&mut self) -> Option<&mut IncomingMultiplexedPackets<MultiplexedPacket>> {
+            self.channels_rx.as_mut()
+        }
+    }
+
+    unsafe impl Send for WebSocketConnection {}
+
+    unsafe impl Sync for WebSocketConnection {}
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) use native::listen;
+#[cfg(not(target_arch = "wasm32"))]
+pub use native::WebSocketConnection;
+#[cfg(target_arch = "wasm32")]
+pub use web::WebSocketConnection;